@@ -0,0 +1,21 @@
+-- The original schema. Safe to run against a database created before migrations were tracked.
+CREATE TABLE IF NOT EXISTS schema_migrations (
+   version integer PRIMARY KEY,
+   name nvarchar(256) NOT NULL,
+   applied_at datetime DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS links (
+   id integer PRIMARY KEY AUTOINCREMENT,
+   slack_thread nvarchar(100),
+   trello_card nvarchar(100)
+    );
+CREATE UNIQUE INDEX IF NOT EXISTS idx_slack ON links (slack_thread);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_trello ON links (trello_card);
+
+CREATE TABLE IF NOT EXISTS accounts (
+   id uuid_str(4) PRIMARY KEY,
+   name nvarchar(256)
+);
+
+INSERT INTO schema_migrations (version, name) VALUES (0, 'initial');
@@ -0,0 +1,34 @@
+-- Per account credentials and channel routing, and scopes links to an account and channel.
+
+CREATE TABLE IF NOT EXISTS account_credentials (
+   account_id uuid_str(4) PRIMARY KEY REFERENCES accounts (id),
+   slack_auth_token nvarchar(256),
+   slack_signing_secret nvarchar(256),
+   trello_api_key nvarchar(256),
+   trello_api_token nvarchar(256),
+   trello_app_secret nvarchar(256)
+);
+
+CREATE TABLE IF NOT EXISTS channel_mappings (
+   id integer PRIMARY KEY AUTOINCREMENT,
+   account_id uuid_str(4) REFERENCES accounts (id),
+   trello_board nvarchar(100) NOT NULL,
+   trello_list nvarchar(100),
+   slack_channel nvarchar(100) NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_channel_mapping ON channel_mappings (account_id, trello_board, COALESCE(trello_list, ''));
+
+ALTER TABLE links ADD COLUMN slack_channel nvarchar(100);
+ALTER TABLE links ADD COLUMN account_id uuid_str(4) REFERENCES accounts (id);
+
+-- Existing links can only be attributed when there is a single account, otherwise they are left
+-- unowned and will not be matched by any account
+UPDATE links SET account_id = (SELECT id FROM accounts)
+   WHERE account_id IS NULL AND (SELECT COUNT(*) FROM accounts) = 1;
+
+DROP INDEX IF EXISTS idx_slack;
+DROP INDEX IF EXISTS idx_trello;
+CREATE UNIQUE INDEX idx_slack ON links (account_id, slack_channel, slack_thread);
+CREATE UNIQUE INDEX idx_trello ON links (account_id, trello_card);
+
+INSERT INTO schema_migrations (version, name) VALUES (1, 'account_scoped_links');
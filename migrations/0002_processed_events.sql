@@ -0,0 +1,11 @@
+-- Webhook deliveries that have already been handled, so retries can be acknowledged without side effects
+CREATE TABLE IF NOT EXISTS processed_events (
+   account_id uuid_str(4) NOT NULL REFERENCES accounts (id),
+   service nvarchar(20) NOT NULL,
+   event_id nvarchar(100) NOT NULL,
+   processed_at integer NOT NULL,
+   PRIMARY KEY (account_id, service, event_id)
+);
+CREATE INDEX IF NOT EXISTS idx_processed_events_processed_at ON processed_events (processed_at);
+
+INSERT INTO schema_migrations (version, name) VALUES (2, 'processed_events');
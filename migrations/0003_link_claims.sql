@@ -0,0 +1,4 @@
+-- A link row is inserted with no thread while the thread is being created, see database::claim_link
+ALTER TABLE links ADD COLUMN claimed_at integer;
+
+INSERT INTO schema_migrations (version, name) VALUES (3, 'link_claims');
@@ -0,0 +1,19 @@
+-- Slack display names looked up with users.info, refreshed once they are older than users::SLACK_USER_CACHE_TTL_SECONDS
+CREATE TABLE IF NOT EXISTS slack_users (
+   account_id uuid_str(4) NOT NULL REFERENCES accounts (id),
+   slack_user nvarchar(100) NOT NULL,
+   display_name nvarchar(256) NOT NULL,
+   fetched_at integer NOT NULL,
+   PRIMARY KEY (account_id, slack_user)
+);
+
+-- Optional pairing of a Slack user with their Trello member, each side can only be mapped once per account
+CREATE TABLE IF NOT EXISTS user_mappings (
+   account_id uuid_str(4) NOT NULL REFERENCES accounts (id),
+   slack_user nvarchar(100) NOT NULL,
+   trello_username nvarchar(100) NOT NULL,
+   PRIMARY KEY (account_id, slack_user)
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_user_mappings_trello ON user_mappings (account_id, trello_username);
+
+INSERT INTO schema_migrations (version, name) VALUES (4, 'user_directory');
@@ -0,0 +1,11 @@
+-- Pairs each Slack reply with the Trello comment it was mirrored from or to, so edits can follow
+CREATE TABLE IF NOT EXISTS message_mappings (
+   account_id uuid_str(4) NOT NULL REFERENCES accounts (id),
+   slack_channel nvarchar(100) NOT NULL,
+   slack_ts nvarchar(100) NOT NULL,
+   trello_comment nvarchar(100) NOT NULL,
+   PRIMARY KEY (account_id, slack_channel, slack_ts)
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_message_mappings_trello ON message_mappings (account_id, trello_comment);
+
+INSERT INTO schema_migrations (version, name) VALUES (5, 'message_mappings');
@@ -0,0 +1,7 @@
+-- Per account behaviour, accounts without a row use the defaults in settings.rs
+CREATE TABLE IF NOT EXISTS account_settings (
+   account_id uuid_str(4) PRIMARY KEY REFERENCES accounts (id),
+   deletion_sync nvarchar(20) NOT NULL DEFAULT 'annotate'
+);
+
+INSERT INTO schema_migrations (version, name) VALUES (6, 'account_settings');
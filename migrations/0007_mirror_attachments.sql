@@ -0,0 +1,4 @@
+-- Off by default, shared files are only linked until an account opts in
+ALTER TABLE account_settings ADD COLUMN mirror_attachments integer NOT NULL DEFAULT 0;
+
+INSERT INTO schema_migrations (version, name) VALUES (7, 'mirror_attachments');
@@ -0,0 +1,13 @@
+-- Queued webhooks that could not be processed, kept to be inspected and replayed.
+-- account_id is the id from the webhook url, which has no accounts row for a worker bound with ACCOUNT_ID.
+CREATE TABLE IF NOT EXISTS dead_letters (
+   id integer PRIMARY KEY AUTOINCREMENT,
+   account_id nvarchar(100) NOT NULL,
+   service nvarchar(20) NOT NULL,
+   payload text NOT NULL,
+   error text NOT NULL,
+   attempts integer NOT NULL,
+   failed_at integer NOT NULL
+);
+
+INSERT INTO schema_migrations (version, name) VALUES (8, 'dead_letters');
@@ -0,0 +1,32 @@
+-- Links pair an item in one service with an item in another, rather than always a Trello card with a Slack thread, so
+-- any two connectors can be linked. The source is the item the link was started from, the target the thread or item
+-- created for it. A container is where an item's id is unique, such as a Slack channel, and NULL for services with none.
+
+CREATE TABLE connector_links (
+   id integer PRIMARY KEY AUTOINCREMENT,
+   account_id uuid_str(4) REFERENCES accounts (id),
+   source_service nvarchar(20) NOT NULL,
+   source_container nvarchar(100),
+   source_id nvarchar(100) NOT NULL,
+   target_service nvarchar(20) NOT NULL,
+   -- Links migrated from before channel routing have no channel recorded, and match any
+   target_container nvarchar(100),
+   -- NULL while the target is being created, see database::claim_link
+   target_id nvarchar(100),
+   claimed_at integer
+);
+
+INSERT INTO connector_links (id, account_id, source_service, source_id, target_service, target_container, target_id, claimed_at)
+   SELECT id, account_id, 'trello', trello_card, 'slack', slack_channel, slack_thread, claimed_at FROM links
+   WHERE trello_card IS NOT NULL;
+
+DROP TABLE links;
+ALTER TABLE connector_links RENAME TO links;
+CREATE UNIQUE INDEX idx_link_source ON links (account_id, source_service, COALESCE(source_container, ''), source_id);
+CREATE UNIQUE INDEX idx_link_target ON links (account_id, target_service, COALESCE(target_container, ''), target_id);
+
+-- A board can be routed to any connector, not only a Slack channel
+ALTER TABLE channel_mappings RENAME COLUMN slack_channel TO target_container;
+ALTER TABLE channel_mappings ADD COLUMN target_service nvarchar(20) NOT NULL DEFAULT 'slack';
+
+INSERT INTO schema_migrations (version, name) VALUES (9, 'connector_links');
@@ -0,0 +1,12 @@
+-- Trello members looked up by username, refreshed once they are older than users::TRELLO_MEMBER_CACHE_TTL_SECONDS.
+-- The token's own member is cached under `me`, the username Trello accepts for it.
+CREATE TABLE IF NOT EXISTS trello_members (
+   account_id uuid_str(4) NOT NULL REFERENCES accounts (id),
+   username nvarchar(100) NOT NULL,
+   member_id nvarchar(100) NOT NULL,
+   full_name nvarchar(256) NOT NULL,
+   fetched_at integer NOT NULL,
+   PRIMARY KEY (account_id, username)
+);
+
+INSERT INTO schema_migrations (version, name) VALUES (10, 'trello_members');
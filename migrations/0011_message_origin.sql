@@ -0,0 +1,5 @@
+-- Which service each mapped message was written in, the other side being the copy the bot posted. Only the copy is
+-- edited or deleted to follow the original. Mappings from before this have no origin, and are left alone.
+ALTER TABLE message_mappings ADD COLUMN origin nvarchar(20);
+
+INSERT INTO schema_migrations (version, name) VALUES (11, 'message_origin');
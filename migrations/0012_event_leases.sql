@@ -0,0 +1,20 @@
+-- A delivery leases an event while processing it and only marks it processed once it succeeds, so a retry arriving
+-- meanwhile isn't acknowledged as done. processed_at is NULL while the lease is held. Events recorded before this were
+-- processed when they were recorded.
+CREATE TABLE event_leases (
+   account_id uuid_str(4) NOT NULL REFERENCES accounts (id),
+   service nvarchar(20) NOT NULL,
+   event_id nvarchar(100) NOT NULL,
+   started_at integer NOT NULL,
+   processed_at integer,
+   PRIMARY KEY (account_id, service, event_id)
+);
+
+INSERT INTO event_leases (account_id, service, event_id, started_at, processed_at)
+   SELECT account_id, service, event_id, processed_at, processed_at FROM processed_events;
+
+DROP TABLE processed_events;
+ALTER TABLE event_leases RENAME TO processed_events;
+CREATE INDEX idx_processed_events_started_at ON processed_events (started_at);
+
+INSERT INTO schema_migrations (version, name) VALUES (12, 'event_leases');
@@ -0,0 +1,26 @@
+-- Message mappings pair a message with its copy in any other service, as links pair items, rather than always a Slack
+-- message with a Trello comment. The source is the message as it was written, the target the copy the bot posted.
+-- Mappings with no origin could have either side as the copy and were never followed, so they aren't carried over.
+CREATE TABLE connector_message_mappings (
+   account_id uuid_str(4) NOT NULL REFERENCES accounts (id),
+   source_service nvarchar(20) NOT NULL,
+   source_container nvarchar(100),
+   source_id nvarchar(100) NOT NULL,
+   target_service nvarchar(20) NOT NULL,
+   target_container nvarchar(100),
+   target_id nvarchar(100) NOT NULL
+);
+
+INSERT INTO connector_message_mappings (account_id, source_service, source_container, source_id, target_service, target_container, target_id)
+   SELECT account_id, 'slack', slack_channel, slack_ts, 'trello', NULL, trello_comment FROM message_mappings
+   WHERE origin = 'slack';
+INSERT INTO connector_message_mappings (account_id, source_service, source_container, source_id, target_service, target_container, target_id)
+   SELECT account_id, 'trello', NULL, trello_comment, 'slack', slack_channel, slack_ts FROM message_mappings
+   WHERE origin = 'trello';
+
+DROP TABLE message_mappings;
+ALTER TABLE connector_message_mappings RENAME TO message_mappings;
+CREATE UNIQUE INDEX idx_message_mapping_source ON message_mappings (account_id, source_service, COALESCE(source_container, ''), source_id);
+CREATE UNIQUE INDEX idx_message_mapping_target ON message_mappings (account_id, target_service, COALESCE(target_container, ''), target_id);
+
+INSERT INTO schema_migrations (version, name) VALUES (13, 'connector_message_mappings');
@@ -0,0 +1 @@
+insert into accounts values ('92cfdda8-bb81-480c-b3ca-092d3366b244', 'Test Account');
@@ -1,20 +1,33 @@
 use serde::Deserialize;
 use worker::{Env, Error};
+use crate::credentials::{Credentials, get_credentials_from_env};
+use crate::settings::{Settings, get_settings_from_env};
+use crate::store::{D1Store, Store};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Debug)]
+#[allow(dead_code)]
 pub struct Account {
     pub id: String,
     pub name: String,
+    #[serde(skip)]
+    pub credentials: Credentials,
+    #[serde(skip)]
+    pub settings: Settings,
 }
 
-pub async fn get_account(env: &Env, id: &str) -> Result<Account, Error> {
+// None when there is no account with this id, D1 errors are returned
+pub async fn get_account(env: &Env, id: &str) -> Result<Option<Account>, Error> {
     let id = match env.secret("ACCOUNT_ID".as_ref()) {
         Ok(val) => val.to_string(),
-        Err(_) => return crate::database::get_account(&env, id).await
+        Err(_) => {
+            return D1Store::new(env).get_account(id).await;
+        }
     };
 
-    return Ok(Account{
+    return Ok(Some(Account{
         id,
         name: "test".to_string(),
-    });
+        credentials: get_credentials_from_env(env),
+        settings: get_settings_from_env(env),
+    }));
 }
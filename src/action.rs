@@ -1,6 +1,7 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Clone, Debug)]
+#[allow(dead_code)]
 pub struct Action {
     pub action: ActionType,
     pub target: ActionTargetSource,
@@ -12,23 +13,84 @@ pub struct Action {
 pub enum ActionType {
     NewThread,
     UpdateThread,
+    // Replaces a message mirrored earlier, the target id is the mirrored message
+    EditMessage,
+    // Removes or annotates a message mirrored earlier, depending on the account's DeletionSync
+    DeleteMessage,
     None,
 }
 
 #[derive(Deserialize, Clone, Debug)]
+#[allow(dead_code)]
 pub struct ActionTargetSource {
     pub id: Option<String>,
     pub url: String,
     pub service: ActionService,
+    // Slack channel the thread lives in, None for Trello
+    pub channel: Option<String>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum ActionService {
     Slack,
     Trello,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+impl ActionService {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            ActionService::Slack => "slack",
+            ActionService::Trello => "trello",
+        };
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
 pub struct ActionUpdate {
+    // Plain text version of the whole update, also used as the notification fallback
     pub text: String,
-}
\ No newline at end of file
+    pub title: Option<String>,
+    pub actor: Option<String>,
+    pub body: Option<String>,
+    pub fields: Vec<ActionUpdateField>,
+    pub link: Option<ActionUpdateLink>,
+    // Files shared along with the update, mirrored when the account has mirror_attachments on
+    pub attachments: Vec<ActionAttachment>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ActionUpdateField {
+    pub label: String,
+    pub value: String,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ActionUpdateLink {
+    pub label: String,
+    pub url: String,
+}
+
+// Larger files are linked rather than copied, a worker has to hold the whole file in memory
+pub const MAX_ATTACHMENT_BYTES: u64 = 10 * 1024 * 1024;
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ActionAttachment {
+    // The file's id in the source service
+    pub id: String,
+    pub name: String,
+    // Where the file can be downloaded from, with the source service's credentials
+    pub url: String,
+    // Where a person can view the file, used when the file is linked instead of copied
+    pub permalink: String,
+    // None when the source doesn't say, such as for link attachments
+    pub size: Option<u64>,
+    pub mime_type: Option<String>,
+}
+
+impl ActionAttachment {
+    /// Whether the file itself can be copied across, otherwise it is only linked
+    pub fn can_copy(&self) -> bool {
+        return matches!(self.size, Some(size) if size <= MAX_ATTACHMENT_BYTES);
+    }
+}
@@ -0,0 +1,355 @@
+use crate::account::Account;
+use crate::action::{Action, ActionAttachment, ActionUpdate};
+use crate::error::SyncError;
+use crate::slack::{self, ChatPostMessageResponse, SlackApiError, SlackUser};
+use crate::trello::{self, TrelloAttachment, TrelloCard, TrelloComment, TrelloMember};
+
+/// The Slack Web API calls the webhook handlers make
+pub trait SlackApi {
+    /// Replies in the action's target thread
+    async fn send_action(&self, account: &Account, action: Action) -> Result<ChatPostMessageResponse, SyncError>;
+
+    async fn send_thread_parent(&self, account: &Account, channel: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError>;
+
+    async fn send_thread_reply(&self, account: &Account, channel: &str, thread_ts: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError>;
+
+    async fn update_message(&self, account: &Account, channel: &str, ts: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError>;
+
+    async fn delete_message(&self, account: &Account, channel: &str, ts: &str) -> Result<(), SyncError>;
+
+    async fn download_file(&self, account: &Account, url: &str) -> Result<Vec<u8>, SyncError>;
+
+    async fn upload_file(&self, account: &Account, channel: &str, thread_ts: &str, name: &str, bytes: Vec<u8>) -> Result<(), SyncError>;
+
+    /// Looks up a user's profile, with Slack's own error when it doesn't know the user
+    async fn get_user(&self, account: &Account, user_id: &str) -> Result<Result<SlackUser, SlackApiError>, SyncError>;
+}
+
+/// The Trello REST API calls the webhook handlers make
+pub trait TrelloApi {
+    async fn get_card(&self, account: &Account, card_id: &str) -> Result<TrelloCard, SyncError>;
+
+    /// Starts a card on a list, named after the update's title
+    async fn create_card(&self, account: &Account, list_id: &str, update: &ActionUpdate) -> Result<TrelloCard, SyncError>;
+
+    /// Comments on the action's target card
+    async fn add_comment(&self, account: &Account, action: Action) -> Result<TrelloComment, SyncError>;
+
+    /// Replaces the text of the action's target comment
+    async fn update_comment(&self, account: &Account, action: Action) -> Result<(), SyncError>;
+
+    async fn delete_comment(&self, account: &Account, action: Action) -> Result<(), SyncError>;
+
+    async fn get_attachment(&self, account: &Account, card_id: &str, attachment_id: &str) -> Result<TrelloAttachment, SyncError>;
+
+    async fn download_attachment(&self, account: &Account, url: &str) -> Result<Vec<u8>, SyncError>;
+
+    async fn upload_attachment(&self, account: &Account, card_id: &str, attachment: &ActionAttachment, bytes: Vec<u8>) -> Result<(), SyncError>;
+
+    async fn attach_link(&self, account: &Account, card_id: &str, name: &str, link: &str) -> Result<(), SyncError>;
+
+    /// Looks up a member by username, failing with NotFound when there is no such member
+    async fn get_member(&self, account: &Account, username: &str) -> Result<TrelloMember, SyncError>;
+}
+
+/// The real APIs, over HTTP
+pub struct HttpApi;
+
+impl SlackApi for HttpApi {
+    async fn send_action(&self, account: &Account, action: Action) -> Result<ChatPostMessageResponse, SyncError> {
+        return slack::send_action(account, action).await;
+    }
+
+    async fn send_thread_parent(&self, account: &Account, channel: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError> {
+        return slack::send_thread_parent(account, channel, update).await;
+    }
+
+    async fn send_thread_reply(&self, account: &Account, channel: &str, thread_ts: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError> {
+        return slack::send_thread_reply(account, channel, thread_ts, update).await;
+    }
+
+    async fn update_message(&self, account: &Account, channel: &str, ts: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError> {
+        return slack::update_message(account, channel, ts, update).await;
+    }
+
+    async fn delete_message(&self, account: &Account, channel: &str, ts: &str) -> Result<(), SyncError> {
+        return slack::delete_message(account, channel, ts).await;
+    }
+
+    async fn download_file(&self, account: &Account, url: &str) -> Result<Vec<u8>, SyncError> {
+        return slack::download_file(account, url).await;
+    }
+
+    async fn upload_file(&self, account: &Account, channel: &str, thread_ts: &str, name: &str, bytes: Vec<u8>) -> Result<(), SyncError> {
+        return slack::upload_file(account, channel, thread_ts, name, bytes).await;
+    }
+
+    async fn get_user(&self, account: &Account, user_id: &str) -> Result<Result<SlackUser, SlackApiError>, SyncError> {
+        return slack::get_user(account, user_id).await;
+    }
+}
+
+impl TrelloApi for HttpApi {
+    async fn get_card(&self, account: &Account, card_id: &str) -> Result<TrelloCard, SyncError> {
+        return trello::get_card(account, card_id).await;
+    }
+
+    async fn create_card(&self, account: &Account, list_id: &str, update: &ActionUpdate) -> Result<TrelloCard, SyncError> {
+        return trello::create_card(account, list_id, update).await;
+    }
+
+    async fn add_comment(&self, account: &Account, action: Action) -> Result<TrelloComment, SyncError> {
+        return trello::add_comment_to_card(account, action).await;
+    }
+
+    async fn update_comment(&self, account: &Account, action: Action) -> Result<(), SyncError> {
+        return trello::update_comment(account, action).await;
+    }
+
+    async fn delete_comment(&self, account: &Account, action: Action) -> Result<(), SyncError> {
+        return trello::delete_comment(account, action).await;
+    }
+
+    async fn get_attachment(&self, account: &Account, card_id: &str, attachment_id: &str) -> Result<TrelloAttachment, SyncError> {
+        return trello::get_attachment(account, card_id, attachment_id).await;
+    }
+
+    async fn download_attachment(&self, account: &Account, url: &str) -> Result<Vec<u8>, SyncError> {
+        return trello::download_attachment(account, url).await;
+    }
+
+    async fn upload_attachment(&self, account: &Account, card_id: &str, attachment: &ActionAttachment, bytes: Vec<u8>) -> Result<(), SyncError> {
+        return trello::upload_attachment_to_card(account, card_id, attachment, bytes).await;
+    }
+
+    async fn attach_link(&self, account: &Account, card_id: &str, name: &str, link: &str) -> Result<(), SyncError> {
+        return trello::attach_link_to_card(account, card_id, name, link).await;
+    }
+
+    async fn get_member(&self, account: &Account, username: &str) -> Result<TrelloMember, SyncError> {
+        return trello::get_member(account, username).await;
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::cell::{Cell, RefCell};
+    use crate::account::Account;
+    use crate::action::{Action, ActionAttachment, ActionUpdate};
+    use crate::api::{SlackApi, TrelloApi};
+    use crate::error::SyncError;
+    use crate::slack::{ChatPostMessageResponse, SlackApiError, SlackUser};
+    use crate::trello::{TrelloAttachment, TrelloCard, TrelloComment, TrelloMember};
+
+    /// A call made through RecordingApi, with the arguments that matter to the tests
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ApiCall {
+        SendAction { channel: String, thread: Option<String>, text: String },
+        SendThreadParent { channel: String, text: String },
+        SendThreadReply { channel: String, thread: String, text: String },
+        UpdateMessage { channel: String, ts: String, text: String },
+        DeleteMessage { channel: String, ts: String },
+        DownloadFile { url: String },
+        UploadFile { channel: String, thread: String, name: String },
+        GetCard { card: String },
+        CreateCard { list: String, name: String },
+        AddComment { card: String, text: String },
+        UpdateComment { comment: String, text: String },
+        DeleteComment { comment: String },
+        GetAttachment { card: String, attachment: String },
+        DownloadAttachment { url: String },
+        UploadAttachment { card: String, name: String },
+        AttachLink { card: String, name: String, link: String },
+        GetUser { user: String },
+        GetMember { username: String },
+    }
+
+    impl ApiCall {
+        fn name(&self) -> &'static str {
+            return match self {
+                ApiCall::SendAction { .. } => "send_action",
+                ApiCall::SendThreadParent { .. } => "send_thread_parent",
+                ApiCall::SendThreadReply { .. } => "send_thread_reply",
+                ApiCall::UpdateMessage { .. } => "update_message",
+                ApiCall::DeleteMessage { .. } => "delete_message",
+                ApiCall::DownloadFile { .. } => "download_file",
+                ApiCall::UploadFile { .. } => "upload_file",
+                ApiCall::GetCard { .. } => "get_card",
+                ApiCall::CreateCard { .. } => "create_card",
+                ApiCall::AddComment { .. } => "add_comment",
+                ApiCall::UpdateComment { .. } => "update_comment",
+                ApiCall::DeleteComment { .. } => "delete_comment",
+                ApiCall::GetAttachment { .. } => "get_attachment",
+                ApiCall::DownloadAttachment { .. } => "download_attachment",
+                ApiCall::UploadAttachment { .. } => "upload_attachment",
+                ApiCall::AttachLink { .. } => "attach_link",
+                ApiCall::GetUser { .. } => "get_user",
+                ApiCall::GetMember { .. } => "get_member",
+            };
+        }
+    }
+
+    /// Records every call and answers as the real APIs would on success, unless told to fail a call
+    #[derive(Default)]
+    pub struct RecordingApi {
+        pub calls: RefCell<Vec<ApiCall>>,
+        // Calls by name that fail the next time they are made
+        failures: RefCell<Vec<(&'static str, SyncError)>>,
+        // Cards returned by get_card, which fails with NotFound for any other card
+        pub cards: RefCell<Vec<TrelloCard>>,
+        pub attachments: RefCell<Vec<TrelloAttachment>>,
+        // Users and members returned by get_user and get_member, any others aren't found
+        pub users: RefCell<Vec<SlackUser>>,
+        pub members: RefCell<Vec<TrelloMember>>,
+        next_ts: Cell<u32>,
+    }
+
+    impl RecordingApi {
+        pub fn fail(self, call: &'static str, error: SyncError) -> Self {
+            self.failures.borrow_mut().push((call, error));
+            return self;
+        }
+
+        pub fn with_attachment(self, attachment: TrelloAttachment) -> Self {
+            self.attachments.borrow_mut().push(attachment);
+            return self;
+        }
+
+        pub fn calls(&self) -> Vec<ApiCall> {
+            return self.calls.borrow().clone();
+        }
+
+        fn record(&self, call: ApiCall) -> Result<(), SyncError> {
+            let name = call.name();
+            self.calls.borrow_mut().push(call);
+            let mut failures = self.failures.borrow_mut();
+            if let Some(index) = failures.iter().position(|(failing, _)| *failing == name) {
+                return Err(failures.remove(index).1);
+            }
+            return Ok(());
+        }
+
+        // Timestamps in the order messages are posted, 1000000000.000001 and so on
+        fn posted(&self, channel: &str) -> ChatPostMessageResponse {
+            self.next_ts.set(self.next_ts.get() + 1);
+            return ChatPostMessageResponse {
+                ok: true,
+                channel: channel.to_string(),
+                ts: format!("1000000000.{:06}", self.next_ts.get()),
+            };
+        }
+    }
+
+    impl SlackApi for RecordingApi {
+        async fn send_action(&self, _account: &Account, action: Action) -> Result<ChatPostMessageResponse, SyncError> {
+            let channel = action.target.channel.unwrap_or_default();
+            self.record(ApiCall::SendAction { channel: channel.to_owned(), thread: action.target.id, text: action.update.text })?;
+            return Ok(self.posted(&channel));
+        }
+
+        async fn send_thread_parent(&self, _account: &Account, channel: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError> {
+            self.record(ApiCall::SendThreadParent { channel: channel.to_string(), text: update.text.to_owned() })?;
+            return Ok(self.posted(channel));
+        }
+
+        async fn send_thread_reply(&self, _account: &Account, channel: &str, thread_ts: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError> {
+            self.record(ApiCall::SendThreadReply { channel: channel.to_string(), thread: thread_ts.to_string(), text: update.text.to_owned() })?;
+            return Ok(self.posted(channel));
+        }
+
+        async fn update_message(&self, _account: &Account, channel: &str, ts: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError> {
+            self.record(ApiCall::UpdateMessage { channel: channel.to_string(), ts: ts.to_string(), text: update.text.to_owned() })?;
+            return Ok(ChatPostMessageResponse { ok: true, channel: channel.to_string(), ts: ts.to_string() });
+        }
+
+        async fn delete_message(&self, _account: &Account, channel: &str, ts: &str) -> Result<(), SyncError> {
+            return self.record(ApiCall::DeleteMessage { channel: channel.to_string(), ts: ts.to_string() });
+        }
+
+        async fn download_file(&self, _account: &Account, url: &str) -> Result<Vec<u8>, SyncError> {
+            self.record(ApiCall::DownloadFile { url: url.to_string() })?;
+            return Ok(url.as_bytes().to_vec());
+        }
+
+        async fn upload_file(&self, _account: &Account, channel: &str, thread_ts: &str, name: &str, _bytes: Vec<u8>) -> Result<(), SyncError> {
+            return self.record(ApiCall::UploadFile { channel: channel.to_string(), thread: thread_ts.to_string(), name: name.to_string() });
+        }
+
+        async fn get_user(&self, _account: &Account, user_id: &str) -> Result<Result<SlackUser, SlackApiError>, SyncError> {
+            self.record(ApiCall::GetUser { user: user_id.to_string() })?;
+            let users = self.users.borrow();
+            return Ok(users.iter().find(|user| user.id == user_id).cloned().ok_or(SlackApiError::UserNotFound));
+        }
+    }
+
+    impl TrelloApi for RecordingApi {
+        async fn get_card(&self, _account: &Account, card_id: &str) -> Result<TrelloCard, SyncError> {
+            self.record(ApiCall::GetCard { card: card_id.to_string() })?;
+            let cards = self.cards.borrow();
+            return match cards.iter().find(|card| card.id == card_id) {
+                Some(card) => Ok(card.clone()),
+                None => Err(SyncError::NotFound(format!("/1/cards/{}", card_id))),
+            };
+        }
+
+        async fn create_card(&self, _account: &Account, list_id: &str, update: &ActionUpdate) -> Result<TrelloCard, SyncError> {
+            let name = update.title.clone().unwrap_or(update.text.to_owned());
+            self.record(ApiCall::CreateCard { list: list_id.to_string(), name: name.to_owned() })?;
+            let id = format!("card{}", self.calls.borrow().len());
+            return Ok(TrelloCard {
+                short_url: format!("https://trello.com/c/{}", id),
+                id,
+                name,
+                closed: false,
+                due: None,
+                due_complete: false,
+                labels: vec![],
+                list: None,
+                members: vec![],
+            });
+        }
+
+        async fn add_comment(&self, _account: &Account, action: Action) -> Result<TrelloComment, SyncError> {
+            let card = action.target.id.unwrap_or_default();
+            self.record(ApiCall::AddComment { card, text: action.update.text })?;
+            return Ok(TrelloComment { id: format!("comment{}", self.calls.borrow().len()) });
+        }
+
+        async fn update_comment(&self, _account: &Account, action: Action) -> Result<(), SyncError> {
+            return self.record(ApiCall::UpdateComment { comment: action.target.id.unwrap_or_default(), text: action.update.text });
+        }
+
+        async fn delete_comment(&self, _account: &Account, action: Action) -> Result<(), SyncError> {
+            return self.record(ApiCall::DeleteComment { comment: action.target.id.unwrap_or_default() });
+        }
+
+        async fn get_attachment(&self, _account: &Account, card_id: &str, attachment_id: &str) -> Result<TrelloAttachment, SyncError> {
+            self.record(ApiCall::GetAttachment { card: card_id.to_string(), attachment: attachment_id.to_string() })?;
+            let attachments = self.attachments.borrow();
+            return match attachments.iter().find(|attachment| attachment.id == attachment_id) {
+                Some(attachment) => Ok(attachment.clone()),
+                None => Err(SyncError::NotFound(format!("/1/cards/{}/attachments/{}", card_id, attachment_id))),
+            };
+        }
+
+        async fn download_attachment(&self, _account: &Account, url: &str) -> Result<Vec<u8>, SyncError> {
+            self.record(ApiCall::DownloadAttachment { url: url.to_string() })?;
+            return Ok(url.as_bytes().to_vec());
+        }
+
+        async fn upload_attachment(&self, _account: &Account, card_id: &str, attachment: &ActionAttachment, _bytes: Vec<u8>) -> Result<(), SyncError> {
+            return self.record(ApiCall::UploadAttachment { card: card_id.to_string(), name: attachment.name.to_owned() });
+        }
+
+        async fn attach_link(&self, _account: &Account, card_id: &str, name: &str, link: &str) -> Result<(), SyncError> {
+            return self.record(ApiCall::AttachLink { card: card_id.to_string(), name: name.to_string(), link: link.to_string() });
+        }
+
+        async fn get_member(&self, _account: &Account, username: &str) -> Result<TrelloMember, SyncError> {
+            self.record(ApiCall::GetMember { username: username.to_string() })?;
+            let members = self.members.borrow();
+            return members.iter().find(|member| member.username == username).cloned()
+                .ok_or(SyncError::NotFound(format!("/1/members/{}", username)));
+        }
+    }
+}
@@ -0,0 +1,10 @@
+#![allow(clippy::needless_return)]
+
+// The worker itself is built from the library, this binary only exists outside of Workers
+#[cfg(not(target_arch = "wasm32"))]
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    return saas_sync::server::run();
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {}
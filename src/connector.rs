@@ -0,0 +1,105 @@
+use std::fmt::Display;
+use crate::account::Account;
+use crate::action::{Action, ActionAttachment, ActionService, ActionTargetSource, ActionType, ActionUpdate};
+use crate::database::LinkEnd;
+use crate::error::SyncError;
+use crate::users::UserDirectory;
+use crate::{slack, trello};
+
+/// A service the sync engine can link items in. Its webhooks are read as actions for whatever is at the other end
+/// of the item's link, and actions from the other end are delivered to it. Adding an integration means adding an
+/// ActionService and a Connector for it, then an arm wherever a connector is picked by service.
+pub trait Connector {
+    /// What the service's webhooks are parsed into
+    type Webhook;
+
+    fn service(&self) -> ActionService;
+
+    /// The item a webhook is about, the end of any link its action follows. None when it isn't about a linkable item.
+    fn link_end(&self, webhook: &Self::Webhook) -> Option<LinkEnd>;
+
+    /// The action for a webhook, aimed at `target`, the other end of the item's link when it has one
+    async fn parse_action<D: UserDirectory>(&self, directory: &D, account: &Account, webhook: &Self::Webhook, target: Option<&LinkEnd>, now: u64) -> Action;
+
+    /// Starts the thread or item a newly linked item's updates go to, in a container such as a Slack channel
+    async fn create_thread(&self, account: &Account, container: &str, summary: &ActionUpdate) -> Result<LinkEnd, SyncError>;
+
+    /// Replaces the summary a thread was started with
+    async fn update_thread(&self, account: &Account, thread: &LinkEnd, summary: &ActionUpdate) -> Result<(), SyncError>;
+
+    /// Posts a plain message to a thread, returning the message
+    async fn reply(&self, account: &Account, thread: &LinkEnd, update: &ActionUpdate) -> Result<LinkEnd, SyncError>;
+
+    /// Posts an action to the thread its target names, returning the message
+    async fn deliver(&self, account: &Account, action: Action) -> Result<LinkEnd, SyncError>;
+
+    /// Replaces a delivered message, the action's target names the message
+    async fn edit(&self, account: &Account, action: Action) -> Result<(), SyncError>;
+
+    async fn delete(&self, account: &Account, action: Action) -> Result<(), SyncError>;
+
+    /// Copies a file into a thread, or only links to it when there are no bytes because it is too large to copy
+    async fn attach(&self, account: &Account, thread: &LinkEnd, attachment: &ActionAttachment, bytes: Option<Vec<u8>>) -> Result<(), SyncError>;
+}
+
+/// Logs a failure in what follows a delivered message, such as saving its mapping or copying its files. Failing the
+/// event would get the message delivered again when it is retried, which is worse than an unmapped message or a file
+/// that is only linked.
+pub fn log_after_delivery<E: Display>(what: &str, result: Result<(), E>) {
+    if let Err(err) = result {
+        console_log!("Error {}: {}", what, err);
+    }
+}
+
+/// Where a person can view a linked item
+pub fn link_url(end: &LinkEnd) -> Option<String> {
+    return match end.service {
+        ActionService::Slack => Some(slack::thread_url(end.container.as_deref()?, &end.id)),
+        ActionService::Trello => Some(trello::card_url(&end.id)),
+    };
+}
+
+/// An action target for a linked item
+pub fn action_target(end: &LinkEnd) -> ActionTargetSource {
+    return ActionTargetSource {
+        id: Some(end.id.to_owned()),
+        url: link_url(end).unwrap_or_default(),
+        service: end.service.clone(),
+        channel: end.container.clone(),
+    };
+}
+
+/// The linked item an action target names
+pub fn target_end(target: &ActionTargetSource) -> LinkEnd {
+    return LinkEnd::new(target.service.clone(), target.channel.as_deref(), target.id.as_deref().unwrap_or_default());
+}
+
+/// An update for a thread that isn't the result of a webhook, such as a connector's reply
+pub fn thread_action(thread: &LinkEnd, update: &ActionUpdate) -> Action {
+    return Action {
+        action: ActionType::UpdateThread,
+        source: action_target(thread),
+        target: action_target(thread),
+        update: update.clone(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::action::ActionService;
+    use crate::connector::{action_target, link_url};
+    use crate::database::LinkEnd;
+
+    #[test]
+    fn link_urls() {
+        let thread = LinkEnd::new(ActionService::Slack, Some("C123456"), "1715287188.123456");
+        assert_eq!(Some("https://slack.com/archives/C123456/p1715287188123456".to_string()), link_url(&thread));
+        // Links from before channel routing don't say which channel the thread is in
+        assert_eq!(None, link_url(&LinkEnd { container: None, ..thread.clone() }));
+        assert_eq!(Some("https://trello.com/c/abc64ds5ad45s6161d".to_string()), link_url(&LinkEnd::new(ActionService::Trello, None, "abc64ds5ad45s6161d")));
+
+        let target = action_target(&thread);
+        assert_eq!((Some("1715287188.123456"), Some("C123456")), (target.id.as_deref(), target.channel.as_deref()));
+        assert_eq!(ActionService::Slack, target.service);
+    }
+}
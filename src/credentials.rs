@@ -0,0 +1,32 @@
+use serde::Deserialize;
+use worker::{Env, Error};
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Credentials {
+    pub slack_auth_token: Option<String>,
+    pub slack_signing_secret: Option<String>,
+    pub trello_api_key: Option<String>,
+    pub trello_api_token: Option<String>,
+    pub trello_app_secret: Option<String>,
+}
+
+pub async fn get_credentials(env: &Env, account_id: &str) -> Result<Credentials, Error> {
+    // An account without a credentials row simply has nothing configured yet
+    return Ok(crate::database::get_account_credentials(env, account_id).await?.unwrap_or_default());
+}
+
+// Used when the worker is bound to a single account through the ACCOUNT_ID secret
+pub fn get_credentials_from_env(env: &Env) -> Credentials {
+    return credentials_from_secrets(|name| env.secret(name).ok().map(|val| val.to_string()));
+}
+
+/// Credentials from the secrets named after them, such as SLACK_AUTH_TOKEN, wherever they are kept
+pub fn credentials_from_secrets<F: Fn(&str) -> Option<String>>(secret: F) -> Credentials {
+    return Credentials {
+        slack_auth_token: secret("SLACK_AUTH_TOKEN"),
+        slack_signing_secret: secret("SLACK_SIGNING_SECRET"),
+        trello_api_key: secret("TRELLO_API_KEY"),
+        trello_api_token: secret("TRELLO_API_TOKEN"),
+        trello_app_secret: secret("TRELLO_APP_SECRET"),
+    };
+}
@@ -1,82 +1,617 @@
-use std::any::{Any, TypeId};
-use serde::{de, Deserialize};
-use worker::{console_log, Env, Error, query};
+use serde::{de, Deserialize, Serialize};
+use worker::{Env, Error};
 use worker::wasm_bindgen::JsValue;
 use crate::account::Account;
+use crate::action::ActionService;
+use crate::credentials::Credentials;
+use crate::events::EventState;
+use crate::queue::Job;
+use crate::settings::Settings;
+use crate::trello::TrelloMember;
+use crate::migrations;
 
-#[derive(Deserialize)]
+// The schema version this code is written against, see the migrations directory
+pub const SCHEMA_VERSION: u32 = migrations::latest_version();
+
+/// One side of a link: an item in a service, such as a Trello card or a Slack thread
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinkEnd {
+    pub service: ActionService,
+    // Where the id is unique, such as a Slack channel, None for services whose ids are unique on their own.
+    // Links created before channel routing have no channel recorded.
+    pub container: Option<String>,
+    pub id: String,
+}
+
+impl LinkEnd {
+    pub fn new(service: ActionService, container: Option<&str>, id: &str) -> Self {
+        return LinkEnd { service, container: container.map(str::to_string), id: id.to_string() };
+    }
+}
+
+/// Pairs the item a link was started from with the thread or item created for it, in any two services
+#[derive(Deserialize, Clone, Debug)]
+#[serde(from = "LinkRow")]
 pub struct Link {
-   // pub id: u32,
-    pub slack_thread: String,
-    pub trello_card: String,
+    pub source: LinkEnd,
+    pub target: LinkEnd,
 }
 
+impl Link {
+    /// The end paired with `end`
+    pub fn other_end(&self, end: &LinkEnd) -> &LinkEnd {
+        if self.source.service == end.service && self.source.id == end.id {
+            return &self.target;
+        }
+        return &self.source;
+    }
+}
 
-pub async fn get_account(env: &Env, id: &str) -> Result<Account, Error> {
-    let query = "SELECT * FROM accounts WHERE id=?1";
-    return get_from_db_by_id(&env, query, id).await;
+#[derive(Deserialize)]
+struct LinkRow {
+    source_service: ActionService,
+    source_container: Option<String>,
+    source_id: String,
+    target_service: ActionService,
+    target_container: Option<String>,
+    // Empty while the link is claimed, see claim_link
+    target_id: Option<String>,
 }
 
-pub async fn get_link_from_slack_thread(env: &Env, slack_thread: &str) -> Result<Link, Error> {
-    let query = "SELECT * FROM links WHERE slack_thread=?1";
-    return get_from_db_by_id(&env, query, slack_thread).await;
+impl From<LinkRow> for Link {
+    fn from(row: LinkRow) -> Self {
+        return Link {
+            source: LinkEnd { service: row.source_service, container: row.source_container, id: row.source_id },
+            target: LinkEnd { service: row.target_service, container: row.target_container, id: row.target_id.unwrap_or_default() },
+        };
+    }
 }
 
-pub async fn get_link_from_trello_card(env: &Env, trello_card: &str) -> Result<Link, Error> {
-    console_log!("Searching for trello card with id {}", trello_card);
-    let query = "SELECT * FROM links WHERE trello_card=?1";
-    return get_from_db_by_id(&env, query, trello_card).await;
+#[derive(Deserialize)]
+struct SchemaVersion {
+    version: Option<u32>,
 }
 
-pub async fn create_link(env: &Env, trello_card: &str, slack_thread: &str) -> Result<TypeId, Error> {
-    console_log!("Creating link - db");
-    let db = match env.d1("DB") {
-        Ok(db) => db,
-        Err(e) => {
-            return Err(e);
-        }
+#[derive(Deserialize, Clone, Debug)]
+#[allow(dead_code)]
+pub struct ChannelMapping {
+    pub account_id: String,
+    pub trello_board: String,
+    pub trello_list: Option<String>,
+    // Where the card's thread is started, such as a Slack channel
+    pub target_service: ActionService,
+    pub target_container: String,
+}
+
+#[derive(Deserialize)]
+pub struct CachedSlackUser {
+    pub display_name: String,
+}
+
+#[derive(Deserialize)]
+pub struct CachedTrelloMember {
+    pub member_id: String,
+    pub full_name: String,
+}
+
+#[derive(Deserialize)]
+pub struct UserMapping {
+    pub slack_user: String,
+    pub trello_username: String,
+}
+
+/// Pairs a message as it was written with the copy posted for it, in any two services. Only the copy is edited or
+/// deleted to follow the message.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "LinkRow")]
+pub struct MessageMapping {
+    pub source: LinkEnd,
+    pub target: LinkEnd,
+}
+
+impl From<LinkRow> for MessageMapping {
+    fn from(row: LinkRow) -> Self {
+        let Link { source, target } = Link::from(row);
+        return MessageMapping { source, target };
+    }
+}
+
+/// A queued job that ran out of attempts, see queue::consume
+#[derive(Deserialize, Serialize, Debug)]
+pub struct DeadLetter {
+    pub id: u32,
+    pub account_id: String,
+    pub service: ActionService,
+    pub payload: String,
+    pub error: String,
+    pub attempts: u32,
+    pub failed_at: u64,
+}
+
+impl DeadLetter {
+    pub fn into_job(self) -> Job {
+        return Job {
+            account_id: self.account_id,
+            service: self.service,
+            payload: self.payload,
+            attempts: self.attempts,
+        };
+    }
+}
+
+pub const SCHEMA_VERSION_QUERY: &str = "SELECT MAX(version) AS version FROM schema_migrations";
+
+/// Errors when the database has not been migrated to the version this code expects
+pub async fn check_schema_version(env: &Env) -> Result<(), Error> {
+    let version = match get_from_db::<SchemaVersion>(env, SCHEMA_VERSION_QUERY, &[]).await {
+        Ok(result) => result.version,
+        Err(_) => None,
     };
 
-    console_log!("Creating link - statement");
-    let statement = db.prepare("insert into links values (null, ?1, ?2)");
-    console_log!("Creating link - query");
-    let query = statement.bind( &[JsValue::from(slack_thread), JsValue::from(trello_card)])?;
+    if version.is_some_and(|version| version >= SCHEMA_VERSION) {
+        return Ok(());
+    }
+
+    let pending: Vec<String> = migrations::pending_migrations(version).iter()
+        .map(|migration| migration.file_name())
+        .collect();
+    return Err(Error::RustError(format!("Database schema is at version {:?}, expected {}. Pending migrations: {}",
+                                        version, SCHEMA_VERSION, pending.join(", "))));
+}
+
+// The statements below are shared with sqlite::SqliteStore, so a native build runs the same SQL as the worker
+
+pub const GET_ACCOUNT_QUERY: &str = "SELECT * FROM accounts WHERE id=?1";
+
+pub async fn get_account(env: &Env, id: &str) -> Result<Option<Account>, Error> {
+    return first_from_db(env, GET_ACCOUNT_QUERY, &[id]).await;
+}
+
+pub const GET_ACCOUNT_CREDENTIALS_QUERY: &str = "SELECT * FROM account_credentials WHERE account_id=?1";
+
+pub async fn get_account_credentials(env: &Env, account_id: &str) -> Result<Option<Credentials>, Error> {
+    return first_from_db(env, GET_ACCOUNT_CREDENTIALS_QUERY, &[account_id]).await;
+}
+
+pub const GET_ACCOUNT_SETTINGS_QUERY: &str = "SELECT * FROM account_settings WHERE account_id=?1";
+
+pub async fn get_account_settings(env: &Env, account_id: &str) -> Result<Option<Settings>, Error> {
+    return first_from_db(env, GET_ACCOUNT_SETTINGS_QUERY, &[account_id]).await;
+}
+
+// Either end of a created link, see claim_link. Links migrated from before channel routing have no channel,
+// so they match any channel within the account.
+pub const GET_LINK_QUERY: &str = "SELECT * FROM links WHERE account_id=?1 AND target_id IS NOT NULL AND (\
+    (source_service=?2 AND source_id=?4 AND (source_container=?3 OR source_container IS NULL)) OR \
+    (target_service=?2 AND target_id=?4 AND (target_container=?3 OR target_container IS NULL)))";
+
+pub async fn get_link(env: &Env, account: &Account, end: &LinkEnd) -> Result<Option<Link>, Error> {
+    console_log!("Searching for link to {} item {}", end.service.as_str(), end.id);
+    let db = env.d1("DB")?;
+    let query = db.prepare(GET_LINK_QUERY).bind(&[
+        JsValue::from(&account.id),
+        JsValue::from(end.service.as_str()),
+        JsValue::from(end.container.as_deref()),
+        JsValue::from(&end.id),
+    ])?;
+    return query.first::<Link>(None).await;
+}
+
+// A mapping for the card's list takes priority over one for the whole board
+pub const GET_CHANNEL_MAPPING_QUERY: &str = "SELECT * FROM channel_mappings WHERE account_id=?1 AND trello_board=?2 AND (trello_list=?3 OR trello_list IS NULL) ORDER BY trello_list IS NULL LIMIT 1";
+
+pub async fn get_channel_mapping(env: &Env, account: &Account, trello_board: &str, trello_list: Option<&str>) -> Result<ChannelMapping, Error> {
+    return get_from_db(env, GET_CHANNEL_MAPPING_QUERY, &[&account.id, trello_board, trello_list.unwrap_or_default()]).await;
+}
 
-    console_log!("Creating link - result");
-    let result = match query.run().await{
-        Ok(result) => result,
+// A claim that hasn't been completed in this time is assumed to have failed part way
+pub const LINK_CLAIM_TIMEOUT_SECONDS: u64 = 60;
+
+pub const CLAIM_LINK_QUERY: &str = "INSERT INTO links (account_id, source_service, source_container, source_id, target_service, claimed_at) \
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6) \
+    ON CONFLICT (account_id, source_service, COALESCE(source_container, ''), source_id) \
+    DO UPDATE SET target_service = excluded.target_service, claimed_at = excluded.claimed_at \
+    WHERE links.target_id IS NULL AND links.claimed_at < ?7 \
+    RETURNING id";
+
+/// Reserves the link for an item before its thread is created, so only one of several
+/// simultaneous first events for the item starts a thread. Returns false if another request holds it.
+pub async fn claim_link(env: &Env, account: &Account, source: &LinkEnd, target_service: &ActionService, now: u64) -> Result<bool, Error> {
+    let db = env.d1("DB")?;
+    let stale = now.saturating_sub(LINK_CLAIM_TIMEOUT_SECONDS) as f64;
+
+    let query = db.prepare(CLAIM_LINK_QUERY).bind(&[
+        JsValue::from(&account.id),
+        JsValue::from(source.service.as_str()),
+        JsValue::from(source.container.as_deref()),
+        JsValue::from(&source.id),
+        JsValue::from(target_service.as_str()),
+        JsValue::from(now as f64),
+        JsValue::from(stale),
+    ])?;
+
+    let result = query.first::<serde_json::Value>(None).await?;
+    return Ok(result.is_some());
+}
+
+pub const CREATE_LINK_QUERY: &str = "UPDATE links SET target_service=?5, target_container=?6, target_id=?7 \
+    WHERE account_id=?1 AND source_service=?2 AND COALESCE(source_container, '')=COALESCE(?3, '') AND source_id=?4";
+
+/// Fills in the thread for a link reserved by claim_link
+pub async fn create_link(env: &Env, account: &Account, source: &LinkEnd, target: &LinkEnd) -> Result<(), Error> {
+    let db = env.d1("DB")?;
+    let statement = db.prepare(CREATE_LINK_QUERY);
+    let query = statement.bind(&[
+        JsValue::from(&account.id),
+        JsValue::from(source.service.as_str()),
+        JsValue::from(source.container.as_deref()),
+        JsValue::from(&source.id),
+        JsValue::from(target.service.as_str()),
+        JsValue::from(target.container.as_deref()),
+        JsValue::from(&target.id),
+    ])?;
+
+    match query.run().await {
+        Ok(_) => {},
         Err(e) => {
             console_log!("Error running query: {}", e.to_string());
             return Err(e);
         }
     };
+    return Ok(());
+}
 
+pub const RELEASE_LINK_QUERY: &str = "DELETE FROM links \
+    WHERE account_id=?1 AND source_service=?2 AND COALESCE(source_container, '')=COALESCE(?3, '') AND source_id=?4 AND target_id IS NULL";
 
-    console_log!("Creating link - done");
-    console_log!("{:?}",result.type_id());
-    return Ok(result.type_id());
+/// Gives up a claim whose thread could not be created
+pub async fn release_link(env: &Env, account: &Account, source: &LinkEnd) -> Result<(), Error> {
+    let db = env.d1("DB")?;
+    db.prepare(RELEASE_LINK_QUERY)
+        .bind(&[
+            JsValue::from(&account.id),
+            JsValue::from(source.service.as_str()),
+            JsValue::from(source.container.as_deref()),
+            JsValue::from(&source.id),
+        ])?
+        .run()
+        .await?;
+    return Ok(());
 }
 
-async fn get_from_db_by_id<T: de::DeserializeOwned>(env: &Env, query: &str, id: &str) -> Result<T, Error> {
-    let db = match env.d1("DB") {
-        Ok(db) => db,
-        Err(e) => {
-            return Err(e);
-        }
-    };
+pub const REPLACE_LINK_QUERY: &str = "UPDATE links SET target_service=?6, target_container=?7, target_id=?8 \
+    WHERE account_id=?1 AND source_service=?2 AND COALESCE(source_container, '')=COALESCE(?3, '') AND source_id=?4 AND target_id=?5";
 
-    let query = query!(
-          &db,
-          query,
-          id,
-        )?;
+/// Points a link at a new thread, if it still leads to the thread it did when `link` was read
+pub async fn replace_link(env: &Env, account: &Account, link: &Link, target: &LinkEnd) -> Result<(), Error> {
+    let db = env.d1("DB")?;
+    db.prepare(REPLACE_LINK_QUERY)
+        .bind(&[
+            JsValue::from(&account.id),
+            JsValue::from(link.source.service.as_str()),
+            JsValue::from(link.source.container.as_deref()),
+            JsValue::from(&link.source.id),
+            JsValue::from(&link.target.id),
+            JsValue::from(target.service.as_str()),
+            JsValue::from(target.container.as_deref()),
+            JsValue::from(&target.id),
+        ])?
+        .run()
+        .await?;
+    return Ok(());
+}
+
+pub const EXPIRE_PROCESSED_EVENTS_QUERY: &str = "DELETE FROM processed_events WHERE started_at < ?1";
 
-    let result = query.first::<T>(None).await?;
+pub const START_EVENT_QUERY: &str = "INSERT INTO processed_events (account_id, service, event_id, started_at) VALUES (?1, ?2, ?3, ?4) \
+    ON CONFLICT (account_id, service, event_id) DO UPDATE SET started_at = excluded.started_at, processed_at = NULL \
+    WHERE processed_events.processed_at IS NULL AND processed_events.started_at <= ?5 \
+    RETURNING event_id";
 
-    let item = match result {
+pub const GET_EVENT_QUERY: &str = "SELECT * FROM processed_events WHERE account_id=?1 AND service=?2 AND event_id=?3";
+
+#[derive(Deserialize)]
+pub struct ProcessedEvent {
+    // None while a delivery holds the lease
+    pub processed_at: Option<u64>,
+}
+
+impl ProcessedEvent {
+    /// The state of an event this delivery couldn't lease, None if the event has since been forgotten
+    pub fn state(event: Option<ProcessedEvent>) -> EventState {
+        return match event {
+            Some(ProcessedEvent { processed_at: Some(_) }) => EventState::Processed,
+            _ => EventState::InProgress,
+        };
+    }
+}
+
+/// Leases an event, unless it was processed less than `ttl` seconds ago or another lease is younger than `lease`.
+/// The insert is a single statement so concurrent deliveries of the same event can't both lease it.
+pub async fn start_event(env: &Env, account: &Account, service: &str, event_id: &str, now: u64, ttl: u64, lease: u64) -> Result<EventState, Error> {
+    let db = env.d1("DB")?;
+
+    db.prepare(EXPIRE_PROCESSED_EVENTS_QUERY)
+        .bind(&[JsValue::from(now.saturating_sub(ttl) as f64)])?
+        .run()
+        .await?;
+
+    let statement = db.prepare(START_EVENT_QUERY);
+    let query = statement.bind(&[
+        JsValue::from(&account.id),
+        JsValue::from(service),
+        JsValue::from(event_id),
+        JsValue::from(now as f64),
+        JsValue::from(now.saturating_sub(lease) as f64),
+    ])?;
+
+    // A row is only returned when this call inserted or took over the lease
+    if query.first::<serde_json::Value>(None).await?.is_some() {
+        return Ok(EventState::Started);
+    }
+    return Ok(ProcessedEvent::state(first_from_db(env, GET_EVENT_QUERY, &[&account.id, service, event_id]).await?));
+}
+
+pub const FINISH_EVENT_QUERY: &str = "UPDATE processed_events SET processed_at=?4 WHERE account_id=?1 AND service=?2 AND event_id=?3";
+
+pub async fn finish_event(env: &Env, account: &Account, service: &str, event_id: &str, now: u64) -> Result<(), Error> {
+    let db = env.d1("DB")?;
+    db.prepare(FINISH_EVENT_QUERY)
+        .bind(&[JsValue::from(&account.id), JsValue::from(service), JsValue::from(event_id), JsValue::from(now as f64)])?
+        .run()
+        .await?;
+    return Ok(());
+}
+
+pub const FORGET_PROCESSED_EVENT_QUERY: &str = "DELETE FROM processed_events WHERE account_id=?1 AND service=?2 AND event_id=?3";
+
+pub async fn forget_processed_event(env: &Env, account: &Account, service: &str, event_id: &str) -> Result<(), Error> {
+    let db = env.d1("DB")?;
+    db.prepare(FORGET_PROCESSED_EVENT_QUERY)
+        .bind(&[JsValue::from(&account.id), JsValue::from(service), JsValue::from(event_id)])?
+        .run()
+        .await?;
+    return Ok(());
+}
+
+pub const GET_CACHED_SLACK_USER_QUERY: &str = "SELECT display_name FROM slack_users WHERE account_id=?1 AND slack_user=?2 AND fetched_at >= ?3";
+
+/// A cached display name, ignoring any fetched before `fetched_after`
+pub async fn get_cached_slack_user(env: &Env, account: &Account, slack_user: &str, fetched_after: u64) -> Result<Option<CachedSlackUser>, Error> {
+    let db = env.d1("DB")?;
+    let query = db.prepare(GET_CACHED_SLACK_USER_QUERY)
+        .bind(&[JsValue::from(&account.id), JsValue::from(slack_user), JsValue::from(fetched_after as f64)])?;
+    return query.first::<CachedSlackUser>(None).await;
+}
+
+pub const CACHE_SLACK_USER_QUERY: &str = "INSERT INTO slack_users (account_id, slack_user, display_name, fetched_at) VALUES (?1, ?2, ?3, ?4) \
+    ON CONFLICT (account_id, slack_user) DO UPDATE SET display_name = excluded.display_name, fetched_at = excluded.fetched_at";
+
+pub async fn cache_slack_user(env: &Env, account: &Account, slack_user: &str, display_name: &str, now: u64) -> Result<(), Error> {
+    let db = env.d1("DB")?;
+    db.prepare(CACHE_SLACK_USER_QUERY)
+        .bind(&[JsValue::from(&account.id), JsValue::from(slack_user), JsValue::from(display_name), JsValue::from(now as f64)])?
+        .run()
+        .await?;
+    return Ok(());
+}
+
+pub const GET_CACHED_TRELLO_MEMBER_QUERY: &str = "SELECT member_id, full_name FROM trello_members WHERE account_id=?1 AND username=?2 AND fetched_at >= ?3";
+
+/// A cached member, ignoring any fetched before `fetched_after`
+pub async fn get_cached_trello_member(env: &Env, account: &Account, username: &str, fetched_after: u64) -> Result<Option<CachedTrelloMember>, Error> {
+    let db = env.d1("DB")?;
+    let query = db.prepare(GET_CACHED_TRELLO_MEMBER_QUERY)
+        .bind(&[JsValue::from(&account.id), JsValue::from(username), JsValue::from(fetched_after as f64)])?;
+    return query.first::<CachedTrelloMember>(None).await;
+}
+
+pub const CACHE_TRELLO_MEMBER_QUERY: &str = "INSERT INTO trello_members (account_id, username, member_id, full_name, fetched_at) VALUES (?1, ?2, ?3, ?4, ?5) \
+    ON CONFLICT (account_id, username) DO UPDATE SET member_id = excluded.member_id, full_name = excluded.full_name, fetched_at = excluded.fetched_at";
+
+pub async fn cache_trello_member(env: &Env, account: &Account, username: &str, member: &TrelloMember, now: u64) -> Result<(), Error> {
+    let db = env.d1("DB")?;
+    db.prepare(CACHE_TRELLO_MEMBER_QUERY)
+        .bind(&[JsValue::from(&account.id), JsValue::from(username), JsValue::from(&member.id), JsValue::from(&member.full_name), JsValue::from(now as f64)])?
+        .run()
+        .await?;
+    return Ok(());
+}
+
+pub const GET_USER_MAPPING_BY_SLACK_USER_QUERY: &str = "SELECT * FROM user_mappings WHERE account_id=?1 AND slack_user=?2";
+
+pub async fn get_user_mapping_by_slack_user(env: &Env, account: &Account, slack_user: &str) -> Result<Option<UserMapping>, Error> {
+    let db = env.d1("DB")?;
+    let query = db.prepare(GET_USER_MAPPING_BY_SLACK_USER_QUERY)
+        .bind(&[JsValue::from(&account.id), JsValue::from(slack_user)])?;
+    return query.first::<UserMapping>(None).await;
+}
+
+pub const GET_USER_MAPPING_BY_TRELLO_USERNAME_QUERY: &str = "SELECT * FROM user_mappings WHERE account_id=?1 AND trello_username=?2";
+
+pub async fn get_user_mapping_by_trello_username(env: &Env, account: &Account, trello_username: &str) -> Result<Option<UserMapping>, Error> {
+    let db = env.d1("DB")?;
+    let query = db.prepare(GET_USER_MAPPING_BY_TRELLO_USERNAME_QUERY)
+        .bind(&[JsValue::from(&account.id), JsValue::from(trello_username)])?;
+    return query.first::<UserMapping>(None).await;
+}
+
+pub const CREATE_MESSAGE_MAPPING_QUERY: &str = "INSERT INTO message_mappings \
+    (account_id, source_service, source_container, source_id, target_service, target_container, target_id) \
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) ON CONFLICT DO NOTHING";
+
+/// Remembers the copy posted for a message. A message that is already mapped keeps its first copy.
+pub async fn create_message_mapping(env: &Env, account: &Account, source: &LinkEnd, target: &LinkEnd) -> Result<(), Error> {
+    let db = env.d1("DB")?;
+    db.prepare(CREATE_MESSAGE_MAPPING_QUERY)
+        .bind(&[
+            JsValue::from(&account.id),
+            JsValue::from(source.service.as_str()),
+            JsValue::from(source.container.as_deref()),
+            JsValue::from(&source.id),
+            JsValue::from(target.service.as_str()),
+            JsValue::from(target.container.as_deref()),
+            JsValue::from(&target.id),
+        ])?
+        .run()
+        .await?;
+    return Ok(());
+}
+
+// The mapping with either the message or its copy at `end`
+pub const GET_MESSAGE_MAPPING_QUERY: &str = "SELECT * FROM message_mappings WHERE account_id=?1 AND (\
+    (source_service=?2 AND COALESCE(source_container, '')=COALESCE(?3, '') AND source_id=?4) OR \
+    (target_service=?2 AND COALESCE(target_container, '')=COALESCE(?3, '') AND target_id=?4))";
+
+pub async fn get_message_mapping(env: &Env, account: &Account, end: &LinkEnd) -> Result<Option<MessageMapping>, Error> {
+    let db = env.d1("DB")?;
+    let query = db.prepare(GET_MESSAGE_MAPPING_QUERY).bind(&[
+        JsValue::from(&account.id),
+        JsValue::from(end.service.as_str()),
+        JsValue::from(end.container.as_deref()),
+        JsValue::from(&end.id),
+    ])?;
+    return query.first::<MessageMapping>(None).await;
+}
+
+pub const DELETE_MESSAGE_MAPPING_QUERY: &str = "DELETE FROM message_mappings WHERE account_id=?1 AND (\
+    (source_service=?2 AND COALESCE(source_container, '')=COALESCE(?3, '') AND source_id=?4) OR \
+    (target_service=?2 AND COALESCE(target_container, '')=COALESCE(?3, '') AND target_id=?4))";
+
+pub async fn delete_message_mapping(env: &Env, account: &Account, end: &LinkEnd) -> Result<(), Error> {
+    let db = env.d1("DB")?;
+    db.prepare(DELETE_MESSAGE_MAPPING_QUERY)
+        .bind(&[
+            JsValue::from(&account.id),
+            JsValue::from(end.service.as_str()),
+            JsValue::from(end.container.as_deref()),
+            JsValue::from(&end.id),
+        ])?
+        .run()
+        .await?;
+    return Ok(());
+}
+
+pub const CREATE_DEAD_LETTER_QUERY: &str = "INSERT INTO dead_letters (account_id, service, payload, error, attempts, failed_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+
+pub async fn create_dead_letter(env: &Env, job: &Job, error: &str, now: u64) -> Result<(), Error> {
+    let db = env.d1("DB")?;
+    db.prepare(CREATE_DEAD_LETTER_QUERY)
+        .bind(&[
+            JsValue::from(&job.account_id),
+            JsValue::from(job.service.as_str()),
+            JsValue::from(&job.payload),
+            JsValue::from(error),
+            JsValue::from(job.attempts),
+            JsValue::from(now as f64),
+        ])?
+        .run()
+        .await?;
+    return Ok(());
+}
+
+// The most recent first, a backlog this long needs looking at in the database itself
+pub const GET_DEAD_LETTERS_QUERY: &str = "SELECT * FROM dead_letters ORDER BY failed_at DESC LIMIT 100";
+
+pub async fn get_dead_letters(env: &Env) -> Result<Vec<DeadLetter>, Error> {
+    let db = env.d1("DB")?;
+    let result = db.prepare(GET_DEAD_LETTERS_QUERY).all().await?;
+    return result.results::<DeadLetter>();
+}
+
+pub const TAKE_DEAD_LETTER_QUERY: &str = "DELETE FROM dead_letters WHERE id=?1 RETURNING *";
+
+/// Removes a dead letter so it can be replayed, in one statement so it is only replayed once
+pub async fn take_dead_letter(env: &Env, id: u32) -> Result<Option<DeadLetter>, Error> {
+    let db = env.d1("DB")?;
+    let query = db.prepare(TAKE_DEAD_LETTER_QUERY).bind(&[JsValue::from(id)])?;
+    return query.first::<DeadLetter>(None).await;
+}
+
+async fn get_from_db<T: de::DeserializeOwned>(env: &Env, query: &str, params: &[&str]) -> Result<T, Error> {
+    let item = match first_from_db(env, query, params).await? {
         Some(item) => item,
         None => return Err(Error::RustError("No results found".to_string())),
     };
 
     return Ok(item);
-}
\ No newline at end of file
+}
+
+// None only when there is no row, query errors are returned
+async fn first_from_db<T: de::DeserializeOwned>(env: &Env, query: &str, params: &[&str]) -> Result<Option<T>, Error> {
+    let db = env.d1("DB")?;
+    let params: Vec<JsValue> = params.iter().map(|param| JsValue::from(*param)).collect();
+    let query = db.prepare(query).bind(&params)?;
+    return query.first::<T>(None).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{params, Connection, OptionalExtension};
+    use crate::database::{CACHE_SLACK_USER_QUERY, CLAIM_LINK_QUERY, CREATE_DEAD_LETTER_QUERY, GET_DEAD_LETTERS_QUERY, TAKE_DEAD_LETTER_QUERY};
+    use crate::migrations::tests::{migrate, open_database};
+
+    fn claim(connection: &Connection, card: &str, now: u64) -> bool {
+        return connection.query_row(CLAIM_LINK_QUERY, params!["account", "trello", None::<String>, card, "slack", now, now - 60], |row| row.get::<_, i64>(0))
+            .optional()
+            .expect("Error claiming link")
+            .is_some();
+    }
+
+    #[test]
+    fn claim_link_once_per_card() {
+        let connection = open_database();
+        migrate(&connection, None);
+        connection.execute("insert into accounts values ('account', 'Test Account')", []).unwrap();
+
+        assert!(claim(&connection, "card", 1000));
+        assert!(!claim(&connection, "card", 1001));
+        assert!(claim(&connection, "other-card", 1001));
+    }
+
+    #[test]
+    fn claim_link_after_abandoned_claim() {
+        let connection = open_database();
+        migrate(&connection, None);
+        connection.execute("insert into accounts values ('account', 'Test Account')", []).unwrap();
+
+        assert!(claim(&connection, "card", 1000));
+        assert!(claim(&connection, "card", 1100));
+
+        connection.execute("UPDATE links SET target_id='1.1', target_container='C1' WHERE source_id='card'", []).unwrap();
+        assert!(!claim(&connection, "card", 1300));
+    }
+
+    #[test]
+    fn cache_slack_user_replaces_name() {
+        let connection = open_database();
+        migrate(&connection, None);
+        connection.execute("insert into accounts values ('account', 'Test Account')", []).unwrap();
+
+        connection.execute(CACHE_SLACK_USER_QUERY, params!["account", "U123", "Alice", 1000]).unwrap();
+        connection.execute(CACHE_SLACK_USER_QUERY, params!["account", "U123", "Alice Smith", 2000]).unwrap();
+
+        let (name, fetched_at): (String, u64) = connection.query_row("SELECT display_name, fetched_at FROM slack_users WHERE slack_user='U123'", [],
+                                                                     |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!("Alice Smith", name);
+        assert_eq!(2000, fetched_at);
+    }
+
+    #[test]
+    fn take_dead_letter_once() {
+        let connection = open_database();
+        migrate(&connection, None);
+        connection.execute(CREATE_DEAD_LETTER_QUERY, params!["account", "slack", "{}", "Network error: timeout", 5, 1000]).unwrap();
+        connection.execute(CREATE_DEAD_LETTER_QUERY, params!["account", "trello", "{}", "Network error: timeout", 5, 2000]).unwrap();
+        let services: Vec<String> = connection.prepare(GET_DEAD_LETTERS_QUERY).unwrap()
+            .query_map([], |row| row.get("service")).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(vec!["trello", "slack"], services);
+
+        let take = |id: u32| connection.query_row(TAKE_DEAD_LETTER_QUERY, params![id], |row| row.get::<_, String>("service"))
+            .optional()
+            .expect("Error taking dead letter");
+        assert_eq!(Some("slack".to_string()), take(1));
+        assert_eq!(None, take(1));
+    }
+}
@@ -0,0 +1,72 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use worker::Response;
+
+#[derive(Debug)]
+pub enum SyncError {
+    /// The request never got a response
+    Network(String),
+    /// Credentials are missing or were rejected
+    Auth(String),
+    RateLimited { retry_after: Option<Duration> },
+    NotFound(String),
+    /// The service responded with something we couldn't use
+    BadResponse(String),
+    /// Errors from the worker runtime, such as D1
+    Worker(worker::Error),
+    /// Another delivery of the same event is still being processed
+    InProgress(String),
+}
+
+impl SyncError {
+    pub fn status_code(&self) -> u16 {
+        return match self {
+            SyncError::Network(_) => 502,
+            SyncError::Auth(_) => 500,
+            SyncError::RateLimited { .. } => 503,
+            SyncError::NotFound(_) => 404,
+            SyncError::BadResponse(_) => 502,
+            SyncError::Worker(_) => 500,
+            SyncError::InProgress(_) => 409,
+        };
+    }
+
+    pub fn to_response(&self) -> worker::Result<Response> {
+        let mut response = Response::error(self.to_string(), self.status_code())?;
+        if let SyncError::RateLimited { retry_after: Some(retry_after) } = self {
+            response.headers_mut().set("Retry-After", &retry_after.as_secs().to_string())?;
+        }
+        return Ok(response);
+    }
+}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            SyncError::Network(message) => write!(f, "Network error: {}", message),
+            SyncError::Auth(message) => write!(f, "Authentication error: {}", message),
+            SyncError::RateLimited { retry_after } => write!(f, "Rate limited, retry after {:?}", retry_after),
+            SyncError::NotFound(message) => write!(f, "Not found: {}", message),
+            SyncError::BadResponse(message) => write!(f, "Bad response: {}", message),
+            SyncError::Worker(err) => write!(f, "{}", err),
+            SyncError::InProgress(event_id) => write!(f, "Event {} is already being processed", event_id),
+        };
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+impl From<worker::Error> for SyncError {
+    fn from(err: worker::Error) -> Self {
+        return SyncError::Worker(err);
+    }
+}
+
+impl From<reqwest::Error> for SyncError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            return SyncError::BadResponse(err.to_string());
+        }
+        return SyncError::Network(err.to_string());
+    }
+}
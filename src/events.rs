@@ -0,0 +1,157 @@
+use std::future::Future;
+use worker::Error;
+use crate::account::Account;
+use crate::action::ActionService;
+use crate::connector::log_after_delivery;
+use crate::error::SyncError;
+
+// Slack and Trello both give up retrying well within a day
+pub const PROCESSED_EVENT_TTL_SECONDS: u64 = 60 * 60 * 24;
+
+// A delivery still processing an event after this is assumed to have been killed part way
+pub const EVENT_LEASE_SECONDS: u64 = 60;
+
+/// What a delivery found when it tried to start on an event
+#[derive(Debug, PartialEq)]
+pub enum EventState {
+    /// This delivery holds the lease and should process the event
+    Started,
+    /// Another delivery holds a fresh lease on the event
+    InProgress,
+    /// The event was processed within the TTL
+    Processed,
+}
+
+pub trait ProcessedEvents {
+    /// Leases the event for processing, unless it was processed within the TTL or another delivery's lease is younger
+    /// than EVENT_LEASE_SECONDS
+    async fn start(&self, account: &Account, service: &ActionService, event_id: &str, now: u64) -> Result<EventState, Error>;
+
+    /// Records a leased event as processed
+    async fn finish(&self, account: &Account, service: &ActionService, event_id: &str, now: u64) -> Result<(), Error>;
+
+    /// Removes a recorded event so a retried delivery is processed again
+    async fn forget(&self, account: &Account, service: &ActionService, event_id: &str) -> Result<(), Error>;
+}
+
+/// Runs `process` only for the first delivery of an event, returning whether it ran.
+/// A delivery that arrives while another is still processing the event is an InProgress error, so the sender retries
+/// it rather than taking it as done. If processing fails the event is forgotten again so the sender's retry can succeed.
+pub async fn process_once<E, F, Fut>(events: &E, account: &Account, service: &ActionService, event_id: &str, now: u64, process: F) -> Result<bool, SyncError>
+where
+    E: ProcessedEvents,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<(), SyncError>>,
+{
+    match events.start(account, service, event_id, now).await? {
+        EventState::Started => {}
+        EventState::InProgress => return Err(SyncError::InProgress(event_id.to_string())),
+        EventState::Processed => return Ok(false),
+    }
+
+    if let Err(err) = process().await {
+        events.forget(account, service, event_id).await?;
+        return Err(err);
+    }
+    log_after_delivery("recording processed event", events.finish(account, service, event_id, now).await);
+    return Ok(true);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use worker::Error;
+    use crate::account::Account;
+    use crate::action::ActionService;
+    use crate::error::SyncError;
+    use crate::events::{process_once, EventState, ProcessedEvents, EVENT_LEASE_SECONDS, PROCESSED_EVENT_TTL_SECONDS};
+
+    type EventKey = (String, &'static str, String);
+
+    // Started and processed times by account, service and event id
+    #[derive(Default)]
+    pub struct MemoryProcessedEvents {
+        events: RefCell<HashMap<EventKey, (u64, Option<u64>)>>,
+    }
+
+    impl ProcessedEvents for MemoryProcessedEvents {
+        async fn start(&self, account: &Account, service: &ActionService, event_id: &str, now: u64) -> Result<EventState, Error> {
+            let key = (account.id.to_owned(), service.as_str(), event_id.to_owned());
+            let mut events = self.events.borrow_mut();
+            match events.get(&key) {
+                Some((started_at, _)) if started_at + PROCESSED_EVENT_TTL_SECONDS <= now => {}
+                Some((_, Some(_))) => return Ok(EventState::Processed),
+                Some((started_at, None)) if started_at + EVENT_LEASE_SECONDS > now => return Ok(EventState::InProgress),
+                _ => {}
+            }
+            events.insert(key, (now, None));
+            return Ok(EventState::Started);
+        }
+
+        async fn finish(&self, account: &Account, service: &ActionService, event_id: &str, now: u64) -> Result<(), Error> {
+            if let Some(event) = self.events.borrow_mut().get_mut(&(account.id.to_owned(), service.as_str(), event_id.to_owned())) {
+                event.1 = Some(now);
+            }
+            return Ok(());
+        }
+
+        async fn forget(&self, account: &Account, service: &ActionService, event_id: &str) -> Result<(), Error> {
+            self.events.borrow_mut().remove(&(account.id.to_owned(), service.as_str(), event_id.to_owned()));
+            return Ok(());
+        }
+    }
+
+    pub fn test_account(id: &str) -> Account {
+        return Account {
+            id: id.to_string(),
+            name: "Test Account".to_string(),
+            credentials: Default::default(),
+            settings: Default::default(),
+        };
+    }
+
+    #[tokio::test]
+    async fn processed_events_expire_after_ttl() {
+        let events = MemoryProcessedEvents::default();
+        let account = test_account("account");
+
+        assert_eq!(EventState::Started, events.start(&account, &ActionService::Slack, "EVENT_ID", 100).await.unwrap());
+        events.finish(&account, &ActionService::Slack, "EVENT_ID", 101).await.unwrap();
+        assert_eq!(EventState::Processed, events.start(&account, &ActionService::Slack, "EVENT_ID", 200).await.unwrap());
+        assert_eq!(EventState::Started, events.start(&account, &ActionService::Trello, "EVENT_ID", 200).await.unwrap());
+        assert_eq!(EventState::Started, events.start(&test_account("other"), &ActionService::Slack, "EVENT_ID", 200).await.unwrap());
+        assert_eq!(EventState::Started, events.start(&account, &ActionService::Slack, "EVENT_ID", 100 + PROCESSED_EVENT_TTL_SECONDS).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn process_once_retries_after_failure() {
+        let events = MemoryProcessedEvents::default();
+        let account = test_account("account");
+
+        let failed = process_once(&events, &account, &ActionService::Trello, "ACTION_ID", 100, || async {
+            Err(SyncError::Network("Slack is down".to_string()))
+        }).await;
+        assert!(failed.is_err());
+
+        let retried = process_once(&events, &account, &ActionService::Trello, "ACTION_ID", 130, || async { Ok(()) }).await;
+        assert!(retried.unwrap());
+
+        let duplicate = process_once(&events, &account, &ActionService::Trello, "ACTION_ID", 160, || async { Ok(()) }).await;
+        assert!(!duplicate.unwrap());
+    }
+
+    #[tokio::test]
+    async fn process_once_retries_during_and_after_an_abandoned_delivery() {
+        let events = MemoryProcessedEvents::default();
+        let account = test_account("account");
+        // A delivery that was killed part way, before it could finish or forget the event
+        assert_eq!(EventState::Started, events.start(&account, &ActionService::Slack, "EVENT_ID", 100).await.unwrap());
+
+        let in_flight = process_once(&events, &account, &ActionService::Slack, "EVENT_ID", 130, || async { Ok(()) }).await;
+        assert!(matches!(in_flight, Err(SyncError::InProgress(_))));
+
+        let retried = process_once(&events, &account, &ActionService::Slack, "EVENT_ID", 100 + EVENT_LEASE_SECONDS, || async { Ok(()) }).await;
+        assert!(retried.unwrap());
+    }
+}
@@ -0,0 +1,452 @@
+//! Converts message text between Trello's markdown and Slack's mrkdwn.
+//!
+//! Both formats share backtick code spans and fenced code blocks, but differ for emphasis, links and lists. Slack
+//! also requires `&`, `<` and `>` to be escaped everywhere, as `<` starts a link or mention.
+
+use std::collections::HashMap;
+
+const CODE_FENCE: &str = "```";
+
+/// Replacement text for mentions, keyed on the Slack user id or Trello username being mentioned
+pub type Mentions = HashMap<String, String>;
+
+/// Converts Trello markdown into Slack mrkdwn
+pub fn trello_to_slack(text: &str, mentions: &Mentions) -> String {
+    let mut output = String::new();
+    for (index, segment) in text.split(CODE_FENCE).enumerate() {
+        if index % 2 == 1 {
+            // Slack has no syntax highlighting, so a language tag would show as the first line of code
+            output.push_str(CODE_FENCE);
+            output.push_str(&escape(strip_language(segment)));
+            output.push_str(CODE_FENCE);
+        } else {
+            let lines: Vec<String> = segment.split('\n').map(|line| trello_line_to_slack(line, mentions)).collect();
+            output.push_str(&lines.join("\n"));
+        }
+    }
+    return output;
+}
+
+/// Converts Slack mrkdwn into Trello markdown
+pub fn slack_to_trello(text: &str, mentions: &Mentions) -> String {
+    let mut output = String::new();
+    for (index, segment) in text.split(CODE_FENCE).enumerate() {
+        if index % 2 == 1 {
+            // Slack allows code on the same line as the fence, Trello needs the fences on their own lines
+            let code = unescape(segment);
+            if !output.is_empty() && !output.ends_with('\n') {
+                output.truncate(output.trim_end_matches(' ').len());
+                output.push('\n');
+            }
+            output.push_str(CODE_FENCE);
+            if !code.starts_with('\n') {
+                output.push('\n');
+            }
+            output.push_str(&code);
+            if !code.ends_with('\n') {
+                output.push('\n');
+            }
+            output.push_str(CODE_FENCE);
+        } else {
+            let mut segment = segment;
+            if index > 0 && !segment.is_empty() && !segment.starts_with('\n') {
+                segment = segment.trim_start_matches(' ');
+                output.push('\n');
+            }
+            let lines: Vec<String> = segment.split('\n').map(|line| slack_line_to_trello(line, mentions)).collect();
+            output.push_str(&lines.join("\n"));
+        }
+    }
+    return output;
+}
+
+fn trello_line_to_slack(line: &str, mentions: &Mentions) -> String {
+    let content = line.trim_start();
+    let indent = &line[..line.len() - content.len()];
+
+    for marker in ["- ", "* ", "+ "] {
+        if let Some(item) = content.strip_prefix(marker) {
+            return format!("{}• {}", indent, trello_inline_to_slack(item, mentions));
+        }
+    }
+    if let Some(quote) = content.strip_prefix('>') {
+        return format!("{}>{}", indent, trello_inline_to_slack(quote, mentions));
+    }
+    let heading = content.trim_start_matches('#');
+    if heading.len() < content.len() && heading.starts_with(' ') {
+        // Slack has no headings, bold is the closest
+        return format!("{}*{}*", indent, trello_inline_to_slack(heading.trim(), mentions));
+    }
+    return format!("{}{}", indent, trello_inline_to_slack(content, mentions));
+}
+
+fn slack_line_to_trello(line: &str, mentions: &Mentions) -> String {
+    let content = line.trim_start();
+    let indent = &line[..line.len() - content.len()];
+
+    for marker in ["• ", "◦ ", "▪︎ "] {
+        if let Some(item) = content.strip_prefix(marker) {
+            return format!("{}- {}", indent, slack_inline_to_trello(item, mentions));
+        }
+    }
+    return format!("{}{}", indent, slack_inline_to_trello(content, mentions));
+}
+
+fn trello_inline_to_slack(text: &str, mentions: &Mentions) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let current = chars[i];
+        if current == '\\' && i + 1 < chars.len() && chars[i + 1].is_ascii_punctuation() {
+            output.push_str(&escape(&chars[i + 1].to_string()));
+            i += 2;
+            continue;
+        }
+        if current == '`' {
+            if let Some(end) = find_char(&chars, i + 1, '`') {
+                output.push_str(&format!("`{}`", escape(&collect(&chars[i + 1..end]))));
+                i = end + 1;
+                continue;
+            }
+        }
+        if current == '@' {
+            if let Some(username) = trello_mention_at(&chars, i) {
+                if let Some(replacement) = mentions.get(&username) {
+                    output.push_str(replacement);
+                    i += 1 + username.chars().count();
+                    continue;
+                }
+            }
+        }
+        if current == '[' {
+            if let Some((label, url, end)) = parse_markdown_link(&chars, i) {
+                let label = trello_inline_to_slack(&label, mentions);
+                if label == escape(&url) {
+                    output.push_str(&format!("<{}>", escape(&url)));
+                } else {
+                    output.push_str(&format!("<{}|{}>", escape(&url), label));
+                }
+                i = end + 1;
+                continue;
+            }
+        }
+        if let Some((delimiter, replacement)) = [("**", "*"), ("__", "*"), ("~~", "~"), ("*", "_"), ("_", "_")]
+            .into_iter()
+            .find(|(delimiter, _)| starts_with(&chars, i, delimiter)) {
+            if let Some(end) = find_closing(&chars, i, delimiter) {
+                let inner = collect(&chars[i + delimiter.len()..end]);
+                output.push_str(&format!("{}{}{}", replacement, trello_inline_to_slack(&inner, mentions), replacement));
+                i = end + delimiter.len();
+                continue;
+            }
+        }
+        output.push_str(&escape(&current.to_string()));
+        i += 1;
+    }
+    return output;
+}
+
+fn slack_inline_to_trello(text: &str, mentions: &Mentions) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let current = chars[i];
+        if current == '`' {
+            if let Some(end) = find_char(&chars, i + 1, '`') {
+                output.push_str(&format!("`{}`", unescape(&collect(&chars[i + 1..end]))));
+                i = end + 1;
+                continue;
+            }
+        }
+        if current == '<' {
+            if let Some(end) = find_char(&chars, i + 1, '>') {
+                output.push_str(&slack_link_to_trello(&collect(&chars[i + 1..end]), mentions));
+                i = end + 1;
+                continue;
+            }
+        }
+        if let Some((delimiter, replacement)) = [("*", "**"), ("_", "_"), ("~", "~~")]
+            .into_iter()
+            .find(|(delimiter, _)| starts_with(&chars, i, delimiter)) {
+            if let Some(end) = find_closing(&chars, i, delimiter) {
+                let inner = collect(&chars[i + 1..end]);
+                output.push_str(&format!("{}{}{}", replacement, slack_inline_to_trello(&inner, mentions), replacement));
+                i = end + 1;
+                continue;
+            }
+        }
+        match ["&amp;", "&lt;", "&gt;"].into_iter().find(|entity| starts_with(&chars, i, entity)) {
+            Some(entity) => {
+                output.push_str(&unescape(entity));
+                i += entity.len();
+            }
+            None => {
+                output.push(current);
+                i += 1;
+            }
+        }
+    }
+    return output;
+}
+
+/// Converts the inside of a Slack `<...>` link, mention or special command
+fn slack_link_to_trello(link: &str, mentions: &Mentions) -> String {
+    let link = unescape(link);
+    let (target, label) = match link.split_once('|') {
+        Some((target, label)) => (target, Some(label)),
+        None => (link.as_str(), None),
+    };
+    if let Some(user) = target.strip_prefix('@') {
+        return match mentions.get(user) {
+            Some(replacement) => replacement.to_owned(),
+            None => format!("@{}", label.unwrap_or(user)),
+        };
+    }
+    if let Some(channel) = target.strip_prefix('#') {
+        return format!("#{}", label.unwrap_or(channel));
+    }
+    if let Some(command) = target.strip_prefix('!') {
+        return match label {
+            Some(label) => label.to_string(),
+            None => format!("@{}", command),
+        };
+    }
+    return match label {
+        Some(label) if label != target => format!("[{}]({})", label, target),
+        _ => target.to_string(),
+    };
+}
+
+/// Strikes through each line of converted text with `delimiter`, `~~` for Trello or `~` for Slack.
+/// Code blocks are left alone as neither app formats inside them.
+pub fn strike_through(text: &str, delimiter: &str) -> String {
+    let mut in_code = false;
+    let lines: Vec<String> = text.split('\n').map(|line| {
+        if line.matches(CODE_FENCE).count() % 2 == 1 {
+            in_code = !in_code;
+            return line.to_string();
+        }
+        if in_code || line.trim().is_empty() || line.contains(CODE_FENCE) {
+            return line.to_string();
+        }
+        return format!("{}{}{}", delimiter, line, delimiter);
+    }).collect();
+    return lines.join("\n");
+}
+
+/// Slack user ids mentioned as `<@U123>`
+pub fn slack_mentions(text: &str) -> Vec<String> {
+    let mut users: Vec<String> = vec![];
+    for part in text.split("<@").skip(1) {
+        let user = match part.split_once('>') {
+            Some((user, _)) => user.split('|').next().unwrap_or_default(),
+            None => continue,
+        };
+        if !user.is_empty() && !users.iter().any(|existing| existing == user) {
+            users.push(user.to_string());
+        }
+    }
+    return users;
+}
+
+/// Trello usernames mentioned as `@username`
+pub fn trello_mentions(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut usernames: Vec<String> = vec![];
+    for (i, c) in chars.iter().enumerate() {
+        if *c != '@' {
+            continue;
+        }
+        if let Some(username) = trello_mention_at(&chars, i) {
+            if !usernames.contains(&username) {
+                usernames.push(username);
+            }
+        }
+    }
+    return usernames;
+}
+
+// Trello's own @card and @board notify everyone on the card or board, they aren't members
+const TRELLO_GROUP_MENTIONS: [&str; 2] = ["card", "board"];
+
+/// The username mentioned by an `@` at `start`. Trello usernames are lowercase letters, numbers and underscores,
+/// and an `@` inside a word is an email address rather than a mention.
+fn trello_mention_at(chars: &[char], start: usize) -> Option<String> {
+    if start > 0 && chars[start - 1].is_alphanumeric() {
+        return None;
+    }
+    let username: String = chars[start + 1..].iter()
+        .take_while(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || **c == '_')
+        .collect();
+    if username.is_empty() || TRELLO_GROUP_MENTIONS.contains(&username.as_str()) {
+        return None;
+    }
+    return Some(username);
+}
+
+/// Parses `[label](url)` starting at `start`, returning the label, url and index of the closing `)`
+fn parse_markdown_link(chars: &[char], start: usize) -> Option<(String, String, usize)> {
+    let label_end = find_char(chars, start + 1, ']')?;
+    if chars.get(label_end + 1) != Some(&'(') {
+        return None;
+    }
+    let url_end = find_char(chars, label_end + 2, ')')?;
+    let url = collect(&chars[label_end + 2..url_end]);
+    if url.is_empty() || url.contains(char::is_whitespace) {
+        return None;
+    }
+    return Some((collect(&chars[start + 1..label_end]), url, url_end));
+}
+
+/// Finds the closing emphasis delimiter for the one at `start`.
+///
+/// Like both apps, emphasis has to hug its text (`*bold*` but not `* not bold *`) and can't start or end inside a
+/// word, so `snake_case_names` are left alone.
+fn find_closing(chars: &[char], start: usize, delimiter: &str) -> Option<usize> {
+    let length = delimiter.chars().count();
+    let marker = delimiter.chars().next()?;
+    let first = chars.get(start + length)?;
+    if first.is_whitespace() || *first == marker {
+        return None;
+    }
+    if start > 0 && chars[start - 1].is_alphanumeric() {
+        return None;
+    }
+
+    let mut end = start + length + 1;
+    while end + length <= chars.len() {
+        let matches = starts_with(chars, end, delimiter)
+            && !chars[end - 1].is_whitespace()
+            && chars[end - 1] != marker
+            && chars.get(end + length).is_none_or(|next| !next.is_alphanumeric() && *next != marker);
+        if matches {
+            return Some(end);
+        }
+        end += 1;
+    }
+    return None;
+}
+
+fn find_char(chars: &[char], start: usize, target: char) -> Option<usize> {
+    return chars.iter().skip(start).position(|c| *c == target).map(|position| position + start);
+}
+
+fn starts_with(chars: &[char], start: usize, prefix: &str) -> bool {
+    return prefix.chars().enumerate().all(|(offset, c)| chars.get(start + offset) == Some(&c));
+}
+
+fn collect(chars: &[char]) -> String {
+    return chars.iter().collect();
+}
+
+fn strip_language(code: &str) -> &str {
+    return match code.split_once('\n') {
+        Some((language, rest)) if !language.is_empty() && !language.contains(char::is_whitespace) => {
+            &code[code.len() - rest.len() - 1..]
+        }
+        _ => code,
+    };
+}
+
+pub fn escape(text: &str) -> String {
+    return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+}
+
+pub fn unescape(text: &str) -> String {
+    return text.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::{slack_mentions, strike_through, trello_mentions, Mentions};
+
+    fn trello_to_slack(text: &str) -> String {
+        return super::trello_to_slack(text, &Mentions::new());
+    }
+
+    fn slack_to_trello(text: &str) -> String {
+        return super::slack_to_trello(text, &Mentions::new());
+    }
+
+    const TRELLO: &str = "**bold** _italic_ ~~strike~~ `a < b`\n\
+        [the docs](https://example.com/?a=1&b=2) and https://example.com\n\
+        - one\n  - two\n\
+        ```\nif a < b && c > d {}\n```";
+
+    const SLACK: &str = "*bold* _italic_ ~strike~ `a &lt; b`\n\
+        <https://example.com/?a=1&amp;b=2|the docs> and https://example.com\n\
+        • one\n  • two\n\
+        ```\nif a &lt; b &amp;&amp; c &gt; d {}\n```";
+
+    #[test]
+    fn trello_to_slack_formatting() {
+        assert_eq!(SLACK, trello_to_slack(TRELLO));
+    }
+
+    #[test]
+    fn slack_to_trello_formatting() {
+        assert_eq!(TRELLO, slack_to_trello(SLACK));
+    }
+
+    #[test]
+    fn trello_round_trip() {
+        assert_eq!(TRELLO, slack_to_trello(&trello_to_slack(TRELLO)));
+    }
+
+    #[test]
+    fn slack_round_trip() {
+        assert_eq!(SLACK, trello_to_slack(&slack_to_trello(SLACK)));
+    }
+
+    #[test]
+    fn trello_alternative_syntax() {
+        assert_eq!("*bold* _italic_\n• item", trello_to_slack("__bold__ *italic*\n+ item"));
+        assert_eq!("*Heading*", trello_to_slack("## Heading"));
+        assert_eq!("```\nfn main() {}\n```", trello_to_slack("```rust\nfn main() {}\n```"));
+        assert_eq!("<https://example.com>", trello_to_slack("[https://example.com](https://example.com)"));
+        assert_eq!("&gt; quoted", trello_to_slack("\\> quoted"));
+        assert_eq!("> quoted *text*", trello_to_slack("> quoted **text**"));
+    }
+
+    #[test]
+    fn slack_mentions_and_links() {
+        assert_eq!("@U123 and @alice in #general @here",
+                   slack_to_trello("<@U123> and <@U456|alice> in <#C123|general> <!here>"));
+        assert_eq!("see https://example.com", slack_to_trello("see <https://example.com>"));
+        assert_eq!("see\n```\ncode\n```\nafter", slack_to_trello("see ```code``` after"));
+    }
+
+    #[test]
+    fn text_that_is_not_formatting() {
+        assert_eq!("snake_case_name 2 * 3 * 4", trello_to_slack("snake_case_name 2 * 3 * 4"));
+        assert_eq!("snake_case_name 2 * 3 * 4", slack_to_trello("snake_case_name 2 * 3 * 4"));
+        assert_eq!("Tom &amp; Jerry &lt;3", trello_to_slack("Tom & Jerry <3"));
+        assert_eq!("Tom & Jerry <3", slack_to_trello("Tom &amp; Jerry &lt;3"));
+    }
+
+    #[test]
+    fn mentions_are_found() {
+        assert_eq!(vec!["U123", "U456"], slack_mentions("<@U123> and <@U456|bob>, again <@U123>, <#C123|general>"));
+        assert_eq!(vec!["alice", "bob_2"], trello_mentions("@alice and @bob_2. Not alice@example.com, @card or @alice"));
+    }
+
+    #[test]
+    fn mentions_are_replaced() {
+        let mentions = Mentions::from([
+            ("U123".to_string(), "@alice".to_string()),
+            ("alice".to_string(), "<@U123>".to_string()),
+            ("bob".to_string(), "Bob Jones".to_string()),
+        ]);
+
+        assert_eq!("@alice and @U456 see **this**", super::slack_to_trello("<@U123> and <@U456> see *this*", &mentions));
+        assert_eq!("<@U123>, Bob Jones and @carol see *this*", super::trello_to_slack("@alice, @bob and @carol see **this**", &mentions));
+        assert_eq!("`@alice` alice@example.com", super::trello_to_slack("`@alice` alice@example.com", &mentions));
+    }
+
+    #[test]
+    fn strike_through_lines() {
+        assert_eq!("~~one~~\n\n~~two~~\n```\ncode\n```", strike_through("one\n\ntwo\n```\ncode\n```", "~~"));
+    }
+}
@@ -0,0 +1,144 @@
+use std::time::Duration;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use crate::error::SyncError;
+
+const MAX_ATTEMPTS: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+// Slack wants a webhook answered within 3 seconds, so only a wait that leaves time for the retry is done inline.
+// Anything longer is returned for the queue or the webhook sender to wait out.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(2);
+
+/// Sends a request, retrying network errors, 429s and 5xx responses with backoff.
+/// Non-success responses that are not retried are returned as the matching `SyncError`.
+pub async fn send_with_retry(request: RequestBuilder) -> Result<Response, SyncError> {
+    let mut attempt = 1;
+    loop {
+        let this_request = match request.try_clone() {
+            Some(value) => value,
+            None => return send_once(request).await,
+        };
+
+        let error = match send_once(this_request).await {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+
+        let delay = match retry_delay(&error, attempt) {
+            Some(delay) if attempt < MAX_ATTEMPTS => delay,
+            _ => return Err(error),
+        };
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+async fn send_once(request: RequestBuilder) -> Result<Response, SyncError> {
+    let response = request.send().await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let url = response.url().path().to_string();
+    return Err(match status {
+        StatusCode::TOO_MANY_REQUESTS => SyncError::RateLimited {
+            retry_after: parse_retry_after(response.headers().get("Retry-After").and_then(|value| value.to_str().ok())),
+        },
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => SyncError::Auth(format!("{} returned {}", url, status)),
+        StatusCode::NOT_FOUND => SyncError::NotFound(url),
+        _ if status.is_server_error() => SyncError::Network(format!("{} returned {}", url, status)),
+        _ => SyncError::BadResponse(format!("{} returned {}", url, status)),
+    });
+}
+
+/// How long to wait before retrying after `error`, None if it should not be retried
+pub fn retry_delay(error: &SyncError, attempt: u32) -> Option<Duration> {
+    let backoff = INITIAL_BACKOFF * 2u32.pow(attempt - 1);
+    return match error {
+        SyncError::Network(_) => Some(backoff),
+        SyncError::RateLimited { retry_after: Some(retry_after) } if *retry_after > MAX_RETRY_AFTER => None,
+        SyncError::RateLimited { retry_after } => Some(retry_after.unwrap_or(backoff)),
+        _ => None,
+    };
+}
+
+pub fn parse_retry_after(value: Option<&str>) -> Option<Duration> {
+    return value
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+}
+
+#[cfg(target_arch = "wasm32")]
+pub async fn sleep(duration: Duration) {
+    worker::Delay::from(duration).await;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::error::SyncError;
+    use crate::http::{parse_retry_after, retry_delay, send_with_retry};
+
+    #[test]
+    fn retry_delay_backs_off() {
+        let error = SyncError::Network("timeout".to_string());
+        assert_eq!(Some(Duration::from_millis(250)), retry_delay(&error, 1));
+        assert_eq!(Some(Duration::from_millis(500)), retry_delay(&error, 2));
+        assert_eq!(Some(Duration::from_millis(1000)), retry_delay(&error, 3));
+    }
+
+    #[test]
+    fn retry_delay_honors_short_retry_after() {
+        let error = SyncError::RateLimited { retry_after: Some(Duration::from_secs(2)) };
+        assert_eq!(Some(Duration::from_secs(2)), retry_delay(&error, 1));
+
+        // Left to the queue or the webhook sender
+        let error = SyncError::RateLimited { retry_after: Some(Duration::from_secs(3)) };
+        assert_eq!(None, retry_delay(&error, 1));
+
+        let error = SyncError::RateLimited { retry_after: None };
+        assert_eq!(Some(Duration::from_millis(500)), retry_delay(&error, 2));
+    }
+
+    #[tokio::test]
+    async fn send_with_retry_waits_out_short_retry_after() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/chat.postMessage", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            for response in ["HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\ncontent-length: 0\r\n\r\n", "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let started = Instant::now();
+        let response = send_with_retry(reqwest::Client::new().post(url)).await.unwrap();
+
+        assert!(response.status().is_success());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn retry_delay_not_for_client_errors() {
+        assert_eq!(None, retry_delay(&SyncError::Auth("invalid_auth".to_string()), 1));
+        assert_eq!(None, retry_delay(&SyncError::NotFound("card".to_string()), 1));
+        assert_eq!(None, retry_delay(&SyncError::BadResponse("400".to_string()), 1));
+    }
+
+    #[test]
+    fn parse_retry_after_seconds() {
+        assert_eq!(Some(Duration::from_secs(30)), parse_retry_after(Some("30")));
+        assert_eq!(None, parse_retry_after(Some("Wed, 21 Oct 2015 07:28:00 GMT")));
+        assert_eq!(None, parse_retry_after(None));
+    }
+}
@@ -1,20 +1,52 @@
+#![allow(clippy::needless_return)]
+
+// worker::console_log only works inside the Workers runtime, elsewhere, such as in tests, messages go to stderr
+macro_rules! console_log {
+    ($($t:tt)*) => {
+        #[cfg(target_arch = "wasm32")]
+        worker::console_log!($($t)*);
+        #[cfg(not(target_arch = "wasm32"))]
+        eprintln!($($t)*);
+    };
+}
+
 mod trello;
 mod action;
 mod slack;
 mod database;
 mod account;
+mod credentials;
+mod settings;
+mod migrations;
+mod events;
+mod error;
+mod http;
+mod format;
+mod users;
+mod queue;
+mod api;
+mod store;
+mod connector;
+#[cfg(not(target_arch = "wasm32"))]
+mod sqlite;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod server;
 
-use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use sha2::{Digest, Sha256};
 use worker::*;
 use crate::account::{Account, get_account};
+use crate::action::ActionService;
+use crate::api::HttpApi;
+use crate::error::SyncError;
+use crate::queue::{CloudflareJobQueue, D1DeadLetters, Job, JobQueue};
 use crate::slack::{MultipleWebhookEvent};
-use crate::trello::{TrelloWebhook};
+use crate::store::D1Store;
+use crate::trello::TrelloWebhook;
+use crate::users::D1UserDirectory;
 
-#[derive(Debug, Deserialize, Serialize)]
-struct GenericResponse {
-    status: u16,
-    message: String,
-}
+// Only needs checking once per isolate, the schema can't go backwards while it is running
+static SCHEMA_CHECKED: AtomicBool = AtomicBool::new(false);
 
 #[event(fetch)]
 pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
@@ -26,26 +58,128 @@ pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
         req.cf().unwrap().region().unwrap_or("unknown region".into())
     );
 
+    if !SCHEMA_CHECKED.load(Ordering::Relaxed) {
+        if let Err(err) = database::check_schema_version(&env).await {
+            console_log!("Refusing to serve: {}", err);
+            return Response::error(err.to_string(), 503);
+        }
+        SCHEMA_CHECKED.store(true, Ordering::Relaxed);
+    }
 
     Router::new()
         .get_async("/", handle_default)
         .post_async("/trello-webhook/:id", trello_webhook_hit)
         .post_async("/slack-webhook/:id", slack_webhook)
         .head_async("/trello-webhook/:id", trello_webhook_setup)
+        .get_async("/dead-letters", list_dead_letters)
+        .post_async("/dead-letters/:id/replay", replay_dead_letter)
         .run(req, env)
         .await
 }
 
+#[event(queue)]
+pub async fn consume_jobs(batch: MessageBatch<Job>, env: Env, _ctx: Context) -> Result<()> {
+    let jobs = match CloudflareJobQueue::new(&env) {
+        Some(queue) => queue,
+        None => return Err(Error::RustError(format!("No {} queue bound", queue::QUEUE_BINDING))),
+    };
+    let dead_letters = D1DeadLetters::new(&env);
+
+    for message in batch.messages()? {
+        let now = Date::now().as_millis() / 1000;
+        let job = message.body().clone();
+        // Only a failure to requeue or dead letter the job is left to the queue's own retries
+        match queue::consume(&jobs, &dead_letters, job, now, |job| run_job(&env, job)).await {
+            Ok(_) => message.ack(),
+            Err(err) => {
+                console_log!("Error consuming job {}: {}", message.id(), err);
+                message.retry();
+            }
+        }
+    }
+    return Ok(());
+}
+
+async fn run_job(env: &Env, job: Job) -> std::result::Result<(), SyncError> {
+    let account = get_account(env, &job.account_id).await?
+        .ok_or(SyncError::NotFound(format!("account {}", job.account_id)))?;
+    handle_job(env, &job, account).await?;
+    return Ok(());
+}
+
+async fn handle_job(env: &Env, job: &Job, account: Account) -> std::result::Result<Response, SyncError> {
+    let now = Date::now().as_millis() / 1000;
+    let result = queue::handle_job(&D1Store::new(env), &D1UserDirectory::new(env), &HttpApi, job, &account, now).await?;
+    return Ok(Response::ok(result)?);
+}
+
+// With a queue bound the webhook is acknowledged as soon as it is queued, otherwise it is handled before responding
+async fn enqueue_or_handle(env: &Env, job: Job, account: Account) -> Result<Response> {
+    if let Some(queue) = CloudflareJobQueue::new(env) {
+        queue.enqueue(job, 0).await?;
+        return Response::ok("Queued");
+    }
+
+    return match handle_job(env, &job, account).await {
+        Ok(response) => Ok(response),
+        Err(err) => {
+            console_log!("Error handling {} webhook: {}", job.service.as_str(), err);
+            err.to_response()
+        }
+    };
+}
+
+async fn list_dead_letters(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    if !is_admin(&req, &ctx.env)? {
+        return Response::error("Not found", 404);
+    }
+    return Response::from_json(&database::get_dead_letters(&ctx.env).await?);
+}
+
+async fn replay_dead_letter(req: Request, ctx: RouteContext<()>) -> Result<Response> {
+    if !is_admin(&req, &ctx.env)? {
+        return Response::error("Not found", 404);
+    }
+    let id = match ctx.param("id").and_then(|id| id.parse::<u32>().ok()) {
+        Some(id) => id,
+        None => return Response::error("Not found", 404),
+    };
+    let jobs = match CloudflareJobQueue::new(&ctx.env) {
+        Some(queue) => queue,
+        None => return Response::error(format!("No {} queue bound", queue::QUEUE_BINDING), 503),
+    };
+
+    return match queue::replay(&jobs, &D1DeadLetters::new(&ctx.env), id).await? {
+        true => Response::ok("Queued"),
+        false => Response::error("Not found", 404),
+    };
+}
+
+// The dead letter routes are only served when an ADMIN_TOKEN secret is set, to requests bearing it
+fn is_admin(req: &Request, env: &Env) -> Result<bool> {
+    let token = match env.secret("ADMIN_TOKEN") {
+        Ok(value) => value.to_string(),
+        Err(_) => return Ok(false),
+    };
+    let given = match req.headers().get("Authorization")? {
+        Some(value) => value,
+        None => return Ok(false),
+    };
+
+    // Comparing digests keeps the time taken from giving away how much of the token matched
+    let expected = Sha256::digest(format!("Bearer {}", token));
+    return Ok(Sha256::digest(given) == expected);
+}
+
 async fn handle_default(_: Request, _ctx: RouteContext<()>) -> Result<Response> {
     return Response::ok("Default");
 }
 
 async fn trello_webhook_setup(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
     if let Some(id) = ctx.param("id") {
-        let account = get_account(&ctx.env, id).await;
-        return match account {
-            Ok(_) => Response::ok("Success"),
-            _ => Response::error("Not found", 404),
+        return match get_account(&ctx.env, id).await? {
+            Some(_) => Response::ok("Success"),
+            None => Response::error("Not found", 404),
         };
     }
 
@@ -53,44 +187,90 @@ async fn trello_webhook_setup(_req: Request, ctx: RouteContext<()>) -> Result<Re
 }
 
 async fn trello_webhook_hit(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let account = match get_account_from_request(&ctx).await {
-        Ok(account) => account,
-        _ => return Response::error("Not found", 404),
+    let account = match get_account_from_request(&ctx).await? {
+        Some(account) => account,
+        None => return Response::error("Not found", 404),
     };
 
-    let webhook:TrelloWebhook = match req.json().await{
-        Ok(value)=> value,
-        Err(err)=>return Response::error(err.to_string(),400),
-    };
+    let body = req.bytes().await?;
+    if !verify_trello_request(&req, &body, &account)? {
+        console_log!("Rejecting trello webhook with invalid signature");
+        return Response::error("Unauthorized", 401);
+    }
+
+    if let Err(err) = serde_json::from_slice::<TrelloWebhook>(&body) {
+        return Response::error(err.to_string(), 400);
+    }
 
-    return trello::handle_webhook(ctx.env, webhook, account).await;
+    let job = Job::new(&account.id, ActionService::Trello, &body);
+    return enqueue_or_handle(&ctx.env, job, account).await;
 }
 
+fn verify_trello_request(req: &Request, body: &[u8], account: &Account) -> Result<bool> {
+    let app_secret = match &account.credentials.trello_app_secret {
+        Some(value) => value,
+        None => return Ok(false),
+    };
+    let signature = match req.headers().get("X-Trello-Webhook")? {
+        Some(value) => value,
+        None => return Ok(false),
+    };
+
+    let callback_url = req.url()?;
+    return Ok(trello::verify_signature(app_secret, callback_url.as_str(), &signature, body));
+}
 
 async fn slack_webhook(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let account = match get_account_from_request(&ctx).await {
-        Ok(account) => account,
-        _ => return Response::error("Not found", 404),
+    let account = match get_account_from_request(&ctx).await? {
+        Some(account) => account,
+        None => return Response::error("Not found", 404),
     };
 
-    let webhook :MultipleWebhookEvent = match req.json().await{
+    let body = req.bytes().await?;
+    if !verify_slack_request(&req, &body, &account)? {
+        console_log!("Rejecting slack webhook with invalid signature");
+        return Response::error("Unauthorized", 401);
+    }
+    if let Some(retry) = req.headers().get("X-Slack-Retry-Num")? {
+        console_log!("Slack retry {} ({:?})", retry, req.headers().get("X-Slack-Retry-Reason")?);
+    }
+
+    let webhook :MultipleWebhookEvent = match serde_json::from_slice(&body){
         Ok(value) => value,
         Err(err) => return Response::error(err.to_string(),400),
     };
     return match webhook {
         MultipleWebhookEvent::Challenge(challenge) => Response::ok(challenge.challenge),
-        MultipleWebhookEvent::EventWebhook(event) => slack::handle_webhook(event, ctx.env, account).await,
+        MultipleWebhookEvent::EventWebhook(_) => {
+            let job = Job::new(&account.id, ActionService::Slack, &body);
+            enqueue_or_handle(&ctx.env, job, account).await
+        }
         _ => Response::error("Bad request", 400),
     };
 }
 
-async fn get_account_from_request(ctx: &RouteContext<()>) -> Result<Account>{
-    return if let Some(id) = ctx.param("id") {
-        match get_account(&ctx.env, id).await {
-            Ok(account) => Ok(account),
-            _ => Err(Error::RustError("Not Found".to_string())),
-        }
-    } else {
-        Err(Error::RustError("Not Found".to_string()))
-    }
+fn verify_slack_request(req: &Request, body: &[u8], account: &Account) -> Result<bool> {
+    let signing_secret = match &account.credentials.slack_signing_secret {
+        Some(value) => value,
+        None => return Ok(false),
+    };
+    let timestamp = match req.headers().get("X-Slack-Request-Timestamp")? {
+        Some(value) => value,
+        None => return Ok(false),
+    };
+    let signature = match req.headers().get("X-Slack-Signature")? {
+        Some(value) => value,
+        None => return Ok(false),
+    };
+
+    let now = Date::now().as_millis() / 1000;
+    return Ok(slack::verify_signature(signing_secret, &timestamp, &signature, body, now));
+}
+
+// None becomes a 404, while a D1 error is returned so the route fails with a 500 and the sender retries
+async fn get_account_from_request(ctx: &RouteContext<()>) -> Result<Option<Account>>{
+    return match ctx.param("id") {
+        Some(id) => get_account(&ctx.env, id).await,
+        None => Ok(None),
+    };
 }
\ No newline at end of file
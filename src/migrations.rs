@@ -0,0 +1,179 @@
+// Every file in the migrations directory, in the order they are applied
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { version: 0, name: "initial", sql: include_str!("../migrations/0000_initial.sql") },
+    Migration { version: 1, name: "account_scoped_links", sql: include_str!("../migrations/0001_account_scoped_links.sql") },
+    Migration { version: 2, name: "processed_events", sql: include_str!("../migrations/0002_processed_events.sql") },
+    Migration { version: 3, name: "link_claims", sql: include_str!("../migrations/0003_link_claims.sql") },
+    Migration { version: 4, name: "user_directory", sql: include_str!("../migrations/0004_user_directory.sql") },
+    Migration { version: 5, name: "message_mappings", sql: include_str!("../migrations/0005_message_mappings.sql") },
+    Migration { version: 6, name: "account_settings", sql: include_str!("../migrations/0006_account_settings.sql") },
+    Migration { version: 7, name: "mirror_attachments", sql: include_str!("../migrations/0007_mirror_attachments.sql") },
+    Migration { version: 8, name: "dead_letters", sql: include_str!("../migrations/0008_dead_letters.sql") },
+    Migration { version: 9, name: "connector_links", sql: include_str!("../migrations/0009_connector_links.sql") },
+    Migration { version: 10, name: "trello_members", sql: include_str!("../migrations/0010_trello_members.sql") },
+    Migration { version: 11, name: "message_origin", sql: include_str!("../migrations/0011_message_origin.sql") },
+    Migration { version: 12, name: "event_leases", sql: include_str!("../migrations/0012_event_leases.sql") },
+    Migration { version: 13, name: "connector_message_mappings", sql: include_str!("../migrations/0013_connector_message_mappings.sql") },
+];
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    // D1 is migrated with wrangler, this is only run against SQLite by sqlite::SqliteStore
+    #[allow(dead_code)]
+    pub sql: &'static str,
+}
+
+impl Migration {
+    pub fn file_name(&self) -> String {
+        return format!("{:04}_{}.sql", self.version, self.name);
+    }
+}
+
+pub const fn latest_version() -> u32 {
+    return MIGRATIONS[MIGRATIONS.len() - 1].version;
+}
+
+/// Migrations that still need to run on a database at `current` version, None if nothing has been applied
+pub fn pending_migrations(current: Option<u32>) -> Vec<&'static Migration> {
+    return MIGRATIONS.iter()
+        .filter(|migration| current.is_none_or(|version| migration.version > version))
+        .collect();
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use rusqlite::Connection;
+    use crate::migrations::{latest_version, pending_migrations, MIGRATIONS};
+
+    pub fn open_database() -> Connection {
+        return Connection::open_in_memory().expect("Error opening database");
+    }
+
+    /// A database file for tests that reopen it, removed when the test finishes
+    pub struct DatabaseFile(PathBuf);
+
+    impl DatabaseFile {
+        pub fn new(name: &str) -> Self {
+            return DatabaseFile(std::env::temp_dir().join(format!("saas-sync-{}-{}.sqlite", name, uuid::Uuid::new_v4())));
+        }
+
+        pub fn path(&self) -> &str {
+            return self.0.to_str().expect("Temp path isn't UTF-8");
+        }
+    }
+
+    impl Drop for DatabaseFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    pub fn migrate(connection: &Connection, current: Option<u32>) {
+        for migration in pending_migrations(current) {
+            connection.execute_batch(migration.sql).expect(migration.name);
+        }
+    }
+
+    fn database_version(connection: &Connection) -> u32 {
+        return connection.query_row("SELECT MAX(version) FROM schema_migrations", [], |row| row.get(0))
+            .expect("Error reading version");
+    }
+
+    #[test]
+    fn migrations_are_listed_in_order() {
+        let mut files: Vec<String> = fs::read_dir("./migrations").expect("Error reading migrations")
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+
+        assert_eq!(files.len(), MIGRATIONS.len());
+        for (index, (file, migration)) in files.iter().zip(MIGRATIONS).enumerate() {
+            assert_eq!(index as u32, migration.version);
+            assert_eq!(&migration.file_name(), file);
+        }
+    }
+
+    #[test]
+    fn pending_migrations_after_version() {
+        assert_eq!(MIGRATIONS.len(), pending_migrations(None).len());
+        assert_eq!(MIGRATIONS.len() - 1, pending_migrations(Some(0)).len());
+        assert!(pending_migrations(Some(latest_version())).is_empty());
+    }
+
+    #[test]
+    fn migrate_empty_database() {
+        let connection = open_database();
+        migrate(&connection, None);
+
+        assert_eq!(latest_version(), database_version(&connection));
+        connection.execute("insert into accounts values ('account', 'Test Account')", []).unwrap();
+        connection.execute("insert into links (account_id, source_service, source_id, target_service, target_container, target_id) values ('account', 'trello', 'card', 'slack', 'C1', '1.1')", []).unwrap();
+        // The same thread in another channel is a different link
+        connection.execute("insert into links (account_id, source_service, source_id, target_service, target_container, target_id) values ('account', 'trello', 'card2', 'slack', 'C2', '1.1')", []).unwrap();
+        assert!(connection.execute("insert into links (account_id, source_service, source_id, target_service, target_container, target_id) values ('account', 'trello', 'card3', 'slack', 'C1', '1.1')", []).is_err());
+        // A card can only be linked once
+        assert!(connection.execute("insert into links (account_id, source_service, source_id, target_service, target_container, target_id) values ('account', 'trello', 'card', 'trello', 'list', 'card4')", []).is_err());
+    }
+
+    #[test]
+    fn migrate_keeps_existing_links() {
+        let connection = open_database();
+        // A database created by the original schema.sql, before migrations were tracked
+        connection.execute_batch("
+            CREATE TABLE links (id integer PRIMARY KEY AUTOINCREMENT, slack_thread nvarchar(100), trello_card nvarchar(100));
+            CREATE UNIQUE INDEX idx_slack ON links (slack_thread);
+            CREATE UNIQUE INDEX idx_trello ON links (trello_card);
+            CREATE TABLE accounts (id uuid_str(4) PRIMARY KEY, name nvarchar(256));
+            insert into accounts values ('account', 'Test Account');
+            insert into links values (null, '1.1', 'card');
+        ").unwrap();
+
+        migrate(&connection, None);
+
+        assert_eq!(latest_version(), database_version(&connection));
+        let (account, target): (String, String) = connection.query_row("SELECT account_id, target_service FROM links WHERE source_service='trello' AND source_id='card'", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!(("account", "slack"), (account.as_str(), target.as_str()));
+    }
+
+    #[test]
+    fn migrate_keeps_message_mappings_with_an_origin() {
+        let connection = open_database();
+        for migration in &MIGRATIONS[..=12] {
+            connection.execute_batch(migration.sql).unwrap();
+        }
+        connection.execute_batch("
+            insert into accounts values ('account', 'Test Account');
+            insert into message_mappings values ('account', 'C1', '1.1', 'comment1', 'slack');
+            insert into message_mappings values ('account', 'C1', '1.2', 'comment2', 'trello');
+            insert into message_mappings values ('account', 'C1', '1.3', 'comment3', NULL);
+        ").unwrap();
+
+        migrate(&connection, Some(12));
+
+        let mut statement = connection.prepare("SELECT source_service, source_id, target_service, target_container, target_id FROM message_mappings ORDER BY source_id").unwrap();
+        let rows: Vec<(String, String, String, Option<String>, String)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(vec![
+            ("slack".to_string(), "1.1".to_string(), "trello".to_string(), None, "comment1".to_string()),
+            ("trello".to_string(), "comment2".to_string(), "slack".to_string(), Some("C1".to_string()), "1.2".to_string()),
+        ], rows);
+    }
+
+    #[test]
+    fn migrate_from_previous_version() {
+        let connection = open_database();
+        for migration in &MIGRATIONS[..1] {
+            connection.execute_batch(migration.sql).unwrap();
+        }
+
+        migrate(&connection, Some(database_version(&connection)));
+
+        assert_eq!(latest_version(), database_version(&connection));
+    }
+}
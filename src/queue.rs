@@ -0,0 +1,394 @@
+use std::future::Future;
+use serde::{Deserialize, Serialize};
+use worker::{Env, Error, MessageBuilder};
+use crate::account::Account;
+use crate::action::ActionService;
+use crate::api::{SlackApi, TrelloApi};
+use crate::database::{create_dead_letter, take_dead_letter};
+use crate::error::SyncError;
+use crate::slack::{self, MultipleWebhookEvent};
+use crate::store::Store;
+use crate::trello::{self, TrelloWebhook};
+use crate::users::UserDirectory;
+
+// The binding in wrangler.toml, without it webhooks are processed as they arrive
+pub const QUEUE_BINDING: &str = "JOBS";
+
+// Attempts before a job is moved to the dead letter table
+pub const MAX_JOB_ATTEMPTS: u32 = 5;
+const INITIAL_RETRY_DELAY_SECONDS: u32 = 30;
+// The longest delay Cloudflare Queues accepts
+const MAX_RETRY_DELAY_SECONDS: u32 = 12 * 60 * 60;
+
+/// A verified webhook waiting to be processed by the queue consumer
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Job {
+    // The id from the webhook url, resolved to an account when the job runs
+    pub account_id: String,
+    pub service: ActionService,
+    // The webhook body exactly as it was received
+    pub payload: String,
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+impl Job {
+    pub fn new(account_id: &str, service: ActionService, payload: &[u8]) -> Job {
+        return Job {
+            account_id: account_id.to_string(),
+            service,
+            payload: String::from_utf8_lossy(payload).to_string(),
+            attempts: 0,
+        };
+    }
+}
+
+pub trait JobQueue {
+    async fn enqueue(&self, job: Job, delay_seconds: u32) -> Result<(), Error>;
+}
+
+pub struct CloudflareJobQueue {
+    queue: worker::Queue,
+}
+
+impl CloudflareJobQueue {
+    /// None when the worker has no queue bound
+    pub fn new(env: &Env) -> Option<Self> {
+        return env.queue(QUEUE_BINDING).ok().map(|queue| CloudflareJobQueue { queue });
+    }
+}
+
+impl JobQueue for CloudflareJobQueue {
+    async fn enqueue(&self, job: Job, delay_seconds: u32) -> Result<(), Error> {
+        return self.queue.send(MessageBuilder::new(job).delay_seconds(delay_seconds).build()).await;
+    }
+}
+
+pub trait DeadLetters {
+    /// Keeps a job that has run out of attempts, so it can be inspected and replayed
+    async fn record(&self, job: &Job, error: &str, now: u64) -> Result<(), Error>;
+
+    /// Removes a dead letter, returning its job
+    async fn take(&self, id: u32) -> Result<Option<Job>, Error>;
+}
+
+pub struct D1DeadLetters<'a> {
+    env: &'a Env,
+}
+
+impl<'a> D1DeadLetters<'a> {
+    pub fn new(env: &'a Env) -> Self {
+        return D1DeadLetters { env };
+    }
+}
+
+impl DeadLetters for D1DeadLetters<'_> {
+    async fn record(&self, job: &Job, error: &str, now: u64) -> Result<(), Error> {
+        return create_dead_letter(self.env, job, error, now).await;
+    }
+
+    async fn take(&self, id: u32) -> Result<Option<Job>, Error> {
+        let dead_letter = take_dead_letter(self.env, id).await?;
+        return Ok(dead_letter.map(|dead_letter| dead_letter.into_job()));
+    }
+}
+
+/// Runs a job from the queue. A job that fails with an error that may clear up is queued again
+/// with a growing delay, anything else, or a job out of attempts, becomes a dead letter.
+pub async fn consume<Q, D, F, Fut>(queue: &Q, dead_letters: &D, job: Job, now: u64, run: F) -> Result<(), Error>
+where
+    Q: JobQueue,
+    D: DeadLetters,
+    F: FnOnce(Job) -> Fut,
+    Fut: Future<Output = Result<(), SyncError>>,
+{
+    let error = match run(job.clone()).await {
+        Ok(_) => return Ok(()),
+        Err(err) => err,
+    };
+
+    let attempts = job.attempts + 1;
+    match retry_delay_seconds(&error, attempts) {
+        Some(delay) if attempts < MAX_JOB_ATTEMPTS => {
+            return queue.enqueue(Job { attempts, ..job }, delay).await;
+        }
+        _ => {
+            return dead_letters.record(&Job { attempts, ..job }, &error.to_string(), now).await;
+        }
+    }
+}
+
+/// Runs a job's webhook through the handler for its service, as it would have been run had it not been queued
+pub async fn handle_job<S, D, A>(store: &S, directory: &D, api: &A, job: &Job, account: &Account, now: u64) -> Result<&'static str, SyncError>
+where
+    S: Store,
+    D: UserDirectory,
+    A: SlackApi + TrelloApi,
+{
+    return match job.service {
+        ActionService::Trello => {
+            let webhook: TrelloWebhook = serde_json::from_str(&job.payload)
+                .map_err(|err| SyncError::BadResponse(err.to_string()))?;
+            trello::handle(store, directory, api, &webhook, account, now).await
+        }
+        ActionService::Slack => match serde_json::from_str(&job.payload) {
+            Ok(MultipleWebhookEvent::EventWebhook(event)) => slack::handle(store, directory, api, &event, account, now).await,
+            Ok(_) => Err(SyncError::BadResponse("Not a slack event".to_string())),
+            Err(err) => Err(SyncError::BadResponse(err.to_string())),
+        },
+    };
+}
+
+/// Queues a dead letter to run again from scratch, returning false if there is no such dead letter
+pub async fn replay<Q: JobQueue, D: DeadLetters>(queue: &Q, dead_letters: &D, id: u32) -> Result<bool, Error> {
+    let job = match dead_letters.take(id).await? {
+        Some(job) => job,
+        None => return Ok(false),
+    };
+    queue.enqueue(Job { attempts: 0, ..job }, 0).await?;
+    return Ok(true);
+}
+
+// Missing credentials or a bad payload won't fix themselves, those are left for a replay. A rate limited job waits
+// as long as the service asked, rather than the backoff.
+fn retry_delay_seconds(error: &SyncError, attempts: u32) -> Option<u32> {
+    return match error {
+        SyncError::RateLimited { retry_after: Some(retry_after) } => {
+            Some((retry_after.as_secs_f64().ceil() as u32).clamp(1, MAX_RETRY_DELAY_SECONDS))
+        }
+        SyncError::Network(_) | SyncError::RateLimited { .. } | SyncError::Worker(_) | SyncError::InProgress(_) => {
+            Some(INITIAL_RETRY_DELAY_SECONDS * 2u32.pow(attempts - 1))
+        }
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::cell::RefCell;
+    use std::time::Duration;
+    use worker::Error;
+    use crate::action::ActionService;
+    use crate::api::tests::{ApiCall, RecordingApi};
+    use crate::error::SyncError;
+    use crate::events::tests::test_account;
+    use crate::queue::{consume, handle_job, replay, DeadLetters, Job, JobQueue, MAX_JOB_ATTEMPTS};
+    use crate::store::tests::MemoryStore;
+    use crate::store::Store;
+    use crate::trello::TrelloWebhook;
+    use crate::users::tests::MemoryUserDirectory;
+
+    #[derive(Default)]
+    pub struct MemoryJobQueue {
+        // Queued jobs with their delay
+        pub jobs: RefCell<Vec<(Job, u32)>>,
+    }
+
+    impl MemoryJobQueue {
+        pub fn pop(&self) -> Option<Job> {
+            let mut jobs = self.jobs.borrow_mut();
+            if jobs.is_empty() {
+                return None;
+            }
+            return Some(jobs.remove(0).0);
+        }
+    }
+
+    impl JobQueue for MemoryJobQueue {
+        async fn enqueue(&self, job: Job, delay_seconds: u32) -> Result<(), Error> {
+            self.jobs.borrow_mut().push((job, delay_seconds));
+            return Ok(());
+        }
+    }
+
+    #[derive(Default)]
+    pub struct MemoryDeadLetters {
+        pub dead_letters: RefCell<Vec<(u32, Job, String)>>,
+    }
+
+    impl DeadLetters for MemoryDeadLetters {
+        async fn record(&self, job: &Job, error: &str, _now: u64) -> Result<(), Error> {
+            let mut dead_letters = self.dead_letters.borrow_mut();
+            let id = dead_letters.len() as u32 + 1;
+            dead_letters.push((id, job.clone(), error.to_string()));
+            return Ok(());
+        }
+
+        async fn take(&self, id: u32) -> Result<Option<Job>, Error> {
+            let mut dead_letters = self.dead_letters.borrow_mut();
+            let index = dead_letters.iter().position(|(dead_letter_id, _, _)| *dead_letter_id == id);
+            return Ok(index.map(|index| dead_letters.remove(index).1));
+        }
+    }
+
+    fn test_job() -> Job {
+        return Job::new("account", ActionService::Trello, b"{}");
+    }
+
+    #[tokio::test]
+    async fn consume_successful_job() {
+        let queue = MemoryJobQueue::default();
+        let dead_letters = MemoryDeadLetters::default();
+
+        consume(&queue, &dead_letters, test_job(), 100, |_| async { Ok(()) }).await.unwrap();
+
+        assert!(queue.jobs.borrow().is_empty());
+        assert!(dead_letters.dead_letters.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn queued_webhook_survives_the_queue() {
+        let body = std::fs::read("./data/trello/card-moved.json").expect("Error reading file");
+        let queue = MemoryJobQueue::default();
+        let dead_letters = MemoryDeadLetters::default();
+        let moved = RefCell::new(vec![]);
+
+        // Cloudflare serializes the message, so the job has to come back out the same
+        let message = serde_json::to_string(&Job::new("account", ActionService::Trello, &body)).unwrap();
+        queue.enqueue(serde_json::from_str(&message).unwrap(), 0).await.unwrap();
+
+        let job = queue.pop().unwrap();
+        consume(&queue, &dead_letters, job, 100, |job| {
+            let moved = &moved;
+            async move {
+                assert_eq!(ActionService::Trello, job.service);
+                let webhook: TrelloWebhook = serde_json::from_str(&job.payload).map_err(|err| SyncError::BadResponse(err.to_string()))?;
+                moved.borrow_mut().push(webhook.action.id);
+                Ok(())
+            }
+        }).await.unwrap();
+
+        assert_eq!(vec!["abc64ds5ad45s6161d".to_string()], *moved.borrow());
+        assert!(dead_letters.dead_letters.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn consume_retries_then_dead_letters() {
+        let queue = MemoryJobQueue::default();
+        let dead_letters = MemoryDeadLetters::default();
+        queue.enqueue(test_job(), 0).await.unwrap();
+
+        let mut runs = 0;
+        while let Some(job) = queue.pop() {
+            runs += 1;
+            consume(&queue, &dead_letters, job, 100, |_| async { Err(SyncError::Network("timeout".to_string())) }).await.unwrap();
+        }
+
+        assert_eq!(MAX_JOB_ATTEMPTS, runs);
+        let dead_letters = dead_letters.dead_letters.borrow();
+        assert_eq!(1, dead_letters.len());
+        assert_eq!(MAX_JOB_ATTEMPTS, dead_letters[0].1.attempts);
+        assert_eq!("Network error: timeout", dead_letters[0].2);
+    }
+
+    #[tokio::test]
+    async fn consume_backs_off() {
+        let queue = MemoryJobQueue::default();
+        let dead_letters = MemoryDeadLetters::default();
+
+        let job = Job { attempts: 2, ..test_job() };
+        consume(&queue, &dead_letters, job, 100, |_| async { Err(SyncError::RateLimited { retry_after: None }) }).await.unwrap();
+
+        assert_eq!(vec![(Job { attempts: 3, ..test_job() }, 120)], *queue.jobs.borrow());
+    }
+
+    #[tokio::test]
+    async fn consume_waits_as_long_as_asked() {
+        let queue = MemoryJobQueue::default();
+        let dead_letters = MemoryDeadLetters::default();
+
+        let rate_limited = SyncError::RateLimited { retry_after: Some(Duration::from_millis(89_500)) };
+        consume(&queue, &dead_letters, test_job(), 100, |_| async { Err(rate_limited) }).await.unwrap();
+
+        assert_eq!(vec![(Job { attempts: 1, ..test_job() }, 90)], *queue.jobs.borrow());
+    }
+
+    #[tokio::test]
+    async fn failing_webhook_is_dead_lettered_after_its_attempts() {
+        let body = std::fs::read("./data/trello/card-moved.json").expect("Error reading file");
+        let store = MemoryStore::default()
+            .with_account(test_account("account"))
+            .with_channel_mapping("boardid", None, "C123456");
+        let directory = MemoryUserDirectory::default();
+        let mut api = RecordingApi::default();
+        for _ in 0..MAX_JOB_ATTEMPTS {
+            api = api.fail("send_thread_parent", SyncError::Network("timeout".to_string()));
+        }
+        let queue = MemoryJobQueue::default();
+        let dead_letters = MemoryDeadLetters::default();
+
+        // As the webhook route and the queue consumer in lib.rs do, but with the fakes
+        queue.enqueue(Job::new("account", ActionService::Trello, &body), 0).await.unwrap();
+        while let Some(job) = queue.pop() {
+            consume(&queue, &dead_letters, job, 100, |job| {
+                let (store, directory, api) = (&store, &directory, &api);
+                async move {
+                    let account = store.get_account(&job.account_id).await?.ok_or(SyncError::NotFound(format!("account {}", job.account_id)))?;
+                    handle_job(store, directory, api, &job, &account, 100).await?;
+                    Ok(())
+                }
+            }).await.unwrap();
+        }
+
+        let starts = api.calls().into_iter().filter(|call| matches!(call, ApiCall::SendThreadParent { .. })).count();
+        assert_eq!(MAX_JOB_ATTEMPTS as usize, starts);
+        let dead_letters = dead_letters.dead_letters.borrow();
+        assert_eq!(1, dead_letters.len());
+        assert_eq!((MAX_JOB_ATTEMPTS, "Network error: timeout"), (dead_letters[0].1.attempts, dead_letters[0].2.as_str()));
+        assert_eq!(body, dead_letters[0].1.payload.as_bytes());
+        // Each attempt gave up its claim, so a replay can start the thread
+        assert!(store.links.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn webhook_missing_optional_fields_is_handled() {
+        let mut webhook: serde_json::Value = serde_json::from_slice(&std::fs::read("./data/trello/card-moved.json").expect("Error reading file")).unwrap();
+        webhook["action"]["display"]["entities"].as_object_mut().unwrap().remove("listBefore");
+        let store = MemoryStore::default()
+            .with_account(test_account("account"))
+            .with_channel_mapping("boardid", None, "C123456");
+        let directory = MemoryUserDirectory::default();
+        let api = RecordingApi::default();
+        let queue = MemoryJobQueue::default();
+        let dead_letters = MemoryDeadLetters::default();
+
+        queue.enqueue(Job::new("account", ActionService::Trello, webhook.to_string().as_bytes()), 0).await.unwrap();
+        let job = queue.pop().unwrap();
+        consume(&queue, &dead_letters, job, 100, |job| {
+            let (store, directory, api) = (&store, &directory, &api);
+            async move {
+                let account = store.get_account(&job.account_id).await?.ok_or(SyncError::NotFound(format!("account {}", job.account_id)))?;
+                handle_job(store, directory, api, &job, &account, 100).await?;
+                Ok(())
+            }
+        }).await.unwrap();
+
+        assert!(queue.jobs.borrow().is_empty());
+        assert!(dead_letters.dead_letters.borrow().is_empty());
+        assert!(api.calls().iter().any(|call| matches!(call, ApiCall::SendAction { text, .. } if text == "This card has been moved from list  to list Done by TEST UPDATED NAME")));
+    }
+
+    #[tokio::test]
+    async fn consume_dead_letters_permanent_errors() {
+        let queue = MemoryJobQueue::default();
+        let dead_letters = MemoryDeadLetters::default();
+
+        consume(&queue, &dead_letters, test_job(), 100, |_| async { Err(SyncError::Auth("invalid_auth".to_string())) }).await.unwrap();
+
+        assert!(queue.jobs.borrow().is_empty());
+        assert_eq!(1, dead_letters.dead_letters.borrow().len());
+    }
+
+    #[tokio::test]
+    async fn replay_requeues_dead_letter() {
+        let queue = MemoryJobQueue::default();
+        let dead_letters = MemoryDeadLetters::default();
+        dead_letters.record(&Job { attempts: MAX_JOB_ATTEMPTS, ..test_job() }, "Network error: timeout", 100).await.unwrap();
+
+        assert!(replay(&queue, &dead_letters, 1).await.unwrap());
+        assert!(!replay(&queue, &dead_letters, 1).await.unwrap());
+
+        assert_eq!(vec![(test_job(), 0)], *queue.jobs.borrow());
+        assert!(dead_letters.dead_letters.borrow().is_empty());
+    }
+}
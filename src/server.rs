@@ -0,0 +1,412 @@
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tokio::task::LocalSet;
+use worker::Error;
+use crate::account::Account;
+use crate::api::{HttpApi, SlackApi, TrelloApi};
+use crate::credentials::credentials_from_secrets;
+use crate::error::SyncError;
+use crate::settings::settings_from_vars;
+use crate::slack::{self, MultipleWebhookEvent};
+use crate::sqlite::SqliteStore;
+use crate::store::Store;
+use crate::trello::{self, TrelloWebhook};
+
+const DEFAULT_DATABASE_PATH: &str = "saas-sync.sqlite";
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8787";
+
+/// Runs the webhook routes as a plain HTTP server on a SQLite database, configured from the environment:
+/// DATABASE_PATH, LISTEN_ADDRESS, the required PUBLIC_URL and, for a single account, ACCOUNT_ID with the same secrets the worker reads
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+    let database_path = var("DATABASE_PATH").unwrap_or(DEFAULT_DATABASE_PATH.to_string());
+    let address: SocketAddr = var("LISTEN_ADDRESS").unwrap_or(DEFAULT_ADDRESS.to_string()).parse()?;
+    let single_account = var("ACCOUNT_ID").map(|id| Account {
+        id,
+        name: "test".to_string(),
+        credentials: credentials_from_secrets(var),
+        settings: settings_from_vars(var),
+    });
+
+    // Trello signs the URL it calls, which can't be rebuilt from the request behind a proxy or TLS terminator
+    let public_url = var("PUBLIC_URL").ok_or("PUBLIC_URL must be set to the address Trello calls the server on")?;
+
+    let store = SqliteStore::open(&database_path)?;
+    let server = Rc::new(Server { store, api: HttpApi, public_url, single_account });
+
+    // The engine's futures aren't Send, so every connection is served on this one thread
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    return LocalSet::new().block_on(&runtime, async move {
+        let listener = TcpListener::bind(address).await?;
+        console_log!("Listening on http://{} with database {}", address, database_path);
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = server.clone();
+            tokio::task::spawn_local(async move {
+                let service = service_fn(|req| {
+                    let server = server.clone();
+                    async move { server.serve(req).await }
+                });
+                if let Err(err) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                    console_log!("Error serving connection: {}", err);
+                }
+            });
+        }
+    });
+}
+
+struct Server<A: SlackApi + TrelloApi> {
+    store: SqliteStore,
+    api: A,
+    // Where Trello reaches the server, which the webhook signature covers
+    public_url: String,
+    // Set through ACCOUNT_ID, in which case every webhook is handled for it, as in the worker
+    single_account: Option<Account>,
+}
+
+impl<A: SlackApi + TrelloApi> Server<A> {
+    async fn serve(&self, req: Request<Incoming>) -> Result<Response<Full<Bytes>>, hyper::Error> {
+        let (parts, body) = req.into_parts();
+        let body = body.collect().await?.to_bytes();
+        return Ok(self.respond(&parts.method, &parts.uri, &parts.headers, &body, unix_now()).await);
+    }
+
+    async fn respond(&self, method: &Method, uri: &Uri, headers: &HeaderMap, body: &[u8], now: u64) -> Response<Full<Bytes>> {
+        console_log!("{} {}", method, uri.path());
+
+        let segments: Vec<&str> = uri.path().trim_matches('/').split('/').collect();
+        return match (method, segments.as_slice()) {
+            (&Method::GET, [""]) => text(StatusCode::OK, "Default"),
+            (&Method::HEAD, ["trello-webhook", id]) => match self.get_account(id).await {
+                Ok(Some(_)) => text(StatusCode::OK, "Success"),
+                Ok(None) => not_found(),
+                Err(err) => account_error(err),
+            },
+            (&Method::POST, ["trello-webhook", id]) => match self.get_account(id).await {
+                Ok(Some(account)) => self.trello_webhook(uri, headers, body, &account, now).await,
+                Ok(None) => not_found(),
+                Err(err) => account_error(err),
+            },
+            (&Method::POST, ["slack-webhook", id]) => match self.get_account(id).await {
+                Ok(Some(account)) => self.slack_webhook(headers, body, &account, now).await,
+                Ok(None) => not_found(),
+                Err(err) => account_error(err),
+            },
+            _ => not_found(),
+        };
+    }
+
+    async fn get_account(&self, id: &str) -> Result<Option<Account>, Error> {
+        if let Some(account) = &self.single_account {
+            return Ok(Some(account.clone()));
+        }
+        return self.store.get_account(id).await;
+    }
+
+    // Without a queue the webhook is handled before responding, so a failure is retried by the sender
+    async fn trello_webhook(&self, uri: &Uri, headers: &HeaderMap, body: &[u8], account: &Account, now: u64) -> Response<Full<Bytes>> {
+        if !self.verify_trello_request(uri, headers, body, account) {
+            console_log!("Rejecting trello webhook with invalid signature");
+            return text(StatusCode::UNAUTHORIZED, "Unauthorized");
+        }
+
+        let webhook: TrelloWebhook = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(err) => return text(StatusCode::BAD_REQUEST, err.to_string()),
+        };
+        return match trello::handle(&self.store, &self.store, &self.api, &webhook, account, now).await {
+            Ok(result) => text(StatusCode::OK, result),
+            Err(err) => {
+                console_log!("Error handling trello webhook: {}", err);
+                error_response(&err)
+            }
+        };
+    }
+
+    fn verify_trello_request(&self, uri: &Uri, headers: &HeaderMap, body: &[u8], account: &Account) -> bool {
+        let app_secret = match &account.credentials.trello_app_secret {
+            Some(value) => value,
+            None => return false,
+        };
+        let signature = match header(headers, "X-Trello-Webhook") {
+            Some(value) => value,
+            None => return false,
+        };
+
+        // The callback URL was registered with any query string it has, so the signature covers it too
+        let path = uri.path_and_query().map(|path| path.as_str()).unwrap_or(uri.path());
+        let callback_url = format!("{}{}", self.public_url.trim_end_matches('/'), path);
+        return trello::verify_signature(app_secret, &callback_url, signature, body);
+    }
+
+    async fn slack_webhook(&self, headers: &HeaderMap, body: &[u8], account: &Account, now: u64) -> Response<Full<Bytes>> {
+        if !verify_slack_request(headers, body, account, now) {
+            console_log!("Rejecting slack webhook with invalid signature");
+            return text(StatusCode::UNAUTHORIZED, "Unauthorized");
+        }
+        if let Some(retry) = header(headers, "X-Slack-Retry-Num") {
+            console_log!("Slack retry {} ({:?})", retry, header(headers, "X-Slack-Retry-Reason"));
+        }
+
+        let webhook: MultipleWebhookEvent = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(err) => return text(StatusCode::BAD_REQUEST, err.to_string()),
+        };
+        let event = match webhook {
+            MultipleWebhookEvent::Challenge(challenge) => return text(StatusCode::OK, challenge.challenge),
+            MultipleWebhookEvent::EventWebhook(event) => event,
+            _ => return text(StatusCode::BAD_REQUEST, "Bad request"),
+        };
+        return match slack::handle(&self.store, &self.store, &self.api, &event, account, now).await {
+            Ok(result) => text(StatusCode::OK, result),
+            Err(err) => {
+                console_log!("Error handling slack webhook: {}", err);
+                error_response(&err)
+            }
+        };
+    }
+}
+
+fn verify_slack_request(headers: &HeaderMap, body: &[u8], account: &Account, now: u64) -> bool {
+    let signing_secret = match &account.credentials.slack_signing_secret {
+        Some(value) => value,
+        None => return false,
+    };
+    let timestamp = match header(headers, "X-Slack-Request-Timestamp") {
+        Some(value) => value,
+        None => return false,
+    };
+    let signature = match header(headers, "X-Slack-Signature") {
+        Some(value) => value,
+        None => return false,
+    };
+
+    return slack::verify_signature(signing_secret, timestamp, signature, body, now);
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    return headers.get(name).and_then(|value| value.to_str().ok());
+}
+
+fn text(status: StatusCode, body: impl Into<String>) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.into())));
+    *response.status_mut() = status;
+    return response;
+}
+
+fn not_found() -> Response<Full<Bytes>> {
+    return text(StatusCode::NOT_FOUND, "Not found");
+}
+
+// The account may well exist, so the sender is told to retry rather than that it is gone
+fn account_error(err: Error) -> Response<Full<Bytes>> {
+    console_log!("Error reading account: {}", err);
+    return error_response(&SyncError::from(err));
+}
+
+// The same status and Retry-After as SyncError::to_response gives the worker
+fn error_response(err: &SyncError) -> Response<Full<Bytes>> {
+    let status = StatusCode::from_u16(err.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut response = text(status, err.to_string());
+    if let SyncError::RateLimited { retry_after: Some(retry_after) } = err {
+        response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs()));
+    }
+    return response;
+}
+
+fn unix_now() -> u64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use hmac::{Hmac, Mac};
+    use http_body_util::BodyExt;
+    use hyper::header::HeaderMap;
+    use hyper::{Method, StatusCode};
+    use sha1::Sha1;
+    use sha2::Sha256;
+    use crate::action::ActionService;
+    use crate::api::tests::{ApiCall, RecordingApi};
+    use crate::database::LinkEnd;
+    use crate::error::SyncError;
+    use crate::migrations::tests::open_database;
+    use crate::server::Server;
+    use crate::sqlite::SqliteStore;
+    use crate::store::Store;
+
+    const ACCOUNT_ID: &str = "92cfdda8-bb81-480c-b3ca-092d3366b244";
+    const PUBLIC_URL: &str = "https://saas-sync.example.com";
+    // The signature trello::tests checks for card-moved.json, delivered to PUBLIC_URL
+    const TRELLO_SIGNATURE: &str = "6bxeprSQe1cHRx8QEt+xbmE9d+8=";
+    const SLACK_SIGNING_SECRET: &str = "slack-signing-secret";
+    const NOW: u64 = 1715523657;
+
+    const SEED: &str = "
+        INSERT INTO accounts VALUES ('92cfdda8-bb81-480c-b3ca-092d3366b244', 'Test Account');
+        INSERT INTO account_credentials (account_id, slack_signing_secret, trello_app_secret)
+            VALUES ('92cfdda8-bb81-480c-b3ca-092d3366b244', 'slack-signing-secret', 'trello-app-secret');
+        INSERT INTO channel_mappings (account_id, trello_board, trello_list, target_container)
+            VALUES ('92cfdda8-bb81-480c-b3ca-092d3366b244', 'boardid', NULL, 'C123456');
+        INSERT INTO slack_users (account_id, slack_user, display_name, fetched_at)
+            VALUES ('92cfdda8-bb81-480c-b3ca-092d3366b244', 'USER_ID', 'Jane', 1715523657);
+        INSERT INTO trello_members (account_id, username, member_id, full_name, fetched_at)
+            VALUES ('92cfdda8-bb81-480c-b3ca-092d3366b244', 'me', 'ownmemberid', 'Sync Bot', 1715523657);
+    ";
+
+    fn test_server() -> Server<RecordingApi> {
+        let store = SqliteStore::new(open_database()).unwrap();
+        store.execute_batch(SEED).unwrap();
+        return Server { store, api: RecordingApi::default(), public_url: PUBLIC_URL.to_string(), single_account: None };
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        return headers;
+    }
+
+    fn slack_signature(timestamp: u64, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SLACK_SIGNING_SECRET.as_bytes()).unwrap();
+        mac.update(format!("v0:{}:", timestamp).as_bytes());
+        mac.update(body);
+        return format!("v0={}", hex::encode(mac.finalize().into_bytes()));
+    }
+
+    fn trello_signature(callback_url: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(b"trello-app-secret").unwrap();
+        mac.update(body);
+        mac.update(callback_url.as_bytes());
+        return BASE64.encode(mac.finalize().into_bytes());
+    }
+
+    async fn call(server: &Server<RecordingApi>, method: Method, path: &str, headers: &HeaderMap, body: &[u8]) -> (StatusCode, String) {
+        let response = server.respond(&method, &path.parse().unwrap(), headers, body, NOW).await;
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        return (status, String::from_utf8(body.to_vec()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn signed_trello_webhook_starts_thread() {
+        let server = test_server();
+        let body = fs::read("./data/trello/card-moved.json").expect("Error reading file");
+        let path = format!("/trello-webhook/{}", ACCOUNT_ID);
+
+        let (status, text) = call(&server, Method::POST, &path, &headers(&[("X-Trello-Webhook", TRELLO_SIGNATURE)]), &body).await;
+
+        assert_eq!((StatusCode::OK, "Success"), (status, text.as_str()));
+        assert!(matches!(&server.api.calls()[1], ApiCall::SendThreadParent { channel, .. } if channel == "C123456"));
+        let account = server.store.get_account(ACCOUNT_ID).await.unwrap().unwrap();
+        let card = LinkEnd::new(ActionService::Trello, None, "abc64ds5ad45s6161d");
+        let link = server.store.get_link(&account, &card).await.unwrap().unwrap();
+        assert_eq!("1000000000.000001", link.target.id);
+    }
+
+    #[tokio::test]
+    async fn trello_webhook_rejects_bad_signatures_and_unknown_accounts() {
+        let server = test_server();
+        let body = fs::read("./data/trello/card-moved.json").expect("Error reading file");
+        let path = format!("/trello-webhook/{}", ACCOUNT_ID);
+
+        let (status, _) = call(&server, Method::POST, &path, &HeaderMap::new(), &body).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, status);
+
+        // The signature covers the callback URL, so it doesn't carry over to another host
+        let server = Server { public_url: "https://elsewhere.example.com".to_string(), ..server };
+        let (status, _) = call(&server, Method::POST, &path, &headers(&[("X-Trello-Webhook", TRELLO_SIGNATURE)]), &body).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, status);
+        // Nor to a callback URL with a query string
+        let (status, _) = call(&server, Method::POST, &format!("{}?source=board", path), &headers(&[("X-Trello-Webhook", TRELLO_SIGNATURE)]), &body).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, status);
+
+        let (status, _) = call(&server, Method::POST, "/trello-webhook/unknown", &headers(&[("X-Trello-Webhook", TRELLO_SIGNATURE)]), &body).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        let (status, _) = call(&server, Method::HEAD, "/trello-webhook/unknown", &HeaderMap::new(), &[]).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        let (status, _) = call(&server, Method::HEAD, &path, &HeaderMap::new(), &[]).await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(server.api.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn slack_challenge_and_signed_event() {
+        let server = test_server();
+        server.store.execute_batch("INSERT INTO links (account_id, source_service, source_id, target_service, target_container, target_id)
+            VALUES ('92cfdda8-bb81-480c-b3ca-092d3366b244', 'trello', 'abc64ds5ad45s6161d', 'slack', 'CHANNEL_ID', '1715287188.123456')").unwrap();
+        let path = format!("/slack-webhook/{}", ACCOUNT_ID);
+
+        let challenge = br#"{"token": "TOKEN", "challenge": "some-challenge", "type": "url_verification"}"#;
+        let signed = headers(&[("X-Slack-Request-Timestamp", &NOW.to_string()), ("X-Slack-Signature", &slack_signature(NOW, challenge))]);
+        assert_eq!((StatusCode::OK, "some-challenge".to_string()), call(&server, Method::POST, &path, &signed, challenge).await);
+
+        let body = fs::read("./data/slack/thread-replied.json").expect("Error reading file");
+        let (status, _) = call(&server, Method::POST, &path, &signed, &body).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, status);
+
+        let signed = headers(&[("X-Slack-Request-Timestamp", &NOW.to_string()), ("X-Slack-Signature", &slack_signature(NOW, &body))]);
+        assert_eq!((StatusCode::OK, "Woot".to_string()), call(&server, Method::POST, &path, &signed, &body).await);
+        // The display name comes from the slack_users cache, so Slack isn't asked
+        assert_eq!(vec![ApiCall::AddComment {
+            card: "abc64ds5ad45s6161d".to_string(),
+            text: "Jane (via Slack)\nSome reply from slack".to_string(),
+        }], server.api.calls());
+    }
+
+    #[tokio::test]
+    async fn handler_errors_keep_their_status() {
+        let server = test_server();
+        let server = Server { api: RecordingApi::default().fail("send_thread_parent", SyncError::RateLimited { retry_after: Some(std::time::Duration::from_secs(30)) }), ..server };
+        let body = fs::read("./data/trello/card-moved.json").expect("Error reading file");
+        let path = format!("/trello-webhook/{}", ACCOUNT_ID);
+
+        let response = server.respond(&Method::POST, &path.parse().unwrap(), &headers(&[("X-Trello-Webhook", TRELLO_SIGNATURE)]), &body, NOW).await;
+
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert_eq!("30", response.headers()["Retry-After"]);
+    }
+
+    #[tokio::test]
+    async fn trello_signature_covers_the_query_string() {
+        let server = test_server();
+        let body = fs::read("./data/trello/card-moved.json").expect("Error reading file");
+        let path = format!("/trello-webhook/{}?source=board", ACCOUNT_ID);
+        let signature = trello_signature(&format!("{}{}", PUBLIC_URL, path), &body);
+
+        let (status, text) = call(&server, Method::POST, &path, &headers(&[("X-Trello-Webhook", &signature)]), &body).await;
+
+        assert_eq!((StatusCode::OK, "Success"), (status, text.as_str()));
+    }
+
+    #[tokio::test]
+    async fn account_errors_are_not_taken_for_missing_accounts() {
+        let server = test_server();
+        server.store.execute_batch("DROP TABLE account_settings").unwrap();
+        let body = fs::read("./data/trello/card-moved.json").expect("Error reading file");
+        let path = format!("/trello-webhook/{}", ACCOUNT_ID);
+
+        let (status, _) = call(&server, Method::HEAD, &path, &HeaderMap::new(), &[]).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+        let (status, _) = call(&server, Method::POST, &path, &headers(&[("X-Trello-Webhook", TRELLO_SIGNATURE)]), &body).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+        let (status, _) = call(&server, Method::POST, &format!("/slack-webhook/{}", ACCOUNT_ID), &HeaderMap::new(), &[]).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+        assert!(server.api.calls().is_empty());
+    }
+}
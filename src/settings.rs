@@ -0,0 +1,98 @@
+use serde::{Deserialize, Deserializer};
+use worker::{Env, Error};
+
+/// What happens to the mirrored copy when a message or comment is deleted
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionSync {
+    Delete,
+    // Leave the copy in place, marked as deleted
+    #[default]
+    Annotate,
+    Ignore,
+}
+
+impl DeletionSync {
+    pub fn parse(value: &str) -> Option<DeletionSync> {
+        return match value {
+            "delete" => Some(DeletionSync::Delete),
+            "annotate" => Some(DeletionSync::Annotate),
+            "ignore" => Some(DeletionSync::Ignore),
+            _ => None,
+        };
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Settings {
+    #[serde(default)]
+    pub deletion_sync: DeletionSync,
+    // Copy shared files across, rather than only linking to them
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pub mirror_attachments: bool,
+}
+
+// D1 has no boolean type, so flags are stored as 0 or 1
+fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Number(f64),
+    }
+
+    return Ok(match Flag::deserialize(deserializer)? {
+        Flag::Bool(value) => value,
+        Flag::Number(value) => value != 0.0,
+    });
+}
+
+fn parse_flag(value: &str) -> bool {
+    return matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on");
+}
+
+pub async fn get_settings(env: &Env, account_id: &str) -> Result<Settings, Error> {
+    // An account without a settings row uses the defaults
+    return Ok(crate::database::get_account_settings(env, account_id).await?.unwrap_or_default());
+}
+
+// Used when the worker is bound to a single account through the ACCOUNT_ID secret
+pub fn get_settings_from_env(env: &Env) -> Settings {
+    return settings_from_vars(|name| env.var(name).ok().map(|val| val.to_string()));
+}
+
+/// Settings from the variables named after them, such as DELETION_SYNC, wherever they are kept
+pub fn settings_from_vars<F: Fn(&str) -> Option<String>>(var: F) -> Settings {
+    return Settings {
+        deletion_sync: var("DELETION_SYNC").and_then(|value| DeletionSync::parse(&value)).unwrap_or_default(),
+        mirror_attachments: var("MIRROR_ATTACHMENTS").map(|value| parse_flag(&value)).unwrap_or_default(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::{parse_flag, DeletionSync, Settings};
+
+    #[test]
+    fn settings_from_database_row() {
+        let settings: Settings = serde_json::from_str(r#"{"account_id": "account", "deletion_sync": "delete", "mirror_attachments": 1}"#).unwrap();
+        assert_eq!(DeletionSync::Delete, settings.deletion_sync);
+        assert!(settings.mirror_attachments);
+
+        let settings: Settings = serde_json::from_str(r#"{"deletion_sync": "ignore", "mirror_attachments": 0}"#).unwrap();
+        assert!(!settings.mirror_attachments);
+
+        let settings: Settings = serde_json::from_str("{}").unwrap();
+        assert_eq!(DeletionSync::Annotate, settings.deletion_sync);
+        assert!(!settings.mirror_attachments);
+    }
+
+    #[test]
+    fn parse_flag_values() {
+        assert!(parse_flag("true"));
+        assert!(parse_flag("1"));
+        assert!(parse_flag(" On "));
+        assert!(!parse_flag("false"));
+        assert!(!parse_flag(""));
+    }
+}
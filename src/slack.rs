@@ -1,13 +1,26 @@
+use std::future::Future;
+use hmac::{Hmac, Mac};
+use reqwest::RequestBuilder;
 use serde::{Deserialize, Serialize};
-use worker::{console_log, Env, Error, Response};
-use trello::add_comment_to_card;
+use serde::de::DeserializeOwned;
+use sha2::Sha256;
 use crate::account::Account;
-use crate::action::{Action, ActionService, ActionTargetSource, ActionType, ActionUpdate};
-use crate::database::{get_link_from_slack_thread, Link};
-use crate::trello;
+use crate::action::{Action, ActionAttachment, ActionService, ActionTargetSource, ActionType, ActionUpdate};
+use crate::api::{SlackApi, TrelloApi};
+use crate::connector::{action_target, log_after_delivery, target_end, Connector};
+use crate::database::{LinkEnd, MessageMapping};
+use crate::error::SyncError;
+use crate::format::{slack_to_trello, strike_through, Mentions};
+use crate::settings::DeletionSync;
+use crate::http::send_with_retry;
+use crate::events::{process_once, ProcessedEvents};
+use crate::store::Store;
+use crate::trello::TrelloConnector;
+use crate::users::{resolve_slack_mentions, slack_sender_name, UserDirectory};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
 pub enum MultipleWebhookEvent {
     Challenge(Challenge),
     EventWebhook(EventWebhook),
@@ -21,18 +34,56 @@ pub struct Challenge{
     #[serde(rename = "type")]
     pub type_: String,
 }
-#[derive(Serialize, Deserialize, Debug)]
-struct Block{
+// Header text is limited to 150 characters and section text to 3000
+const MAX_HEADER_LENGTH: usize = 150;
+const MAX_SECTION_LENGTH: usize = 3000;
+
+/// Block Kit layout blocks, see https://api.slack.com/reference/block-kit/blocks
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Block {
+    Header { text: TextObject },
+    Section {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        text: Option<TextObject>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        fields: Vec<TextObject>,
+    },
+    Context { elements: Vec<TextObject> },
+    Actions { elements: Vec<ButtonElement> },
+}
 
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TextObject {
+    PlainText { text: String },
+    Mrkdwn { text: String },
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ButtonElement {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub text: TextObject,
+    pub url: String,
 }
 
+// Message subtypes that change an existing message, see https://api.slack.com/events/message#subtypes
+const MESSAGE_CHANGED: &str = "message_changed";
+const MESSAGE_DELETED: &str = "message_deleted";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Event {
+    // Events about an existing message have no user or text of their own, those are on `message`
+    #[serde(default)]
     pub user: String,
     #[serde(rename = "type")]
     pub type_: String,
+    pub subtype: Option<String>,
     pub ts: String,
+    #[serde(default)]
     pub text: String,
+    #[serde(default)]
     pub team: String,
     pub thread_ts: Option<String>,
   //  pub parent_user_id: String,
@@ -41,6 +92,52 @@ pub struct Event {
     pub event_ts: String,
     pub channel_type: String,
     pub bot_id: Option<String>,
+    // The message after a message_changed
+    pub message: Option<EventMessage>,
+    // The message before a message_changed or message_deleted
+    pub previous_message: Option<EventMessage>,
+    pub deleted_ts: Option<String>,
+    // Files shared with the message, for the file_share subtype
+    #[serde(default)]
+    pub files: Vec<EventFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EventMessage {
+    pub user: Option<String>,
+    #[serde(default)]
+    pub text: String,
+    pub ts: String,
+    pub thread_ts: Option<String>,
+    pub bot_id: Option<String>,
+    #[serde(default)]
+    pub files: Vec<EventFile>,
+}
+
+/// A file shared in a message, see https://api.slack.com/types/file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EventFile {
+    pub id: String,
+    // Files over the workspace's storage limit have no name or urls
+    pub name: Option<String>,
+    pub title: Option<String>,
+    pub mimetype: Option<String>,
+    pub size: Option<u64>,
+    pub url_private_download: Option<String>,
+    pub permalink: Option<String>,
+}
+
+impl EventFile {
+    fn to_attachment(&self) -> Option<ActionAttachment> {
+        return Some(ActionAttachment {
+            id: self.id.to_owned(),
+            name: self.name.clone().or(self.title.clone())?,
+            url: self.url_private_download.clone()?,
+            permalink: self.permalink.clone()?,
+            size: self.size,
+            mime_type: self.mimetype.to_owned(),
+        });
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -54,14 +151,20 @@ pub struct EventWebhook {
     pub event_context: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Debug)]
 struct ChatMessage {
     channel: String,
     text: String,
     thread_ts: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    blocks: Vec<Block>,
+    // The message to replace, only for chat.update
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ts: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(dead_code)]
 struct ChatMessageResponse {
     ok: bool,
     channel: String,
@@ -70,6 +173,7 @@ struct ChatMessageResponse {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(dead_code)]
 struct ChatMessageResponseMessage {
     user: String,
     type_: String,
@@ -119,7 +223,7 @@ struct ChatMessageResponseMessage {
 // }
 
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct ChatPostMessageResponse{
     pub ok: bool,
     pub channel: String,
@@ -132,109 +236,733 @@ pub struct ChatPostMessageResponse{
 // {"channel":"C123ABC456","text":"I hope the tour went well, Mr. Wonka.","attachments":[{"text":"Who wins the lifetime supply of chocolate?","fallback":"You could be telling the computer exactly what it can do with a lifetime supply of chocolate.","color":"#3AA3E3","attachment_type":"default","callback_id":"select_simple_1234","actions":[{"name":"winners_list","text":"Who should win?","type":"select","data_source":"users"}]}]}
 
 const POST_MESSAGE_URL: &str = "https://slack.com/api/chat.postMessage";
+const UPDATE_MESSAGE_URL: &str = "https://slack.com/api/chat.update";
+const CONVERSATIONS_JOIN_URL: &str = "https://slack.com/api/conversations.join";
+const DELETE_MESSAGE_URL: &str = "https://slack.com/api/chat.delete";
+const USERS_INFO_URL: &str = "https://slack.com/api/users.info";
+const GET_UPLOAD_URL: &str = "https://slack.com/api/files.getUploadURLExternal";
+const COMPLETE_UPLOAD_URL: &str = "https://slack.com/api/files.completeUploadExternal";
 
-pub async fn send_action(env: &Env, action: Action) -> ChatPostMessageResponse {
+/// The `error` code from a Slack response with `"ok": false`.
+/// See https://api.slack.com/web#evaluating_responses
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlackApiError {
+    NotInChannel,
+    InvalidAuth,
+    ChannelNotFound,
+    Ratelimited,
+    MsgTooLong,
+    UserNotFound,
+    #[serde(untagged)]
+    Unknown(String),
+}
+
+impl From<SlackApiError> for SyncError {
+    fn from(err: SlackApiError) -> Self {
+        return match err {
+            SlackApiError::InvalidAuth => SyncError::Auth("invalid_auth".to_string()),
+            SlackApiError::ChannelNotFound => SyncError::NotFound("channel_not_found".to_string()),
+            SlackApiError::Ratelimited => SyncError::RateLimited { retry_after: None },
+            SlackApiError::NotInChannel => SyncError::BadResponse("not_in_channel".to_string()),
+            SlackApiError::MsgTooLong => SyncError::BadResponse("msg_too_long".to_string()),
+            SlackApiError::UserNotFound => SyncError::NotFound("user_not_found".to_string()),
+            SlackApiError::Unknown(code) => SyncError::BadResponse(code),
+        };
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SlackResponseStatus {
+    ok: bool,
+    error: Option<SlackApiError>,
+}
+
+#[derive(Serialize, Debug)]
+struct ChatDelete {
+    channel: String,
+    ts: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ConversationsJoin {
+    channel: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct UploadUrlResponse {
+    upload_url: String,
+    file_id: String,
+}
+
+#[derive(Serialize, Debug)]
+struct CompleteUpload {
+    files: Vec<CompleteUploadFile>,
+    channel_id: String,
+    thread_ts: String,
+}
+
+#[derive(Serialize, Debug)]
+struct CompleteUploadFile {
+    id: String,
+    title: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UsersInfoResponse {
+    pub user: SlackUser,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[allow(dead_code)]
+pub struct SlackUser {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub profile: SlackUserProfile,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct SlackUserProfile {
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default)]
+    pub real_name: String,
+}
+
+impl SlackUser {
+    /// The name Slack shows for the user, which falls back to the real name when no display name is set
+    pub fn display_name(&self) -> &str {
+        if !self.profile.display_name.is_empty() {
+            return &self.profile.display_name;
+        }
+        if !self.profile.real_name.is_empty() {
+            return &self.profile.real_name;
+        }
+        return &self.name;
+    }
+}
+
+/// Splits a Slack response body into the expected payload or the error code Slack returned
+pub fn parse_response<T: DeserializeOwned>(body: &str) -> Result<Result<T, SlackApiError>, SyncError> {
+    let status: SlackResponseStatus = serde_json::from_str(body)
+        .map_err(|err| SyncError::BadResponse(err.to_string()))?;
+    if !status.ok {
+        return Ok(Err(status.error.unwrap_or(SlackApiError::Unknown("unknown_error".to_string()))));
+    }
+
+    let payload = serde_json::from_str(body)
+        .map_err(|err| SyncError::BadResponse(err.to_string()))?;
+    return Ok(Ok(payload));
+}
+
+const SIGNATURE_VERSION: &str = "v0";
+// Requests older than this are treated as replays
+const MAX_REQUEST_AGE_SECONDS: u64 = 60 * 5;
+
+/// Checks the `X-Slack-Signature` header against the raw request body.
+/// See https://api.slack.com/authentication/verifying-requests-from-slack
+pub fn verify_signature(signing_secret: &str, timestamp: &str, signature: &str, body: &[u8], now: u64) -> bool {
+    let request_time: u64 = match timestamp.parse() {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+    if now.abs_diff(request_time) > MAX_REQUEST_AGE_SECONDS {
+        return false;
+    }
+
+    let expected = match signature.strip_prefix(&format!("{}=", SIGNATURE_VERSION)) {
+        Some(value) => value,
+        None => return false,
+    };
+    let expected = match hex::decode(expected) {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+
+    let mut mac = match Hmac::<Sha256>::new_from_slice(signing_secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(format!("{}:{}:", SIGNATURE_VERSION, timestamp).as_bytes());
+    mac.update(body);
+
+    return mac.verify_slice(&expected).is_ok();
+}
+
+pub async fn send_action(account: &Account, action: Action) -> Result<ChatPostMessageResponse, SyncError> {
 
     let body = ChatMessage{
-        channel: "#test".to_string(),
+        channel: action.target.channel.unwrap_or_default(),
+        blocks: render_blocks(&action.update),
         text: action.update.text,
         thread_ts: action.target.id,
+        ts: None,
     };
 
-    let res = send_message(env, body).await;
+    return post_message(account, body).await;
+}
 
-   // console_log!("{}", res.status().as_str()
-    let json: ChatPostMessageResponse = res.json().await.unwrap();
-    console_log!("{:?}", &json);
-    return json;
+/// Starts a new thread in `channel` with `update` as the parent message
+pub async fn send_thread_parent(account: &Account, channel: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError> {
+    let body = ChatMessage{
+        channel: channel.to_string(),
+        blocks: render_blocks(update),
+        text: update.text.to_owned(),
+        thread_ts: None,
+        ts: None,
+    };
+
+    return post_message(account, body).await;
+}
+
+/// Replies in an existing thread
+pub async fn send_thread_reply(account: &Account, channel: &str, thread_ts: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError> {
+    let body = ChatMessage{
+        channel: channel.to_string(),
+        blocks: render_blocks(update),
+        text: update.text.to_owned(),
+        thread_ts: Some(thread_ts.to_string()),
+        ts: None,
+    };
+
+    return post_message(account, body).await;
 }
 
-async fn send_message(env: &Env, body: ChatMessage) -> reqwest::Response {
+/// Replaces the content of an existing message, such as a thread parent
+pub async fn update_message(account: &Account, channel: &str, ts: &str, update: &ActionUpdate) -> Result<ChatPostMessageResponse, SyncError> {
+    let body = ChatMessage{
+        channel: channel.to_string(),
+        blocks: render_blocks(update),
+        text: update.text.to_owned(),
+        thread_ts: None,
+        ts: Some(ts.to_string()),
+    };
+
+    let result = call_api(account, UPDATE_MESSAGE_URL, &body).await?;
+    return Ok(result?);
+}
+
+pub async fn delete_message(account: &Account, channel: &str, ts: &str) -> Result<(), SyncError> {
+    let body = ChatDelete { channel: channel.to_string(), ts: ts.to_string() };
+    call_api::<_, serde_json::Value>(account, DELETE_MESSAGE_URL, &body).await??;
+    return Ok(());
+}
+
+async fn post_message(account: &Account, body: ChatMessage) -> Result<ChatPostMessageResponse, SyncError> {
     console_log!("Sending message");
-   // console_log!("AT - {:?}",  env.secret("SLACK_AUTH_TOKEN".as_ref()).expect("ERR"));
+    let mut result = call_api(account, POST_MESSAGE_URL, &body).await?;
+    if result == Err(SlackApiError::NotInChannel) {
+        // Public channels can be joined without being invited, then try once more
+        console_log!("Joining channel {}", body.channel);
+        call_api::<_, serde_json::Value>(account, CONVERSATIONS_JOIN_URL, &ConversationsJoin { channel: body.channel.to_owned() }).await??;
+        result = call_api(account, POST_MESSAGE_URL, &body).await?;
+    }
+
+    let json: ChatPostMessageResponse = result?;
+    console_log!("{:?}", &json);
+    return Ok(json);
+}
+
+/// Lays out an update as Block Kit, empty for plain text only updates
+pub fn render_blocks(update: &ActionUpdate) -> Vec<Block> {
+    let title = match &update.title {
+        Some(value) => value,
+        None => return vec![],
+    };
+
+    let mut blocks = vec![Block::Header { text: TextObject::PlainText { text: truncate(title, MAX_HEADER_LENGTH) } }];
+    if let Some(body) = &update.body {
+        blocks.push(Block::Section { text: Some(TextObject::Mrkdwn { text: truncate(body, MAX_SECTION_LENGTH) }), fields: vec![] });
+    }
+    if !update.fields.is_empty() {
+        let fields = update.fields.iter()
+            .map(|field| TextObject::Mrkdwn { text: format!("*{}*\n{}", field.label, field.value) })
+            .collect();
+        blocks.push(Block::Section { text: None, fields });
+    }
+    if let Some(actor) = &update.actor {
+        blocks.push(Block::Context { elements: vec![TextObject::Mrkdwn { text: format!("by {}", actor) }] });
+    }
+    if let Some(link) = &update.link {
+        blocks.push(Block::Actions {
+            elements: vec![ButtonElement {
+                type_: "button".to_string(),
+                text: TextObject::PlainText { text: link.label.to_owned() },
+                url: link.url.to_owned(),
+            }],
+        });
+    }
+    return blocks;
+}
+
+fn truncate(text: &str, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_string();
+    }
+    let truncated: String = text.chars().take(max_length - 1).collect();
+    return format!("{}…", truncated);
+}
+
+/// A link to a thread that works for anyone in the workspace
+pub fn thread_url(channel: &str, ts: &str) -> String {
+    return format!("https://slack.com/archives/{}/p{}", channel, ts.replace('.', ""));
+}
+
+/// Looks up a user's profile, see https://api.slack.com/methods/users.info
+pub async fn get_user(account: &Account, user_id: &str) -> Result<Result<SlackUser, SlackApiError>, SyncError> {
+    // users.info only accepts form encoded arguments, not JSON
+    let request = reqwest::Client::new().get(USERS_INFO_URL).query(&[("user", user_id)]);
+    let result: Result<UsersInfoResponse, SlackApiError> = send_api_request(account, request).await?;
+    return Ok(result.map(|response| response.user));
+}
+
+/// Downloads a file shared in Slack, which needs the bot token and the files:read scope
+pub async fn download_file(account: &Account, url: &str) -> Result<Vec<u8>, SyncError> {
+    let request = reqwest::Client::new().get(url)
+        .header("Authorization", format!("Bearer {}", auth_token(account)?));
+    let response = send_with_retry(request).await?;
+    return Ok(response.bytes().await?.to_vec());
+}
+
+/// Shares a file in a thread, see https://api.slack.com/messaging/files#uploading_files
+pub async fn upload_file(account: &Account, channel: &str, thread_ts: &str, name: &str, bytes: Vec<u8>) -> Result<(), SyncError> {
+    // files.getUploadURLExternal only accepts form encoded arguments, not JSON
+    let length = bytes.len().to_string();
+    let request = reqwest::Client::new().get(GET_UPLOAD_URL).query(&[("filename", name), ("length", &length)]);
+    let upload: UploadUrlResponse = send_api_request(account, request).await??;
+
+    send_with_retry(reqwest::Client::new().post(&upload.upload_url).body(bytes)).await?;
+
+    let body = CompleteUpload {
+        files: vec![CompleteUploadFile { id: upload.file_id, title: name.to_string() }],
+        channel_id: channel.to_string(),
+        thread_ts: thread_ts.to_string(),
+    };
+    call_api::<_, serde_json::Value>(account, COMPLETE_UPLOAD_URL, &body).await??;
+    return Ok(());
+}
+
+async fn call_api<B: Serialize, T: DeserializeOwned>(account: &Account, url: &str, body: &B) -> Result<Result<T, SlackApiError>, SyncError> {
     let client = reqwest::Client::new();
-    let res = match client.post(POST_MESSAGE_URL)
+    let request = client.post(url)
         .header("Content-Type", "application/json; charset=utf-8")
-        .header("Authorization", format!("Bearer {}", env.secret("SLACK_AUTH_TOKEN".as_ref()).unwrap().to_string()))
-        .json(&body)
-        .send()
-        .await{
-        Ok(value)=> value,
-        Err(_err)=> {
-            console_log!("{}", _err.to_string());
-            panic!("ERR");
-        },
-    };
+        .json(body);
+
+    return send_api_request(account, request).await;
+}
+
+async fn send_api_request<T: DeserializeOwned>(account: &Account, request: RequestBuilder) -> Result<Result<T, SlackApiError>, SyncError> {
+    let request = request.header("Authorization", format!("Bearer {}", auth_token(account)?));
+
+    let response = send_with_retry(request).await?;
+    return parse_response(&response.text().await?);
+}
 
-    return res;
+fn auth_token(account: &Account) -> Result<&str, SyncError> {
+    return match account.credentials.slack_auth_token.as_deref() {
+        Some(value) => Ok(value),
+        None => Err(SyncError::Auth(format!("No slack auth token for account {}", account.id))),
+    };
 }
 
-pub async fn handle_webhook(webhook: EventWebhook, env: Env, _account: Account) -> worker::Result<Response> {
+/// Mirrors a Slack thread reply, edit or deletion to Trello, returning what was done with it
+pub async fn handle<S, D, A>(store: &S, directory: &D, api: &A, webhook: &EventWebhook, account: &Account, now: u64) -> Result<&'static str, SyncError>
+where
+    S: Store,
+    D: UserDirectory,
+    A: SlackApi + TrelloApi,
+{
     console_log!("Handling webhook start");
+    match webhook.event.subtype.as_deref() {
+        Some(MESSAGE_CHANGED) => return handle_message_changed(store, directory, api, webhook, account, now).await,
+        Some(MESSAGE_DELETED) => return handle_message_deleted(store, directory, api, webhook, account, now).await,
+        _ => {}
+    }
+
     match &webhook.event.bot_id.as_deref() {
         None => {}, // No bot id
         _ => {
             // This is a message from a bot
             console_log!("Skipping webhook from bot account");
-            return Response::ok("Skipping bot")
+            return Ok("Skipping bot")
         },
     }
 
-    match &webhook.event.thread_ts.as_deref() {
+    let Some(thread) = SlackConnector::new(api).link_end(webhook) else {
+        // No thread id
+        console_log!("Skipping none thread message");
+        return Ok("Skipping none thread message")
+    };
+
+    console_log!("Handling webhook real");
+
+    let target = store.get_link(account, &thread).await?.map(|link| link.other_end(&thread).clone());
+
+    let deliver = |account, action: Action| async move {
+        return match action.target.service {
+            ActionService::Slack => deliver_and_map(store, &SlackConnector::new(api), api, account, action, &webhook.event.channel, &webhook.event.ts).await,
+            ActionService::Trello => deliver_and_map(store, &TrelloConnector::new(api), api, account, action, &webhook.event.channel, &webhook.event.ts).await,
+        };
+    };
+    if !process_event(store, directory, &SlackConnector::new(api), account, webhook, target, now, deliver).await? {
+        console_log!("Skipping already processed event {}", webhook.event_id);
+        return Ok("Already processed");
+    }
+
+    console_log!("Woot");
+    return Ok("Woot");
+}
+
+// The copy is remembered against the Slack message so edits to the message can follow it
+async fn deliver_and_map<S: Store, C: Connector, A: SlackApi>(store: &S, target: &C, api: &A, account: &Account, action: Action, channel: &str, ts: &str) -> Result<(), SyncError> {
+    if action.action == ActionType::None {
+        return Ok(());
+    }
+
+    let thread = target_end(&action.target);
+    let attachments = action.update.attachments.clone();
+    let message = target.deliver(account, action).await?;
+    let source = LinkEnd::new(ActionService::Slack, Some(channel), ts);
+    log_after_delivery("saving message mapping", store.create_message_mapping(account, &source, &message).await);
+
+    if account.settings.mirror_attachments {
+        for attachment in attachments {
+            let result = mirror_attachment_to_thread(target, api, account, &thread, &attachment).await;
+            log_after_delivery(&format!("mirroring file {}", attachment.id), result);
+        }
+    }
+    return Ok(());
+}
+
+// Files over the size limit are left to the connector to link back to Slack instead
+async fn mirror_attachment_to_thread<C: Connector, A: SlackApi>(target: &C, api: &A, account: &Account, thread: &LinkEnd, attachment: &ActionAttachment) -> Result<(), SyncError> {
+    if !attachment.can_copy() {
+        return target.attach(account, thread, attachment, None).await;
+    }
+    let bytes = api.download_file(account, &attachment.url).await?;
+    return target.attach(account, thread, attachment, Some(bytes)).await;
+}
+
+fn thread_end(channel: &str, ts: &str) -> LinkEnd {
+    return LinkEnd::new(ActionService::Slack, Some(channel), ts);
+}
+
+fn message_end(response: &ChatPostMessageResponse) -> LinkEnd {
+    return thread_end(&response.channel, &response.ts);
+}
+
+/// Links Slack threads to items elsewhere. A linked item's thread is started with a summary message in a channel,
+/// and messages are replies in it.
+pub struct SlackConnector<'a, A> {
+    api: &'a A,
+}
+
+impl<'a, A: SlackApi> SlackConnector<'a, A> {
+    pub fn new(api: &'a A) -> SlackConnector<'a, A> {
+        return SlackConnector { api };
+    }
+}
+
+impl<A: SlackApi> Connector for SlackConnector<'_, A> {
+    type Webhook = EventWebhook;
+
+    fn service(&self) -> ActionService {
+        return ActionService::Slack;
+    }
+
+    fn link_end(&self, webhook: &EventWebhook) -> Option<LinkEnd> {
+        return webhook.event.thread_ts.as_deref().map(|ts| thread_end(&webhook.event.channel, ts));
+    }
+
+    async fn parse_action<D: UserDirectory>(&self, directory: &D, account: &Account, webhook: &EventWebhook, target: Option<&LinkEnd>, now: u64) -> Action {
+        return parse_message(directory, self.api, account, webhook, target, now).await;
+    }
+
+    async fn create_thread(&self, account: &Account, channel: &str, summary: &ActionUpdate) -> Result<LinkEnd, SyncError> {
+        let parent = self.api.send_thread_parent(account, channel, summary).await?;
+        return Ok(message_end(&parent));
+    }
+
+    async fn update_thread(&self, account: &Account, thread: &LinkEnd, summary: &ActionUpdate) -> Result<(), SyncError> {
+        let channel = thread.container.as_deref().unwrap_or_default();
+        self.api.update_message(account, channel, &thread.id, summary).await?;
+        return Ok(());
+    }
+
+    async fn reply(&self, account: &Account, thread: &LinkEnd, update: &ActionUpdate) -> Result<LinkEnd, SyncError> {
+        let channel = thread.container.as_deref().unwrap_or_default();
+        let response = self.api.send_thread_reply(account, channel, &thread.id, update).await?;
+        return Ok(message_end(&response));
+    }
+
+    async fn deliver(&self, account: &Account, action: Action) -> Result<LinkEnd, SyncError> {
+        let response = self.api.send_action(account, action).await?;
+        return Ok(message_end(&response));
+    }
+
+    async fn edit(&self, account: &Account, action: Action) -> Result<(), SyncError> {
+        let channel = action.target.channel.clone().unwrap_or_default();
+        let ts = action.target.id.clone().unwrap_or_default();
+        self.api.update_message(account, &channel, &ts, &action.update).await?;
+        return Ok(());
+    }
+
+    async fn delete(&self, account: &Account, action: Action) -> Result<(), SyncError> {
+        let channel = action.target.channel.clone().unwrap_or_default();
+        let ts = action.target.id.clone().unwrap_or_default();
+        return self.api.delete_message(account, &channel, &ts).await;
+    }
+
+    // The message already links to a file that is too large to copy
+    async fn attach(&self, account: &Account, thread: &LinkEnd, attachment: &ActionAttachment, bytes: Option<Vec<u8>>) -> Result<(), SyncError> {
+        let (Some(channel), Some(bytes)) = (thread.container.as_deref(), bytes) else {
+            return Ok(());
+        };
+        return self.api.upload_file(account, channel, &thread.id, &attachment.name, bytes).await;
+    }
+}
+
+async fn handle_message_changed<S, D, A>(store: &S, directory: &D, api: &A, webhook: &EventWebhook, account: &Account, now: u64) -> Result<&'static str, SyncError>
+where
+    S: Store,
+    D: UserDirectory,
+    A: SlackApi + TrelloApi,
+{
+    let message = match &webhook.event.message {
+        Some(message) => message,
+        None => return Ok("No message"),
+    };
+    if message.bot_id.is_some() {
+        // Includes our own chat.update calls
+        console_log!("Skipping edit from bot account");
+        return Ok("Skipping bot");
+    }
+    // Unfurling a link also changes the message, without changing its text
+    let previous_text = webhook.event.previous_message.as_ref().map(|previous| previous.text.as_str());
+    if previous_text == Some(message.text.as_str()) {
+        return Ok("Text unchanged");
+    }
+
+    let edited = LinkEnd::new(ActionService::Slack, Some(&webhook.event.channel), &message.ts);
+    let mapping = match store.get_message_mapping(account, &edited).await? {
+        // Only copies we posted follow their message, a message copied from elsewhere isn't edited back into it
+        Some(mapping) if mapping.source == edited => mapping,
+        Some(_) => {
+            console_log!("Message {} was copied from another service, skipping", message.ts);
+            return Ok("Message not from Slack");
+        }
         None => {
-            // No thread id
-            console_log!("Skipping none thread message");
-            return Response::ok("Skipping none thread message")
-        },
-        _ => {},
+            console_log!("Message {} was not synced, skipping", message.ts);
+            return Ok("Message not synced");
+        }
+    };
+
+    let edit = |account, action: Action| async move {
+        return match action.target.service {
+            ActionService::Slack => SlackConnector::new(api).edit(account, action).await,
+            ActionService::Trello => TrelloConnector::new(api).edit(account, action).await,
+        };
+    };
+    if !process_edit(store, directory, api, account, webhook, mapping, now, edit).await? {
+        console_log!("Skipping already processed event {}", webhook.event_id);
+        return Ok("Already processed");
     }
+    return Ok("Updated");
+}
 
-    console_log!("Handling webhook real");
+// Slack retries deliveries it thinks timed out, so each event_id is only acted on once
+#[allow(clippy::too_many_arguments)]
+async fn process_event<'a, E, D, C, F, Fut>(events: &E, directory: &D, source: &C, account: &'a Account, webhook: &EventWebhook, target: Option<LinkEnd>, now: u64, deliver: F) -> Result<bool, SyncError>
+where
+    E: ProcessedEvents,
+    D: UserDirectory,
+    C: Connector<Webhook = EventWebhook>,
+    F: FnOnce(&'a Account, Action) -> Fut,
+    Fut: Future<Output = Result<(), SyncError>>,
+{
+    return process_once(events, account, &ActionService::Slack, &webhook.event_id, now, || async move {
+        let action = source.parse_action(directory, account, webhook, target.as_ref(), now).await;
+        return deliver(account, action).await;
+    }).await;
+}
+
+async fn parse_message<D: UserDirectory, A: SlackApi>(directory: &D, api: &A, account: &Account, webhook: &EventWebhook, target: Option<&LinkEnd>, now: u64) -> Action {
+    let sender = slack_sender_name(directory, api, account, &webhook.event.user, now).await;
+    let mentions = resolve_slack_mentions(directory, api, account, &webhook.event.text, now).await;
+    return generate_action(webhook, target, &sender, &mentions);
+}
+
+async fn handle_message_deleted<S, D, A>(store: &S, directory: &D, api: &A, webhook: &EventWebhook, account: &Account, now: u64) -> Result<&'static str, SyncError>
+where
+    S: Store,
+    D: UserDirectory,
+    A: SlackApi + TrelloApi,
+{
+    if account.settings.deletion_sync == DeletionSync::Ignore {
+        return Ok("Deletions ignored");
+    }
+    let previous = match &webhook.event.previous_message {
+        Some(previous) => previous,
+        None => return Ok("No message"),
+    };
+    if previous.bot_id.is_some() {
+        // Mirrored Trello comments, including ones we remove ourselves
+        console_log!("Skipping deletion of bot message");
+        return Ok("Skipping bot");
+    }
+
+    let ts = webhook.event.deleted_ts.as_deref().unwrap_or(&previous.ts);
+    let deleted = LinkEnd::new(ActionService::Slack, Some(&webhook.event.channel), ts);
+    let mapping = match store.get_message_mapping(account, &deleted).await? {
+        Some(mapping) if mapping.source == deleted => mapping,
+        Some(_) => {
+            console_log!("Message {} was copied from another service, skipping", ts);
+            return Ok("Message not from Slack");
+        }
+        None => {
+            console_log!("Message {} was not synced, skipping", ts);
+            return Ok("Message not synced");
+        }
+    };
+
+    let send = |account, action: Action| async move {
+        return match action.target.service {
+            ActionService::Slack => send_deletion(store, &SlackConnector::new(api), account, action).await,
+            ActionService::Trello => send_deletion(store, &TrelloConnector::new(api), account, action).await,
+        };
+    };
+    if !process_deletion(store, directory, api, account, webhook, mapping, now, send).await? {
+        console_log!("Skipping already processed event {}", webhook.event_id);
+        return Ok("Already processed");
+    }
+    return Ok("Deleted");
+}
 
-    let link = get_link_from_slack_thread(&env, &webhook.event.thread_ts.clone().unwrap()).await;
+async fn send_deletion<S: Store, C: Connector>(store: &S, target: &C, account: &Account, action: Action) -> Result<(), SyncError> {
+    let copy = target_end(&action.target);
+    match action.action {
+        ActionType::DeleteMessage => target.delete(account, action).await?,
+        ActionType::EditMessage => target.edit(account, action).await?,
+        _ => return Ok(()),
+    }
+    if let Err(err) = store.delete_message_mapping(account, &copy).await {
+        console_log!("Error removing message mapping: {}", err);
+    }
+    return Ok(());
+}
 
+#[allow(clippy::too_many_arguments)]
+async fn process_deletion<'a, E, D, A, F, Fut>(events: &E, directory: &D, api: &A, account: &'a Account, webhook: &EventWebhook, mapping: MessageMapping, now: u64, send: F) -> Result<bool, SyncError>
+where
+    E: ProcessedEvents,
+    D: UserDirectory,
+    A: SlackApi,
+    F: FnOnce(&'a Account, Action) -> Fut,
+    Fut: Future<Output = Result<(), SyncError>>,
+{
+    return process_once(events, account, &ActionService::Slack, &webhook.event_id, now, || async move {
+        let previous = webhook.event.previous_message.as_ref().ok_or(SyncError::BadResponse("No deleted message".to_string()))?;
+        let sender = slack_sender_name(directory, api, account, previous.user.as_deref().unwrap_or_default(), now).await;
+        let mentions = resolve_slack_mentions(directory, api, account, &previous.text, now).await;
+        let action = generate_delete_action(webhook, previous, mapping, &account.settings.deletion_sync, &sender, &mentions);
+        return send(account, action).await;
+    }).await;
+}
 
-    // todo: queue?
-    // todo: Replace sender id with name
-    console_log!("generating action");
-    let action = generate_action(&webhook, link);
+// Either removes the comment, or strikes it through in place
+fn generate_delete_action(webhook: &EventWebhook, previous: &EventMessage, mapping: MessageMapping, deletion_sync: &DeletionSync, sender: &str, mentions: &Mentions) -> Action {
+    let mut action = generate_edit_action(webhook, previous, mapping, sender, mentions);
+    match deletion_sync {
+        DeletionSync::Delete => action.action = ActionType::DeleteMessage,
+        DeletionSync::Annotate => {
+            let text = strike_through(&slack_to_trello(&previous.text, mentions), "~~");
+            action.update.text = format!("{}\n{}\n_(deleted)_", sender, text);
+        }
+        DeletionSync::Ignore => action.action = ActionType::None,
+    }
+    return action;
+}
 
-    console_log!("Running add comment");
-    let  _res = add_comment_to_card(&env, action).await;
+#[allow(clippy::too_many_arguments)]
+async fn process_edit<'a, E, D, A, F, Fut>(events: &E, directory: &D, api: &A, account: &'a Account, webhook: &EventWebhook, mapping: MessageMapping, now: u64, edit: F) -> Result<bool, SyncError>
+where
+    E: ProcessedEvents,
+    D: UserDirectory,
+    A: SlackApi,
+    F: FnOnce(&'a Account, Action) -> Fut,
+    Fut: Future<Output = Result<(), SyncError>>,
+{
+    return process_once(events, account, &ActionService::Slack, &webhook.event_id, now, || async move {
+        let message = webhook.event.message.as_ref().ok_or(SyncError::BadResponse("No edited message".to_string()))?;
+        let user = message.user.as_deref().unwrap_or(&webhook.event.user);
+        let sender = slack_sender_name(directory, api, account, user, now).await;
+        let mentions = resolve_slack_mentions(directory, api, account, &message.text, now).await;
+        let action = generate_edit_action(webhook, message, mapping, &sender, &mentions);
+        return edit(account, action).await;
+    }).await;
+}
 
-    console_log!("Woot");
-    return Response::ok("Woot");
+// Rewrites the whole copy the same way generate_action first wrote it
+fn generate_edit_action(webhook: &EventWebhook, message: &EventMessage, mapping: MessageMapping, sender: &str, mentions: &Mentions) -> Action {
+    return Action {
+        action: ActionType::EditMessage,
+        source: ActionTargetSource {
+            id: Some(message.ts.to_owned()),
+            service: ActionService::Slack,
+            url: "SOME URL FOR SLACK".to_string(),
+            channel: Some(webhook.event.channel.to_owned()),
+        },
+        target: action_target(&mapping.target),
+        update: ActionUpdate {
+            text: comment_text(sender, &message.text, &message.files, mentions),
+            ..Default::default()
+        },
+    };
 }
 
+// Shared files are listed as links, whether or not they are also copied to the card
+fn comment_text(sender: &str, text: &str, files: &[EventFile], mentions: &Mentions) -> String {
+    let mut lines = vec![sender.to_string()];
+    // A file shared on its own has no text
+    if !text.is_empty() || files.is_empty() {
+        lines.push(slack_to_trello(text, mentions));
+    }
+    for attachment in files.iter().filter_map(EventFile::to_attachment) {
+        lines.push(format!("[{}]({})", attachment.name, attachment.permalink));
+    }
+    return lines.join("\n");
+}
 
-fn generate_action(webhook: &EventWebhook, link_result: Result<Link, Error>) -> Action {
+// `target` is the other end of the thread's link, messages in unlinked threads are ignored
+fn generate_action(webhook: &EventWebhook, target: Option<&LinkEnd>, sender: &str, mentions: &Mentions) -> Action {
     let mut action: ActionType;
-    let mut trello_card = None;
 
     let update = match &webhook.event.thread_ts{
         None => {
             action = ActionType::None;
             ActionUpdate{
                 text: "".to_string(),
+                ..Default::default()
             }
         }
         Some(_) => {
             action = ActionType::UpdateThread;
             ActionUpdate{
-                text: format!("{} posted in slack\n{}", webhook.event.user, webhook.event.text),
+                text: comment_text(sender, &webhook.event.text, &webhook.event.files, mentions),
+                attachments: webhook.event.files.iter().filter_map(EventFile::to_attachment).collect(),
+                ..Default::default()
             }
         }
     };
-    match link_result {
-        Ok(link) => {
-            trello_card = Some(link.trello_card);
-        }
-        Err(_) => {
+    let target = match target {
+        Some(target) => action_target(target),
+        None => {
             action = ActionType::None;
+            unlinked_target()
         }
-    }
-    let source = create_action_source(&webhook);
-    let target = create_action_target(&webhook, trello_card);
+    };
+    let source = create_action_source(webhook);
 
     // No action for webhooks from apps
     match &webhook.event.bot_id {
@@ -258,32 +986,170 @@ fn create_action_source(webhook: &EventWebhook) -> ActionTargetSource {
         id: webhook.event.thread_ts.to_owned(),
         service: ActionService::Slack,
         url: "SOME URL FOR SLACK".to_string(),
+        channel: Some(webhook.event.channel.to_owned()),
     };
 }
 
-fn create_action_target(_webhook: &EventWebhook, id: Option<String>) -> ActionTargetSource {
-
-    if let Some(value) = id.to_owned() {
-        return ActionTargetSource {
-            id: id.to_owned(),
-            service: ActionService::Trello,
-            url: format!("https://trello.com/c/{}", value),
-        };
-    }
+fn unlinked_target() -> ActionTargetSource {
     return ActionTargetSource {
         id: None,
         service: ActionService::Trello,
         url: "".to_string(),
+        channel: None,
     };
 }
 
 
+#[cfg(test)]
 mod tests {
     use std::fs;
-    use worker::Error;
-    use crate::action::ActionType;
-    use crate::database::Link;
-    use crate::slack::EventWebhook;
+    use crate::action::{ActionService, ActionType};
+    use crate::database::{LinkEnd, MessageMapping};
+    use std::cell::RefCell;
+    use crate::action::Action;
+    use crate::events::tests::{test_account, MemoryProcessedEvents};
+    use crate::format::Mentions;
+    use crate::users::tests::MemoryUserDirectory;
+    use crate::error::SyncError;
+    use crate::action::{ActionUpdate, ActionUpdateField, ActionUpdateLink};
+    use crate::settings::DeletionSync;
+    use crate::api::tests::{ApiCall, RecordingApi};
+    use crate::store::tests::MemoryStore;
+    use crate::slack::{handle, parse_response, process_deletion, process_edit, process_event, render_blocks, verify_signature, ChatPostMessageResponse, EventWebhook, SlackConnector, SlackApiError, UsersInfoResponse};
+
+    const SIGNING_SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const SIGNATURE: &str = "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503";
+
+    #[test]
+    fn verify_signature_valid() {
+        let body = fs::read("./data/slack/signed-request.txt").expect("Error reading file");
+
+        assert!(verify_signature(SIGNING_SECRET, "1531420618", SIGNATURE, &body, 1531420618 + 10));
+    }
+
+    #[test]
+    fn verify_signature_stale() {
+        let body = fs::read("./data/slack/signed-request.txt").expect("Error reading file");
+
+        assert!(!verify_signature(SIGNING_SECRET, "1531420618", SIGNATURE, &body, 1531420618 + 60 * 10));
+    }
+
+    #[test]
+    fn verify_signature_tampered() {
+        let mut body = fs::read("./data/slack/signed-request.txt").expect("Error reading file");
+        body.push(b'x');
+
+        assert!(!verify_signature(SIGNING_SECRET, "1531420618", SIGNATURE, &body, 1531420618));
+        assert!(!verify_signature("wrong-secret", "1531420618", SIGNATURE, &body[..body.len() - 1], 1531420618));
+        assert!(!verify_signature(SIGNING_SECRET, "1531420618", "", &body[..body.len() - 1], 1531420618));
+    }
+
+    #[test]
+    fn parse_response_ok() {
+        let data = fs::read_to_string("./data/slack/api-post-message.json").expect("Error reading file");
+
+        let response = parse_response::<ChatPostMessageResponse>(&data).unwrap().unwrap();
+        assert_eq!("1715194156.015989", response.ts);
+        assert_eq!("C123456", response.channel);
+    }
+
+    #[test]
+    fn parse_users_info() {
+        let data = fs::read_to_string("./data/slack/api-users-info.json").expect("Error reading file");
+
+        let response = parse_response::<UsersInfoResponse>(&data).unwrap().unwrap();
+        assert_eq!("USER_ID", response.user.id);
+        assert_eq!("alice.s", response.user.display_name());
+    }
+
+    #[test]
+    fn parse_response_error_codes() {
+        let data = fs::read_to_string("./data/slack/api-error-channel-not-found.json").expect("Error reading file");
+
+        let response = parse_response::<ChatPostMessageResponse>(&data).unwrap();
+        assert_eq!(Err(SlackApiError::ChannelNotFound), response);
+
+        let codes = [
+            ("not_in_channel", SlackApiError::NotInChannel),
+            ("invalid_auth", SlackApiError::InvalidAuth),
+            ("ratelimited", SlackApiError::Ratelimited),
+            ("msg_too_long", SlackApiError::MsgTooLong),
+            ("user_not_found", SlackApiError::UserNotFound),
+            ("is_archived", SlackApiError::Unknown("is_archived".to_string())),
+        ];
+        for (code, expected) in codes {
+            let body = format!("{{\"ok\":false,\"error\":\"{}\"}}", code);
+            assert_eq!(Err(expected), parse_response::<ChatPostMessageResponse>(&body).unwrap());
+        }
+    }
+
+    #[test]
+    fn parse_response_not_json() {
+        assert!(matches!(parse_response::<ChatPostMessageResponse>("<html>"), Err(SyncError::BadResponse(_))));
+    }
+
+    #[test]
+    fn slack_errors_map_to_sync_errors() {
+        assert!(matches!(SyncError::from(SlackApiError::InvalidAuth), SyncError::Auth(_)));
+        assert!(matches!(SyncError::from(SlackApiError::ChannelNotFound), SyncError::NotFound(_)));
+        assert!(matches!(SyncError::from(SlackApiError::Ratelimited), SyncError::RateLimited { .. }));
+        assert!(matches!(SyncError::from(SlackApiError::MsgTooLong), SyncError::BadResponse(_)));
+    }
+
+    #[test]
+    fn render_blocks_card_update() {
+        let update = ActionUpdate {
+            text: "This card has been moved from list Doing to list Done by Alice".to_string(),
+            title: Some("Fix the login page".to_string()),
+            actor: Some("Alice".to_string()),
+            body: Some("Card moved".to_string()),
+            fields: vec![
+                ActionUpdateField { label: "From".to_string(), value: "Doing".to_string() },
+                ActionUpdateField { label: "To".to_string(), value: "Done".to_string() },
+            ],
+            link: Some(ActionUpdateLink { label: "View card".to_string(), url: "https://trello.com/c/abc".to_string() }),
+            attachments: vec![],
+        };
+
+        let blocks = serde_json::to_value(render_blocks(&update)).unwrap();
+        let expected = serde_json::json!([
+            {"type": "header", "text": {"type": "plain_text", "text": "Fix the login page"}},
+            {"type": "section", "text": {"type": "mrkdwn", "text": "Card moved"}},
+            {"type": "section", "fields": [
+                {"type": "mrkdwn", "text": "*From*\nDoing"},
+                {"type": "mrkdwn", "text": "*To*\nDone"},
+            ]},
+            {"type": "context", "elements": [{"type": "mrkdwn", "text": "by Alice"}]},
+            {"type": "actions", "elements": [
+                {"type": "button", "text": {"type": "plain_text", "text": "View card"}, "url": "https://trello.com/c/abc"},
+            ]},
+        ]);
+        assert_eq!(expected, blocks);
+    }
+
+    #[test]
+    fn render_blocks_plain_text_update() {
+        let update = ActionUpdate {
+            text: "USER_ID posted in slack".to_string(),
+            ..Default::default()
+        };
+
+        assert!(render_blocks(&update).is_empty());
+    }
+
+    #[test]
+    fn render_blocks_truncates_header() {
+        let update = ActionUpdate {
+            title: Some("x".repeat(200)),
+            ..Default::default()
+        };
+
+        let blocks = render_blocks(&update);
+        match &blocks[0] {
+            crate::slack::Block::Header { text: crate::slack::TextObject::PlainText { text } } => assert_eq!(150, text.chars().count()),
+            other => panic!("Unexpected block {:?}", other),
+        }
+    }
 
     #[test]
     fn generate_action_new_thread() {
@@ -291,7 +1157,7 @@ mod tests {
 
         let webhook: EventWebhook = serde_json::from_str(&data).expect("Error parsing json");
 
-        let action = crate::slack::generate_action(&webhook, Err(Error::RustError("test".to_string())));
+        let action = crate::slack::generate_action(&webhook, None, "USER_ID (via Slack)", &Mentions::new());
         assert_eq!(None, action.source.id);
         assert!(matches!(action.action, ActionType::None));
     }
@@ -302,7 +1168,7 @@ mod tests {
 
         let webhook: EventWebhook = serde_json::from_str(&data).expect("Error parsing json");
 
-        let action = crate::slack::generate_action(&webhook, Err(Error::RustError("test".to_string())));
+        let action = crate::slack::generate_action(&webhook, None, "USER_ID (via Slack)", &Mentions::new());
         assert_eq!(Some("1715524581.123456".to_string()), action.source.id);
         assert!(matches!(action.action, ActionType::None));
     }
@@ -312,13 +1178,156 @@ mod tests {
         let data = fs::read_to_string("./data/slack/thread-replied.json").expect("Error reading file");
 
         let webhook: EventWebhook = serde_json::from_str(&data).expect("Error parsing json");
-        let link = Link{
-            slack_thread: "1715287188.123456".to_string(),
-            trello_card: "ABCDEFG".to_string(),
-        };
-        let action = crate::slack::generate_action(&webhook, Ok(link));
+        let card = LinkEnd::new(ActionService::Trello, None, "ABCDEFG");
+        let action = crate::slack::generate_action(&webhook, Some(&card), "@alice (via Slack)", &Mentions::new());
         assert_eq!(Some("1715287188.123456".to_string()), action.source.id);
         assert!(matches!(action.action, ActionType::UpdateThread));
+        assert_eq!("@alice (via Slack)\nSome reply from slack", action.update.text);
+    }
+
+    #[tokio::test]
+    async fn replayed_event_only_comments_once() {
+        let data = fs::read_to_string("./data/slack/thread-replied.json").expect("Error reading file");
+        let events = MemoryProcessedEvents::default();
+        let directory = MemoryUserDirectory::default().with_user("USER_ID", "Alice Smith", None);
+        let account = test_account("account");
+        let comments = RefCell::new(vec![]);
+
+        for _ in 0..2 {
+            let webhook: EventWebhook = serde_json::from_str(&data).expect("Error parsing json");
+            let card = LinkEnd::new(ActionService::Trello, None, "ABCDEFG");
+            let add_comment = |_, action: Action| {
+                let comments = &comments;
+                async move {
+                    comments.borrow_mut().push(action.update.text);
+                    Ok(())
+                }
+            };
+            process_event(&events, &directory, &SlackConnector::new(&RecordingApi::default()), &account, &webhook, Some(card), 1715523657, add_comment).await.unwrap();
+        }
+
+        assert_eq!(vec!["Alice Smith (via Slack)\nSome reply from slack".to_string()], *comments.borrow());
+    }
+
+    #[tokio::test]
+    async fn mentions_become_trello_members() {
+        let data = fs::read_to_string("./data/slack/thread-replied.json").expect("Error reading file");
+        let events = MemoryProcessedEvents::default();
+        let directory = MemoryUserDirectory::default()
+            .with_user("USER_ID", "Alice Smith", Some("alice"))
+            .with_user("U456", "Bob", None);
+        let account = test_account("account");
+        let comments = RefCell::new(vec![]);
+
+        let mut webhook: EventWebhook = serde_json::from_str(&data).expect("Error parsing json");
+        webhook.event.text = "<@U456> can you check this? cc <@U789>".to_string();
+        let card = LinkEnd::new(ActionService::Trello, None, "ABCDEFG");
+        let add_comment = |_, action: Action| {
+            let comments = &comments;
+            async move {
+                comments.borrow_mut().push(action.update.text);
+                Ok(())
+            }
+        };
+        process_event(&events, &directory, &SlackConnector::new(&RecordingApi::default()), &account, &webhook, Some(card), 1715523657, add_comment).await.unwrap();
+
+        assert_eq!(vec!["@alice (via Slack)\nBob can you check this? cc @U789".to_string()], *comments.borrow());
+    }
+
+    #[tokio::test]
+    async fn edit_rewrites_mapped_comment() {
+        let data = fs::read_to_string("./data/slack/message-changed.json").expect("Error reading file");
+        let events = MemoryProcessedEvents::default();
+        let directory = MemoryUserDirectory::default()
+            .with_user("USER_ID", "Alice Smith", Some("alice"))
+            .with_user("U456", "Bob", None);
+        let account = test_account("account");
+        let edits = RefCell::new(vec![]);
+
+        for _ in 0..2 {
+            let webhook: EventWebhook = serde_json::from_str(&data).expect("Error parsing json");
+            assert_eq!(Some("message_changed"), webhook.event.subtype.as_deref());
+            let mapping = MessageMapping {
+                source: LinkEnd::new(ActionService::Slack, Some("CHANNEL_ID"), "1715523657.123456"),
+                target: LinkEnd::new(ActionService::Trello, None, "COMMENT_ID"),
+            };
+            let edit = |_, action: Action| {
+                let edits = &edits;
+                async move {
+                    edits.borrow_mut().push((action.action, action.target.id, action.update.text));
+                    Ok(())
+                }
+            };
+            process_edit(&events, &directory, &RecordingApi::default(), &account, &webhook, mapping, 1715523700, edit).await.unwrap();
+        }
+
+        assert_eq!(vec![(
+            ActionType::EditMessage,
+            Some("COMMENT_ID".to_string()),
+            "@alice (via Slack)\nSome edited reply from Bob".to_string(),
+        )], *edits.borrow());
+    }
+
+    async fn delete_with(deletion_sync: DeletionSync) -> Vec<(ActionType, Option<String>, String)> {
+        let data = fs::read_to_string("./data/slack/message-deleted.json").expect("Error reading file");
+        let events = MemoryProcessedEvents::default();
+        let directory = MemoryUserDirectory::default().with_user("USER_ID", "Alice Smith", None);
+        let mut account = test_account("account");
+        account.settings.deletion_sync = deletion_sync;
+        let deletions = RefCell::new(vec![]);
+
+        let webhook: EventWebhook = serde_json::from_str(&data).expect("Error parsing json");
+        let mapping = MessageMapping {
+            source: LinkEnd::new(ActionService::Slack, Some("CHANNEL_ID"), "1715523657.123456"),
+            target: LinkEnd::new(ActionService::Trello, None, "COMMENT_ID"),
+        };
+        let send = |_, action: Action| {
+            let deletions = &deletions;
+            async move {
+                deletions.borrow_mut().push((action.action, action.target.id, action.update.text));
+                Ok(())
+            }
+        };
+        process_deletion(&events, &directory, &RecordingApi::default(), &account, &webhook, mapping, 1715523800, send).await.unwrap();
+        return deletions.into_inner();
+    }
+
+    #[tokio::test]
+    async fn deletion_follows_account_setting() {
+        let deleted = delete_with(DeletionSync::Delete).await;
+        assert_eq!(ActionType::DeleteMessage, deleted[0].0);
+        assert_eq!(Some("COMMENT_ID".to_string()), deleted[0].1);
+
+        let annotated = delete_with(DeletionSync::Annotate).await;
+        assert_eq!(vec![(
+            ActionType::EditMessage,
+            Some("COMMENT_ID".to_string()),
+            "Alice Smith (via Slack)\n~~Some reply from slack~~\n_(deleted)_".to_string(),
+        )], annotated);
+
+        let ignored = delete_with(DeletionSync::Ignore).await;
+        assert_eq!(ActionType::None, ignored[0].0);
+    }
+
+    #[test]
+    fn generate_action_file_shared() {
+        let data = fs::read_to_string("./data/slack/file-shared.json").expect("Error reading file");
+
+        let webhook: EventWebhook = serde_json::from_str(&data).expect("Error parsing json");
+        let card = LinkEnd::new(ActionService::Trello, None, "ABCDEFG");
+        let action = crate::slack::generate_action(&webhook, Some(&card), "Alice Smith (via Slack)", &Mentions::new());
+        assert!(matches!(action.action, ActionType::UpdateThread));
+        assert_eq!("Alice Smith (via Slack)\nHere are the designs\n\
+            [mockup.png](https://example.slack.com/files/USER_ID/F0001/mockup.png)\n\
+            [recording.mp4](https://example.slack.com/files/USER_ID/F0002/recording.mp4)", action.update.text);
+
+        let attachments = action.update.attachments;
+        assert_eq!(2, attachments.len());
+        assert_eq!("https://files.slack.com/files-pri/TEAM_ID-F0001/download/mockup.png", attachments[0].url);
+        assert_eq!(Some("image/png".to_string()), attachments[0].mime_type);
+        assert!(attachments[0].can_copy());
+        // Too large to copy, so only linked
+        assert!(!attachments[1].can_copy());
     }
 
     #[test]
@@ -327,8 +1336,150 @@ mod tests {
 
         let webhook: EventWebhook = serde_json::from_str(&data).expect("Error parsing json");
 
-        let action = crate::slack::generate_action(&webhook, Err(Error::RustError("test".to_string())));
+        let action = crate::slack::generate_action(&webhook, None, "USER_ID (via Slack)", &Mentions::new());
         assert_eq!(Some("1715287188.123456".to_string()), action.source.id);
         assert!(matches!(action.action, ActionType::None));
     }
-}
\ No newline at end of file
+
+    fn read_webhook(name: &str) -> EventWebhook {
+        let data = fs::read_to_string(format!("./data/slack/{}.json", name)).expect("Error reading file");
+        return serde_json::from_str(&data).expect("Error parsing json");
+    }
+
+    fn linked_store() -> MemoryStore {
+        return MemoryStore::default().with_link("abc64ds5ad45s6161d", "CHANNEL_ID", "1715287188.123456");
+    }
+
+    #[tokio::test]
+    async fn handle_thread_reply_adds_comment() {
+        let store = linked_store();
+        let api = RecordingApi::default();
+        let directory = MemoryUserDirectory::default().with_user("USER_ID", "Jane", None);
+        let account = test_account("account");
+
+        let result = handle(&store, &directory, &api, &read_webhook("thread-replied"), &account, 1715523657).await;
+
+        assert_eq!("Woot", result.unwrap());
+        assert_eq!(vec![ApiCall::AddComment {
+            card: "abc64ds5ad45s6161d".to_string(),
+            text: "Jane (via Slack)\nSome reply from slack".to_string(),
+        }], api.calls());
+        let mappings = store.message_mappings.borrow();
+        assert_eq!(1, mappings.len());
+        assert_eq!(LinkEnd::new(ActionService::Slack, Some("CHANNEL_ID"), "1715523657.123456"), mappings[0].1.source);
+        assert_eq!(ActionService::Trello, mappings[0].1.target.service);
+    }
+
+    #[tokio::test]
+    async fn handle_unknown_thread_and_bots_are_skipped() {
+        let api = RecordingApi::default();
+        let account = test_account("account");
+
+        let result = handle(&linked_store(), &MemoryUserDirectory::default(), &api, &read_webhook("unknown-thread-reply"), &account, 1715523657).await;
+        assert_eq!("Woot", result.unwrap());
+
+        let result = handle(&linked_store(), &MemoryUserDirectory::default(), &api, &read_webhook("thread-replied-bot"), &account, 1715523657).await;
+        assert_eq!("Skipping bot", result.unwrap());
+
+        assert!(api.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_failed_comment_can_be_retried() {
+        let store = linked_store();
+        let api = RecordingApi::default().fail("add_comment", SyncError::NotFound("/1/cards/abc64ds5ad45s6161d/actions/comments".to_string()));
+        let account = test_account("account");
+        let webhook = read_webhook("thread-replied");
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1715523657).await;
+        assert!(matches!(result, Err(SyncError::NotFound(_))));
+        assert!(store.message_mappings.borrow().is_empty());
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1715523658).await;
+        assert_eq!("Woot", result.unwrap());
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1715523659).await;
+        assert_eq!("Already processed", result.unwrap());
+        assert_eq!(2, api.calls().len());
+    }
+
+    #[tokio::test]
+    async fn handle_message_edit_and_delete() {
+        let store = MemoryStore::default().with_message_mapping(LinkEnd::new(ActionService::Slack, Some("CHANNEL_ID"), "1715523657.123456"), LinkEnd::new(ActionService::Trello, None, "commentid"));
+        let api = RecordingApi::default();
+        let directory = MemoryUserDirectory::default().with_user("USER_ID", "Jane", None).with_user("U456", "Sam", None);
+        let mut account = test_account("account");
+        account.settings.deletion_sync = DeletionSync::Delete;
+
+        let result = handle(&store, &directory, &api, &read_webhook("message-changed"), &account, 1715523700).await;
+        assert_eq!("Updated", result.unwrap());
+        let result = handle(&store, &directory, &api, &read_webhook("message-deleted"), &account, 1715523800).await;
+        assert_eq!("Deleted", result.unwrap());
+
+        assert_eq!(vec![
+            ApiCall::UpdateComment { comment: "commentid".to_string(), text: "Jane (via Slack)\nSome edited reply from Sam".to_string() },
+            ApiCall::DeleteComment { comment: "commentid".to_string() },
+        ], api.calls());
+        assert!(store.message_mappings.borrow().is_empty());
+
+        // With the mapping gone a second deletion has nothing to act on
+        let result = handle(&store, &directory, &api, &read_webhook("message-deleted"), &account, 1715523801).await;
+        assert_eq!("Message not synced", result.unwrap());
+
+        // A message copied from someone's comment is never edited back into it
+        let store = MemoryStore::default().with_message_mapping(LinkEnd::new(ActionService::Trello, None, "commentid"), LinkEnd::new(ActionService::Slack, Some("CHANNEL_ID"), "1715523657.123456"));
+        let result = handle(&store, &directory, &api, &read_webhook("message-changed"), &account, 1715523900).await;
+        assert_eq!("Message not from Slack", result.unwrap());
+        let result = handle(&store, &directory, &api, &read_webhook("message-deleted"), &account, 1715523901).await;
+        assert_eq!("Message not from Slack", result.unwrap());
+        assert_eq!(2, api.calls().len());
+
+        // Edits follow the copy through the connector of whichever service it was posted to
+        let api = RecordingApi::default();
+        let copy = LinkEnd::new(ActionService::Slack, Some("C654321"), "1000000000.000001");
+        let store = MemoryStore::default().with_message_mapping(LinkEnd::new(ActionService::Slack, Some("CHANNEL_ID"), "1715523657.123456"), copy);
+        let result = handle(&store, &directory, &api, &read_webhook("message-changed"), &account, 1715524000).await;
+        assert_eq!("Updated", result.unwrap());
+        let result = handle(&store, &directory, &api, &read_webhook("message-deleted"), &account, 1715524001).await;
+        assert_eq!("Deleted", result.unwrap());
+        let calls = api.calls();
+        assert!(matches!(&calls[0], ApiCall::UpdateMessage { channel, ts, .. } if channel == "C654321" && ts == "1000000000.000001"));
+        assert_eq!(ApiCall::DeleteMessage { channel: "C654321".to_string(), ts: "1000000000.000001".to_string() }, calls[1]);
+        assert!(store.message_mappings.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_lookup_errors_are_retried() {
+        // A database error isn't taken to mean the thread or message was never synced, Slack sends it again
+        let mut store = linked_store().with_message_mapping(LinkEnd::new(ActionService::Slack, Some("CHANNEL_ID"), "1715523657.123456"), LinkEnd::new(ActionService::Trello, None, "commentid"));
+        store.failing_lookups = true;
+        let api = RecordingApi::default();
+        let directory = MemoryUserDirectory::default().with_user("USER_ID", "Jane", None);
+        let mut account = test_account("account");
+        account.settings.deletion_sync = DeletionSync::Delete;
+
+        for name in ["thread-replied", "message-changed", "message-deleted"] {
+            let result = handle(&store, &directory, &api, &read_webhook(name), &account, 1715523700).await;
+            assert!(matches!(result, Err(SyncError::Worker(_))), "{}", name);
+        }
+        assert!(api.calls().is_empty());
+        assert_eq!(1, store.message_mappings.borrow().len());
+    }
+
+    #[tokio::test]
+    async fn handle_shared_files_are_mirrored() {
+        let api = RecordingApi::default();
+        let mut account = test_account("account");
+        account.settings.mirror_attachments = true;
+
+        let result = handle(&linked_store(), &MemoryUserDirectory::default(), &api, &read_webhook("file-shared"), &account, 1715523657).await;
+
+        assert_eq!("Woot", result.unwrap());
+        let calls = api.calls();
+        assert_eq!(4, calls.len());
+        assert!(matches!(&calls[0], ApiCall::AddComment { card, .. } if card == "abc64ds5ad45s6161d"));
+        assert!(matches!(&calls[1], ApiCall::DownloadFile { .. }));
+        assert!(matches!(&calls[2], ApiCall::UploadAttachment { card, .. } if card == "abc64ds5ad45s6161d"));
+        // The second file is over the size limit, so it is linked rather than copied
+        assert!(matches!(&calls[3], ApiCall::AttachLink { card, .. } if card == "abc64ds5ad45s6161d"));
+    }
+}
@@ -0,0 +1,313 @@
+use std::sync::Mutex;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OptionalExtension, Params, Row};
+use serde::de::DeserializeOwned;
+use worker::Error;
+use crate::account::Account;
+use crate::action::ActionService;
+use crate::api::{SlackApi, TrelloApi};
+use crate::database::{CachedSlackUser, CachedTrelloMember, ChannelMapping, Link, LinkEnd, MessageMapping, ProcessedEvent, UserMapping, CACHE_SLACK_USER_QUERY, CACHE_TRELLO_MEMBER_QUERY, CLAIM_LINK_QUERY, CREATE_LINK_QUERY, CREATE_MESSAGE_MAPPING_QUERY, DELETE_MESSAGE_MAPPING_QUERY, EXPIRE_PROCESSED_EVENTS_QUERY, FINISH_EVENT_QUERY, FORGET_PROCESSED_EVENT_QUERY, GET_ACCOUNT_CREDENTIALS_QUERY, GET_ACCOUNT_QUERY, GET_ACCOUNT_SETTINGS_QUERY, GET_CACHED_SLACK_USER_QUERY, GET_CACHED_TRELLO_MEMBER_QUERY, GET_CHANNEL_MAPPING_QUERY, GET_EVENT_QUERY, GET_LINK_QUERY, GET_MESSAGE_MAPPING_QUERY, GET_USER_MAPPING_BY_SLACK_USER_QUERY, GET_USER_MAPPING_BY_TRELLO_USERNAME_QUERY, LINK_CLAIM_TIMEOUT_SECONDS, RELEASE_LINK_QUERY, REPLACE_LINK_QUERY, START_EVENT_QUERY, SCHEMA_VERSION_QUERY};
+use crate::error::SyncError;
+use crate::events::{EventState, ProcessedEvents, EVENT_LEASE_SECONDS, PROCESSED_EVENT_TTL_SECONDS};
+use crate::migrations::pending_migrations;
+use crate::store::Store;
+use crate::trello::TrelloMember;
+use crate::users::{cached_member, fetch_slack_display_name, fetch_trello_member, UserDirectory, SLACK_USER_CACHE_TTL_SECONDS, TRELLO_MEMBER_CACHE_TTL_SECONDS};
+
+/// A Store on a SQLite database, for running outside of Workers. It runs the same statements as the
+/// D1 functions in database.rs, and rows are read through serde the same way D1 results are.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens the database file, creating it if needed, and applies any migrations it is missing
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let connection = Connection::open(path).map_err(sql_error)?;
+        return SqliteStore::new(connection);
+    }
+
+    pub fn new(mut connection: Connection) -> Result<Self, Error> {
+        for migration in pending_migrations(schema_version(&connection)?) {
+            // A migration that fails part way is rolled back, so it can be fixed and run again
+            let transaction = connection.transaction().map_err(sql_error)?;
+            transaction.execute_batch(migration.sql).map_err(sql_error)?;
+            transaction.commit().map_err(sql_error)?;
+        }
+        return Ok(SqliteStore { connection: Mutex::new(connection) });
+    }
+
+    /// Runs statements that aren't part of the Store, such as seeding accounts and channel mappings
+    #[cfg(test)]
+    pub fn execute_batch(&self, sql: &str) -> Result<(), Error> {
+        return self.connection().execute_batch(sql).map_err(sql_error);
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // A panic while holding the lock can't leave a statement half run, so the connection is still usable
+        return self.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+
+    fn first<T: DeserializeOwned, P: Params>(&self, query: &str, params: P) -> Result<Option<T>, Error> {
+        let row = self.connection().query_row(query, params, row_to_json).optional().map_err(sql_error)?;
+        return match row {
+            Some(row) => Ok(Some(serde_json::from_value(row).map_err(|err| Error::RustError(err.to_string()))?)),
+            None => Ok(None),
+        };
+    }
+
+    // Errors when there is no row, as get_from_db does for D1
+    fn get<T: DeserializeOwned, P: Params>(&self, query: &str, params: P) -> Result<T, Error> {
+        return self.first(query, params)?.ok_or(Error::RustError("No results found".to_string()));
+    }
+
+    fn run<P: Params>(&self, query: &str, params: P) -> Result<(), Error> {
+        self.connection().execute(query, params).map_err(sql_error)?;
+        return Ok(());
+    }
+}
+
+const SCHEMA_MIGRATIONS_TABLE_QUERY: &str = "SELECT name FROM sqlite_master WHERE type='table' AND name='schema_migrations'";
+
+// A new database has no schema_migrations table to read the version from, any other error is returned rather
+// than taken to mean the database is empty
+fn schema_version(connection: &Connection) -> Result<Option<u32>, Error> {
+    let has_migrations = connection.query_row(SCHEMA_MIGRATIONS_TABLE_QUERY, [], |_| Ok(()))
+        .optional()
+        .map_err(sql_error)?;
+    if has_migrations.is_none() {
+        return Ok(None);
+    }
+    return connection.query_row(SCHEMA_VERSION_QUERY, [], |row| row.get::<_, Option<u32>>(0)).map_err(sql_error);
+}
+
+fn sql_error(err: rusqlite::Error) -> Error {
+    return Error::RustError(err.to_string());
+}
+
+// Columns by name, so the row deserializes like a D1 result
+fn row_to_json(row: &Row) -> rusqlite::Result<serde_json::Value> {
+    let mut object = serde_json::Map::new();
+    for (index, name) in row.as_ref().column_names().iter().enumerate() {
+        let value = match row.get_ref(index)? {
+            ValueRef::Null => serde_json::Value::Null,
+            ValueRef::Integer(value) => serde_json::Value::from(value),
+            ValueRef::Real(value) => serde_json::Value::from(value),
+            ValueRef::Text(value) | ValueRef::Blob(value) => serde_json::Value::from(String::from_utf8_lossy(value).to_string()),
+        };
+        object.insert(name.to_string(), value);
+    }
+    return Ok(serde_json::Value::Object(object));
+}
+
+impl Store for SqliteStore {
+    async fn get_account(&self, id: &str) -> Result<Option<Account>, Error> {
+        let Some(mut account) = self.first::<Account, _>(GET_ACCOUNT_QUERY, params![id])? else {
+            return Ok(None);
+        };
+        account.credentials = self.first(GET_ACCOUNT_CREDENTIALS_QUERY, params![id])?.unwrap_or_default();
+        account.settings = self.first(GET_ACCOUNT_SETTINGS_QUERY, params![id])?.unwrap_or_default();
+        return Ok(Some(account));
+    }
+
+    async fn get_link(&self, account: &Account, end: &LinkEnd) -> Result<Option<Link>, Error> {
+        return self.first(GET_LINK_QUERY, params![account.id, end.service.as_str(), end.container, end.id]);
+    }
+
+    async fn get_channel_mapping(&self, account: &Account, trello_board: &str, trello_list: Option<&str>) -> Result<ChannelMapping, Error> {
+        return self.get(GET_CHANNEL_MAPPING_QUERY, params![account.id, trello_board, trello_list.unwrap_or_default()]);
+    }
+
+    async fn claim_link(&self, account: &Account, source: &LinkEnd, target_service: &ActionService, now: u64) -> Result<bool, Error> {
+        let stale = now.saturating_sub(LINK_CLAIM_TIMEOUT_SECONDS);
+        let params = params![account.id, source.service.as_str(), source.container, source.id, target_service.as_str(), now, stale];
+        let claimed: Option<serde_json::Value> = self.first(CLAIM_LINK_QUERY, params)?;
+        return Ok(claimed.is_some());
+    }
+
+    async fn create_link(&self, account: &Account, source: &LinkEnd, target: &LinkEnd) -> Result<(), Error> {
+        let params = params![account.id, source.service.as_str(), source.container, source.id, target.service.as_str(), target.container, target.id];
+        return self.run(CREATE_LINK_QUERY, params);
+    }
+
+    async fn release_link(&self, account: &Account, source: &LinkEnd) -> Result<(), Error> {
+        return self.run(RELEASE_LINK_QUERY, params![account.id, source.service.as_str(), source.container, source.id]);
+    }
+
+    async fn replace_link(&self, account: &Account, link: &Link, target: &LinkEnd) -> Result<(), Error> {
+        let source = &link.source;
+        let params = params![account.id, source.service.as_str(), source.container, source.id, link.target.id, target.service.as_str(), target.container, target.id];
+        return self.run(REPLACE_LINK_QUERY, params);
+    }
+
+    async fn create_message_mapping(&self, account: &Account, source: &LinkEnd, target: &LinkEnd) -> Result<(), Error> {
+        let params = params![account.id, source.service.as_str(), source.container, source.id, target.service.as_str(), target.container, target.id];
+        return self.run(CREATE_MESSAGE_MAPPING_QUERY, params);
+    }
+
+    async fn get_message_mapping(&self, account: &Account, end: &LinkEnd) -> Result<Option<MessageMapping>, Error> {
+        return self.first(GET_MESSAGE_MAPPING_QUERY, params![account.id, end.service.as_str(), end.container, end.id]);
+    }
+
+    async fn delete_message_mapping(&self, account: &Account, end: &LinkEnd) -> Result<(), Error> {
+        return self.run(DELETE_MESSAGE_MAPPING_QUERY, params![account.id, end.service.as_str(), end.container, end.id]);
+    }
+}
+
+impl ProcessedEvents for SqliteStore {
+    async fn start(&self, account: &Account, service: &ActionService, event_id: &str, now: u64) -> Result<EventState, Error> {
+        self.run(EXPIRE_PROCESSED_EVENTS_QUERY, params![now.saturating_sub(PROCESSED_EVENT_TTL_SECONDS)])?;
+        let stale = now.saturating_sub(EVENT_LEASE_SECONDS);
+        let started: Option<serde_json::Value> = self.first(START_EVENT_QUERY, params![account.id, service.as_str(), event_id, now, stale])?;
+        if started.is_some() {
+            return Ok(EventState::Started);
+        }
+        return Ok(ProcessedEvent::state(self.first(GET_EVENT_QUERY, params![account.id, service.as_str(), event_id])?));
+    }
+
+    async fn finish(&self, account: &Account, service: &ActionService, event_id: &str, now: u64) -> Result<(), Error> {
+        return self.run(FINISH_EVENT_QUERY, params![account.id, service.as_str(), event_id, now]);
+    }
+
+    async fn forget(&self, account: &Account, service: &ActionService, event_id: &str) -> Result<(), Error> {
+        return self.run(FORGET_PROCESSED_EVENT_QUERY, params![account.id, service.as_str(), event_id]);
+    }
+}
+
+// The same lookups and cache as users::D1UserDirectory
+impl UserDirectory for SqliteStore {
+    async fn slack_display_name<A: SlackApi>(&self, api: &A, account: &Account, slack_user: &str, now: u64) -> Result<Option<String>, SyncError> {
+        let fetched_after = now.saturating_sub(SLACK_USER_CACHE_TTL_SECONDS);
+        if let Some(cached) = self.first::<CachedSlackUser, _>(GET_CACHED_SLACK_USER_QUERY, params![account.id, slack_user, fetched_after])? {
+            return Ok(Some(cached.display_name));
+        }
+
+        let name = fetch_slack_display_name(api, account, slack_user).await?;
+        if let Some(name) = &name {
+            self.run(CACHE_SLACK_USER_QUERY, params![account.id, slack_user, name, now])?;
+        }
+        return Ok(name);
+    }
+
+    async fn trello_member<A: TrelloApi>(&self, api: &A, account: &Account, trello_username: &str, now: u64) -> Result<Option<TrelloMember>, SyncError> {
+        let fetched_after = now.saturating_sub(TRELLO_MEMBER_CACHE_TTL_SECONDS);
+        if let Some(cached) = self.first::<CachedTrelloMember, _>(GET_CACHED_TRELLO_MEMBER_QUERY, params![account.id, trello_username, fetched_after])? {
+            return Ok(Some(cached_member(trello_username, cached)));
+        }
+
+        let member = fetch_trello_member(api, account, trello_username).await?;
+        if let Some(member) = &member {
+            self.run(CACHE_TRELLO_MEMBER_QUERY, params![account.id, trello_username, member.id, member.full_name, now])?;
+        }
+        return Ok(member);
+    }
+
+    async fn trello_username(&self, account: &Account, slack_user: &str) -> Result<Option<String>, Error> {
+        let mapping = self.first::<UserMapping, _>(GET_USER_MAPPING_BY_SLACK_USER_QUERY, params![account.id, slack_user])?;
+        return Ok(mapping.map(|mapping| mapping.trello_username));
+    }
+
+    async fn slack_user(&self, account: &Account, trello_username: &str) -> Result<Option<String>, Error> {
+        let mapping = self.first::<UserMapping, _>(GET_USER_MAPPING_BY_TRELLO_USERNAME_QUERY, params![account.id, trello_username])?;
+        return Ok(mapping.map(|mapping| mapping.slack_user));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::tests::{ApiCall, RecordingApi};
+    use rusqlite::Connection;
+    use crate::migrations::tests::{open_database, DatabaseFile};
+    use crate::migrations::MIGRATIONS;
+    use crate::slack::{SlackUser, SlackUserProfile};
+    use crate::sqlite::SqliteStore;
+    use crate::store::tests::check_store;
+    use crate::store::Store;
+    use crate::trello::TrelloMember;
+    use crate::users::UserDirectory;
+
+    // The same accounts and channel mappings as store::tests::seeded_memory_store
+    const SEED: &str = "
+        INSERT INTO accounts VALUES ('account', 'Test Account'), ('other', 'Other Account');
+        INSERT INTO account_credentials (account_id, slack_auth_token) VALUES ('account', 'xoxb-token');
+        INSERT INTO account_settings (account_id, deletion_sync, mirror_attachments) VALUES ('account', 'delete', 1);
+        INSERT INTO channel_mappings (account_id, trello_board, trello_list, target_container) VALUES ('account', 'board', NULL, 'C1'), ('account', 'board', 'list', 'C2');
+    ";
+
+    #[tokio::test]
+    async fn sqlite_store_conformance() {
+        let store = SqliteStore::new(open_database()).unwrap();
+        store.execute_batch(SEED).unwrap();
+
+        check_store(&store).await;
+    }
+
+    #[tokio::test]
+    async fn slack_names_are_fetched_once() {
+        let store = SqliteStore::new(open_database()).unwrap();
+        store.execute_batch(SEED).unwrap();
+        let account = store.get_account("account").await.unwrap().unwrap();
+        let api = RecordingApi::default();
+        api.users.borrow_mut().push(SlackUser {
+            id: "U123".to_string(),
+            name: "alice".to_string(),
+            profile: SlackUserProfile { display_name: "Alice".to_string(), real_name: "Alice Smith".to_string() },
+        });
+
+        assert_eq!(Some("Alice".to_string()), store.slack_display_name(&api, &account, "U123", 1000).await.unwrap());
+        assert_eq!(Some("Alice".to_string()), store.slack_display_name(&api, &account, "U123", 2000).await.unwrap());
+        assert_eq!(None, store.slack_display_name(&api, &account, "U999", 2000).await.unwrap());
+        assert_eq!(vec![ApiCall::GetUser { user: "U123".to_string() }, ApiCall::GetUser { user: "U999".to_string() }], api.calls());
+    }
+
+    #[tokio::test]
+    async fn trello_members_are_fetched_once() {
+        let store = SqliteStore::new(open_database()).unwrap();
+        store.execute_batch(SEED).unwrap();
+        let account = store.get_account("account").await.unwrap().unwrap();
+        let api = RecordingApi::default();
+        api.members.borrow_mut().push(TrelloMember { id: "5f0c".to_string(), full_name: "Carol".to_string(), username: "carol".to_string() });
+
+        let member = store.trello_member(&api, &account, "carol", 1000).await.unwrap().unwrap();
+        assert_eq!(("5f0c", "Carol"), (member.id.as_str(), member.full_name.as_str()));
+        assert_eq!("Carol", store.trello_member(&api, &account, "carol", 2000).await.unwrap().unwrap().full_name);
+        assert!(store.trello_member(&api, &account, "dave", 2000).await.unwrap().is_none());
+        assert_eq!(vec![ApiCall::GetMember { username: "carol".to_string() }, ApiCall::GetMember { username: "dave".to_string() }], api.calls());
+    }
+
+    #[test]
+    fn reopening_skips_applied_migrations() {
+        let file = DatabaseFile::new("reopen");
+        SqliteStore::open(file.path()).unwrap().execute_batch("INSERT INTO accounts VALUES ('account', 'Test Account')").unwrap();
+
+        // Applying the initial migration again would fail on its schema_migrations insert
+        let store = SqliteStore::open(file.path()).unwrap();
+        store.execute_batch("INSERT INTO accounts VALUES ('other', 'Other Account')").unwrap();
+    }
+
+    #[test]
+    fn unreadable_version_is_an_error() {
+        let connection = open_database();
+        connection.execute_batch("CREATE TABLE schema_migrations (name nvarchar(100))").unwrap();
+
+        assert!(SqliteStore::new(connection).is_err());
+    }
+
+    #[test]
+    fn failed_migration_is_rolled_back() {
+        let file = DatabaseFile::new("rollback");
+        let connection = Connection::open(file.path()).unwrap();
+        for migration in &MIGRATIONS[..9] {
+            connection.execute_batch(migration.sql).unwrap();
+        }
+        // Fails 0009 after it has replaced the links table
+        connection.execute_batch("CREATE INDEX idx_link_source ON accounts (name)").unwrap();
+
+        assert!(SqliteStore::new(connection).is_err());
+
+        let connection = Connection::open(file.path()).unwrap();
+        let version: u32 = connection.query_row("SELECT MAX(version) FROM schema_migrations", [], |row| row.get(0)).unwrap();
+        assert_eq!(8, version);
+        connection.execute("SELECT trello_card FROM links", []).unwrap();
+    }
+}
@@ -0,0 +1,488 @@
+use worker::{Env, Error};
+use crate::account::Account;
+use crate::action::ActionService;
+use crate::credentials::get_credentials;
+use crate::database::{self, ChannelMapping, Link, LinkEnd, MessageMapping};
+use crate::events::{EventState, ProcessedEvents, EVENT_LEASE_SECONDS, PROCESSED_EVENT_TTL_SECONDS};
+use crate::settings::get_settings;
+
+/// Everything the sync engine keeps between webhooks: accounts, links between items in two services,
+/// message mappings and, through ProcessedEvents, the ids of events already handled
+pub trait Store: ProcessedEvents {
+    /// The account with its credentials and settings, which fall back to the defaults when the account has none.
+    /// None if there is no such account.
+    async fn get_account(&self, id: &str) -> Result<Option<Account>, Error>;
+
+    /// The created link with `end` on either side, None if there is none
+    async fn get_link(&self, account: &Account, end: &LinkEnd) -> Result<Option<Link>, Error>;
+
+    /// Where to start the thread for a card on a list, falling back to the mapping for its board
+    async fn get_channel_mapping(&self, account: &Account, trello_board: &str, trello_list: Option<&str>) -> Result<ChannelMapping, Error>;
+
+    /// Reserves the link from `source` to a thread in `target_service`, returning false if another delivery already has it
+    async fn claim_link(&self, account: &Account, source: &LinkEnd, target_service: &ActionService, now: u64) -> Result<bool, Error>;
+
+    /// Fills in a claimed link with the thread that was created
+    async fn create_link(&self, account: &Account, source: &LinkEnd, target: &LinkEnd) -> Result<(), Error>;
+
+    /// Gives up a claim that never got a thread
+    async fn release_link(&self, account: &Account, source: &LinkEnd) -> Result<(), Error>;
+
+    /// Moves a link to a new thread, once the new thread exists
+    async fn replace_link(&self, account: &Account, link: &Link, target: &LinkEnd) -> Result<(), Error>;
+
+    /// Pairs a message as it was written with the copy posted for it, unless the message already has one
+    async fn create_message_mapping(&self, account: &Account, source: &LinkEnd, target: &LinkEnd) -> Result<(), Error>;
+
+    /// The mapping with `end` as either the message or its copy, None if there is none
+    async fn get_message_mapping(&self, account: &Account, end: &LinkEnd) -> Result<Option<MessageMapping>, Error>;
+
+    /// Forgets a message once its copy has been deleted, as nothing more can happen to either
+    async fn delete_message_mapping(&self, account: &Account, end: &LinkEnd) -> Result<(), Error>;
+}
+
+pub struct D1Store<'a> {
+    env: &'a Env,
+}
+
+impl<'a> D1Store<'a> {
+    pub fn new(env: &'a Env) -> Self {
+        return D1Store { env };
+    }
+}
+
+impl Store for D1Store<'_> {
+    async fn get_account(&self, id: &str) -> Result<Option<Account>, Error> {
+        let Some(mut account) = database::get_account(self.env, id).await? else {
+            return Ok(None);
+        };
+        account.credentials = get_credentials(self.env, &account.id).await?;
+        account.settings = get_settings(self.env, &account.id).await?;
+        return Ok(Some(account));
+    }
+
+    async fn get_link(&self, account: &Account, end: &LinkEnd) -> Result<Option<Link>, Error> {
+        return database::get_link(self.env, account, end).await;
+    }
+
+    async fn get_channel_mapping(&self, account: &Account, trello_board: &str, trello_list: Option<&str>) -> Result<ChannelMapping, Error> {
+        return database::get_channel_mapping(self.env, account, trello_board, trello_list).await;
+    }
+
+    async fn claim_link(&self, account: &Account, source: &LinkEnd, target_service: &ActionService, now: u64) -> Result<bool, Error> {
+        return database::claim_link(self.env, account, source, target_service, now).await;
+    }
+
+    async fn create_link(&self, account: &Account, source: &LinkEnd, target: &LinkEnd) -> Result<(), Error> {
+        return database::create_link(self.env, account, source, target).await;
+    }
+
+    async fn release_link(&self, account: &Account, source: &LinkEnd) -> Result<(), Error> {
+        return database::release_link(self.env, account, source).await;
+    }
+
+    async fn replace_link(&self, account: &Account, link: &Link, target: &LinkEnd) -> Result<(), Error> {
+        return database::replace_link(self.env, account, link, target).await;
+    }
+
+    async fn create_message_mapping(&self, account: &Account, source: &LinkEnd, target: &LinkEnd) -> Result<(), Error> {
+        return database::create_message_mapping(self.env, account, source, target).await;
+    }
+
+    async fn get_message_mapping(&self, account: &Account, end: &LinkEnd) -> Result<Option<MessageMapping>, Error> {
+        return database::get_message_mapping(self.env, account, end).await;
+    }
+
+    async fn delete_message_mapping(&self, account: &Account, end: &LinkEnd) -> Result<(), Error> {
+        return database::delete_message_mapping(self.env, account, end).await;
+    }
+}
+
+impl ProcessedEvents for D1Store<'_> {
+    async fn start(&self, account: &Account, service: &ActionService, event_id: &str, now: u64) -> Result<EventState, Error> {
+        return database::start_event(self.env, account, service.as_str(), event_id, now, PROCESSED_EVENT_TTL_SECONDS, EVENT_LEASE_SECONDS).await;
+    }
+
+    async fn finish(&self, account: &Account, service: &ActionService, event_id: &str, now: u64) -> Result<(), Error> {
+        return database::finish_event(self.env, account, service.as_str(), event_id, now).await;
+    }
+
+    async fn forget(&self, account: &Account, service: &ActionService, event_id: &str) -> Result<(), Error> {
+        return database::forget_processed_event(self.env, account, service.as_str(), event_id).await;
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::cell::RefCell;
+    use worker::Error;
+    use crate::account::Account;
+    use crate::action::ActionService;
+    use crate::credentials::Credentials;
+    use crate::database::{ChannelMapping, Link, LinkEnd, MessageMapping, LINK_CLAIM_TIMEOUT_SECONDS};
+    use crate::events::tests::MemoryProcessedEvents;
+    use crate::events::{EventState, ProcessedEvents, EVENT_LEASE_SECONDS, PROCESSED_EVENT_TTL_SECONDS};
+    use crate::settings::{DeletionSync, Settings};
+    use crate::store::Store;
+
+    // Links and message mappings are kept with the id of the account they belong to. A claimed link has
+    // an empty target id until create_link fills it in, as in the links table.
+    #[derive(Default)]
+    pub struct MemoryStore {
+        pub accounts: Vec<Account>,
+        pub events: MemoryProcessedEvents,
+        pub links: RefCell<Vec<(String, Link)>>,
+        // When each claimed link was claimed, as claimed_at in the links table
+        link_claims: RefCell<Vec<(String, LinkEnd, u64)>>,
+        pub channel_mappings: Vec<ChannelMapping>,
+        pub message_mappings: RefCell<Vec<(String, MessageMapping)>>,
+        // Makes create_link fail, as a database error after the thread is created would
+        pub failing_create_link: bool,
+        // Makes link and message mapping lookups fail, as a database error would rather than a missing row
+        pub failing_lookups: bool,
+    }
+
+    impl MemoryStore {
+        pub fn with_account(mut self, account: Account) -> Self {
+            self.accounts.push(account);
+            return self;
+        }
+
+        pub fn with_channel_mapping(self, trello_board: &str, trello_list: Option<&str>, slack_channel: &str) -> Self {
+            return self.with_target_mapping(trello_board, trello_list, ActionService::Slack, slack_channel);
+        }
+
+        pub fn with_target_mapping(mut self, trello_board: &str, trello_list: Option<&str>, target_service: ActionService, target_container: &str) -> Self {
+            self.channel_mappings.push(ChannelMapping {
+                account_id: "account".to_string(),
+                trello_board: trello_board.to_string(),
+                trello_list: trello_list.map(str::to_string),
+                target_service,
+                target_container: target_container.to_string(),
+            });
+            return self;
+        }
+
+        /// A card linked to a Slack thread
+        pub fn with_link(self, trello_card: &str, slack_channel: &str, slack_thread: &str) -> Self {
+            self.links.borrow_mut().push(("account".to_string(), Link {
+                source: LinkEnd::new(ActionService::Trello, None, trello_card),
+                target: LinkEnd::new(ActionService::Slack, Some(slack_channel), slack_thread),
+            }));
+            return self;
+        }
+
+        /// A message and the copy posted for it
+        pub fn with_message_mapping(self, source: LinkEnd, target: LinkEnd) -> Self {
+            self.message_mappings.borrow_mut().push(("account".to_string(), MessageMapping { source, target }));
+            return self;
+        }
+
+        fn find_link(&self, account: &Account, matches: impl Fn(&Link) -> bool) -> Result<Option<Link>, Error> {
+            if self.failing_lookups {
+                return Err(Self::lookup_error());
+            }
+            return Ok(self.links.borrow().iter()
+                .find(|(account_id, link)| *account_id == account.id && matches(link))
+                .map(|(_, link)| link.clone()));
+        }
+
+        fn find_message_mapping(&self, account: &Account, matches: impl Fn(&MessageMapping) -> bool) -> Result<Option<MessageMapping>, Error> {
+            if self.failing_lookups {
+                return Err(Self::lookup_error());
+            }
+            return Ok(self.message_mappings.borrow().iter()
+                .find(|(account_id, mapping)| *account_id == account.id && matches(mapping))
+                .map(|(_, mapping)| mapping.clone()));
+        }
+
+        fn lookup_error() -> Error {
+            return Error::RustError("D1_ERROR: database is locked".to_string());
+        }
+
+        fn not_found(what: &str) -> Error {
+            return Error::RustError(format!("No {} found", what));
+        }
+    }
+
+    impl Store for MemoryStore {
+        async fn get_account(&self, id: &str) -> Result<Option<Account>, Error> {
+            return Ok(self.accounts.iter().find(|account| account.id == id).cloned());
+        }
+
+        async fn get_link(&self, account: &Account, end: &LinkEnd) -> Result<Option<Link>, Error> {
+            return self.find_link(account, |link| !link.target.id.is_empty() && (matches_end(&link.source, end) || matches_end(&link.target, end)));
+        }
+
+        async fn get_channel_mapping(&self, account: &Account, trello_board: &str, trello_list: Option<&str>) -> Result<ChannelMapping, Error> {
+            let board_mappings = self.channel_mappings.iter()
+                .filter(|mapping| mapping.account_id == account.id && mapping.trello_board == trello_board);
+            let mut board_mapping = None;
+            for mapping in board_mappings {
+                match mapping.trello_list.as_deref() {
+                    Some(list) if Some(list) == trello_list => return Ok(mapping.clone()),
+                    None => board_mapping = Some(mapping.clone()),
+                    Some(_) => {}
+                }
+            }
+            return board_mapping.ok_or(Self::not_found("channel mapping"));
+        }
+
+        async fn claim_link(&self, account: &Account, source: &LinkEnd, target_service: &ActionService, now: u64) -> Result<bool, Error> {
+            let mut links = self.links.borrow_mut();
+            let mut claims = self.link_claims.borrow_mut();
+            let stale = now.saturating_sub(LINK_CLAIM_TIMEOUT_SECONDS);
+            let target = LinkEnd::new(target_service.clone(), None, "");
+            match links.iter_mut().find(|(account_id, link)| *account_id == account.id && link.source == *source) {
+                Some((_, link)) => {
+                    let fresh = claims.iter().any(|(account_id, claimed, claimed_at)| *account_id == account.id && claimed == source && *claimed_at >= stale);
+                    if !link.target.id.is_empty() || fresh {
+                        return Ok(false);
+                    }
+                    link.target = target;
+                }
+                None => links.push((account.id.to_owned(), Link { source: source.clone(), target })),
+            }
+            claims.retain(|(account_id, claimed, _)| *account_id != account.id || claimed != source);
+            claims.push((account.id.to_owned(), source.clone(), now));
+            return Ok(true);
+        }
+
+        async fn create_link(&self, account: &Account, source: &LinkEnd, target: &LinkEnd) -> Result<(), Error> {
+            if self.failing_create_link {
+                return Err(Error::RustError("D1_ERROR: database is locked".to_string()));
+            }
+            for (account_id, link) in self.links.borrow_mut().iter_mut() {
+                if *account_id == account.id && link.source == *source {
+                    link.target = target.clone();
+                }
+            }
+            return Ok(());
+        }
+
+        async fn release_link(&self, account: &Account, source: &LinkEnd) -> Result<(), Error> {
+            self.links.borrow_mut().retain(|(account_id, link)| *account_id != account.id || link.source != *source || !link.target.id.is_empty());
+            return Ok(());
+        }
+
+        async fn replace_link(&self, account: &Account, replaced: &Link, target: &LinkEnd) -> Result<(), Error> {
+            for (account_id, link) in self.links.borrow_mut().iter_mut() {
+                if *account_id == account.id && link.source == replaced.source && link.target.id == replaced.target.id {
+                    link.target = target.clone();
+                }
+            }
+            return Ok(());
+        }
+
+        async fn create_message_mapping(&self, account: &Account, source: &LinkEnd, target: &LinkEnd) -> Result<(), Error> {
+            let mut mappings = self.message_mappings.borrow_mut();
+            let conflicts = mappings.iter().any(|(account_id, mapping)| *account_id == account.id
+                && (mapping.source == *source || mapping.target == *target));
+            if !conflicts {
+                mappings.push((account.id.to_owned(), MessageMapping { source: source.clone(), target: target.clone() }));
+            }
+            return Ok(());
+        }
+
+        async fn get_message_mapping(&self, account: &Account, end: &LinkEnd) -> Result<Option<MessageMapping>, Error> {
+            return self.find_message_mapping(account, |mapping| mapping.source == *end || mapping.target == *end);
+        }
+
+        async fn delete_message_mapping(&self, account: &Account, end: &LinkEnd) -> Result<(), Error> {
+            self.message_mappings.borrow_mut().retain(|(account_id, mapping)| *account_id != account.id || (mapping.source != *end && mapping.target != *end));
+            return Ok(());
+        }
+    }
+
+    // A link end with no container matches any, as for links created before channel routing
+    fn matches_end(end: &LinkEnd, wanted: &LinkEnd) -> bool {
+        return end.service == wanted.service && end.id == wanted.id
+            && end.container.as_ref().is_none_or(|container| Some(container) == wanted.container.as_ref());
+    }
+
+    impl ProcessedEvents for MemoryStore {
+        async fn start(&self, account: &Account, service: &ActionService, event_id: &str, now: u64) -> Result<EventState, Error> {
+            return self.events.start(account, service, event_id, now).await;
+        }
+
+        async fn finish(&self, account: &Account, service: &ActionService, event_id: &str, now: u64) -> Result<(), Error> {
+            return self.events.finish(account, service, event_id, now).await;
+        }
+
+        async fn forget(&self, account: &Account, service: &ActionService, event_id: &str) -> Result<(), Error> {
+            return self.events.forget(account, service, event_id).await;
+        }
+    }
+
+    /// The account the conformance suite expects a store to be seeded with, see seeded_memory_store
+    pub fn conformance_account() -> Account {
+        return Account {
+            id: "account".to_string(),
+            name: "Test Account".to_string(),
+            credentials: Credentials {
+                slack_auth_token: Some("xoxb-token".to_string()),
+                ..Default::default()
+            },
+            settings: Settings {
+                deletion_sync: DeletionSync::Delete,
+                mirror_attachments: true,
+            },
+        };
+    }
+
+    /// Seeded with conformance_account, an account "other" with no credentials or settings,
+    /// and channel mappings for board "board" to C1 and its list "list" to C2
+    pub fn seeded_memory_store() -> MemoryStore {
+        return MemoryStore::default()
+            .with_account(conformance_account())
+            .with_account(Account { id: "other".to_string(), name: "Other Account".to_string(), credentials: Default::default(), settings: Default::default() })
+            .with_channel_mapping("board", None, "C1")
+            .with_channel_mapping("board", Some("list"), "C2");
+    }
+
+    /// The behaviour the handlers rely on from every Store, run against each implementation with the
+    /// same seed data as seeded_memory_store. D1Store can't run outside the Workers runtime, so it is unverified
+    /// here beyond sharing SqliteStore's queries.
+    pub async fn check_store<S: Store>(store: &S) {
+        check_accounts(store).await;
+        check_links(store).await;
+        check_channel_mappings(store).await;
+        check_message_mappings(store).await;
+        check_processed_events(store).await;
+    }
+
+    async fn check_accounts<S: Store>(store: &S) {
+        let account = store.get_account("account").await.unwrap().unwrap();
+        assert_eq!("Test Account", account.name);
+        assert_eq!(Some("xoxb-token".to_string()), account.credentials.slack_auth_token);
+        assert_eq!(None, account.credentials.trello_api_key);
+        assert_eq!(DeletionSync::Delete, account.settings.deletion_sync);
+        assert!(account.settings.mirror_attachments);
+
+        let other = store.get_account("other").await.unwrap().unwrap();
+        assert_eq!(None, other.credentials.slack_auth_token);
+        assert_eq!(DeletionSync::Annotate, other.settings.deletion_sync);
+        assert!(!other.settings.mirror_attachments);
+
+        assert!(store.get_account("missing").await.unwrap().is_none());
+    }
+
+    async fn check_links<S: Store>(store: &S) {
+        let account = conformance_account();
+        let other = store.get_account("other").await.unwrap().unwrap();
+        let card = LinkEnd::new(ActionService::Trello, None, "card");
+        let thread = LinkEnd::new(ActionService::Slack, Some("C1"), "1000.0001");
+
+        // A claimed link isn't visible until its thread is created
+        assert!(store.claim_link(&account, &card, &ActionService::Slack, 1000).await.unwrap());
+        assert!(!store.claim_link(&account, &card, &ActionService::Slack, 1001).await.unwrap());
+        assert!(store.get_link(&account, &card).await.unwrap().is_none());
+        // A claim whose thread was never created is taken over once it is older than the timeout
+        let abandoned = LinkEnd::new(ActionService::Trello, None, "abandoned-card");
+        assert!(store.claim_link(&account, &abandoned, &ActionService::Slack, 1000).await.unwrap());
+        assert!(!store.claim_link(&account, &abandoned, &ActionService::Slack, 1000 + LINK_CLAIM_TIMEOUT_SECONDS).await.unwrap());
+        assert!(store.claim_link(&account, &abandoned, &ActionService::Slack, 1001 + LINK_CLAIM_TIMEOUT_SECONDS).await.unwrap());
+        assert!(!store.claim_link(&account, &abandoned, &ActionService::Slack, 1002 + LINK_CLAIM_TIMEOUT_SECONDS).await.unwrap());
+        assert!(store.claim_link(&other, &card, &ActionService::Slack, 1001).await.unwrap());
+
+        store.create_link(&account, &card, &thread).await.unwrap();
+        let link = store.get_link(&account, &card).await.unwrap().unwrap();
+        assert_eq!(thread, link.target);
+        assert_eq!(&thread, link.other_end(&card));
+        // Either end finds the link
+        let link = store.get_link(&account, &thread).await.unwrap().unwrap();
+        assert_eq!(&card, link.other_end(&thread));
+        assert!(store.get_link(&account, &LinkEnd::new(ActionService::Slack, Some("C2"), "1000.0001")).await.unwrap().is_none());
+        assert!(store.get_link(&account, &LinkEnd::new(ActionService::Trello, None, "1000.0001")).await.unwrap().is_none());
+        assert!(store.get_link(&other, &card).await.unwrap().is_none());
+
+        // Releasing only gives up a claim, never a created link
+        store.release_link(&account, &card).await.unwrap();
+        assert!(store.get_link(&account, &card).await.unwrap().is_some());
+        store.release_link(&other, &card).await.unwrap();
+        assert!(store.claim_link(&other, &card, &ActionService::Slack, 1002).await.unwrap());
+
+        // A link is only replaced if it still leads where it did when it was read
+        let moved = LinkEnd::new(ActionService::Slack, Some("C3"), "3000.0001");
+        let stale = Link { source: card.clone(), target: LinkEnd::new(ActionService::Slack, Some("C1"), "1000.0002") };
+        store.replace_link(&account, &stale, &moved).await.unwrap();
+        assert_eq!(thread, store.get_link(&account, &card).await.unwrap().unwrap().target);
+        store.replace_link(&account, &link, &moved).await.unwrap();
+        assert_eq!(moved, store.get_link(&account, &card).await.unwrap().unwrap().target);
+        assert!(store.get_link(&account, &thread).await.unwrap().is_none());
+        assert!(!store.claim_link(&account, &card, &ActionService::Slack, 1003).await.unwrap());
+
+        // Any two services can be linked, in either direction
+        let channel_thread = LinkEnd::new(ActionService::Slack, Some("C2"), "2000.0001");
+        let other_card = LinkEnd::new(ActionService::Trello, None, "card2");
+        assert!(store.claim_link(&account, &channel_thread, &ActionService::Trello, 1004).await.unwrap());
+        store.create_link(&account, &channel_thread, &other_card).await.unwrap();
+        assert_eq!(&channel_thread, store.get_link(&account, &other_card).await.unwrap().unwrap().other_end(&other_card));
+        assert!(store.get_link(&account, &LinkEnd::new(ActionService::Slack, Some("C1"), "2000.0001")).await.unwrap().is_none());
+    }
+
+    async fn check_channel_mappings<S: Store>(store: &S) {
+        let account = conformance_account();
+
+        let mapping = store.get_channel_mapping(&account, "board", None).await.unwrap();
+        assert_eq!((ActionService::Slack, "C1"), (mapping.target_service, mapping.target_container.as_str()));
+        assert_eq!("C1", store.get_channel_mapping(&account, "board", Some("other-list")).await.unwrap().target_container);
+        assert_eq!("C2", store.get_channel_mapping(&account, "board", Some("list")).await.unwrap().target_container);
+        assert!(store.get_channel_mapping(&account, "other-board", None).await.is_err());
+    }
+
+    async fn check_message_mappings<S: Store>(store: &S) {
+        let account = conformance_account();
+        let message = LinkEnd::new(ActionService::Slack, Some("C1"), "1000.0001");
+        let comment = LinkEnd::new(ActionService::Trello, None, "comment");
+
+        store.create_message_mapping(&account, &message, &comment).await.unwrap();
+        // A message keeps the first copy it was mapped to
+        store.create_message_mapping(&account, &message, &LinkEnd::new(ActionService::Trello, None, "other-comment")).await.unwrap();
+        let mapping = store.get_message_mapping(&account, &message).await.unwrap().unwrap();
+        assert_eq!((&message, &comment), (&mapping.source, &mapping.target));
+        // Either end finds the mapping
+        assert_eq!(Some(mapping), store.get_message_mapping(&account, &comment).await.unwrap());
+        assert!(store.get_message_mapping(&account, &LinkEnd::new(ActionService::Trello, None, "other-comment")).await.unwrap().is_none());
+        assert!(store.get_message_mapping(&account, &LinkEnd::new(ActionService::Slack, Some("C2"), "1000.0001")).await.unwrap().is_none());
+
+        // Any two services can be mapped, in either direction
+        let reply = LinkEnd::new(ActionService::Slack, Some("C2"), "2000.0001");
+        store.create_message_mapping(&account, &LinkEnd::new(ActionService::Trello, None, "comment2"), &reply).await.unwrap();
+        assert_eq!(ActionService::Trello, store.get_message_mapping(&account, &reply).await.unwrap().unwrap().source.service);
+
+        store.delete_message_mapping(&account, &comment).await.unwrap();
+        assert!(store.get_message_mapping(&account, &message).await.unwrap().is_none());
+        assert!(store.get_message_mapping(&account, &comment).await.unwrap().is_none());
+        assert!(store.get_message_mapping(&account, &reply).await.unwrap().is_some());
+    }
+
+    async fn check_processed_events<S: Store>(store: &S) {
+        let account = conformance_account();
+        let other = store.get_account("other").await.unwrap().unwrap();
+
+        assert_eq!(EventState::Started, store.start(&account, &ActionService::Slack, "EVENT_ID", 1000).await.unwrap());
+        // The first delivery is still processing the event
+        assert_eq!(EventState::InProgress, store.start(&account, &ActionService::Slack, "EVENT_ID", 1001).await.unwrap());
+        assert_eq!(EventState::Started, store.start(&account, &ActionService::Trello, "EVENT_ID", 1001).await.unwrap());
+        assert_eq!(EventState::Started, store.start(&other, &ActionService::Slack, "EVENT_ID", 1001).await.unwrap());
+
+        store.finish(&account, &ActionService::Slack, "EVENT_ID", 1002).await.unwrap();
+        assert_eq!(EventState::Processed, store.start(&account, &ActionService::Slack, "EVENT_ID", 1003).await.unwrap());
+        assert_eq!(EventState::Processed, store.start(&account, &ActionService::Slack, "EVENT_ID", 1000 + EVENT_LEASE_SECONDS).await.unwrap());
+
+        store.forget(&account, &ActionService::Slack, "EVENT_ID").await.unwrap();
+        assert_eq!(EventState::Started, store.start(&account, &ActionService::Slack, "EVENT_ID", 1004).await.unwrap());
+        assert_eq!(EventState::Started, store.start(&account, &ActionService::Slack, "EVENT_ID", 1005 + PROCESSED_EVENT_TTL_SECONDS).await.unwrap());
+
+        // A lease that is never finished runs out, as when a delivery is killed part way
+        assert_eq!(EventState::Started, store.start(&account, &ActionService::Trello, "ACTION_ID", 2000).await.unwrap());
+        assert_eq!(EventState::InProgress, store.start(&account, &ActionService::Trello, "ACTION_ID", 2000 + EVENT_LEASE_SECONDS - 1).await.unwrap());
+        assert_eq!(EventState::Started, store.start(&account, &ActionService::Trello, "ACTION_ID", 2000 + EVENT_LEASE_SECONDS).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn memory_store_conformance() {
+        check_store(&seeded_memory_store()).await;
+    }
+}
@@ -1,18 +1,36 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use reqwest::multipart::{Form, Part};
 use serde::Deserialize;
+use sha1::Sha1;
 use url::form_urlencoded::byte_serialize;
-use worker::{console_log, Env, Error, Response};
+use std::future::Future;
+use std::time::Duration;
+use worker::Error;
 use crate::account::Account;
-use crate::action::{Action, ActionService, ActionTargetSource, ActionType, ActionUpdate};
-use crate::database::{create_link, get_link_from_trello_card, Link};
-use crate::slack::send_action;
+use crate::action::{Action, ActionAttachment, ActionService, ActionTargetSource, ActionType, ActionUpdate, ActionUpdateField, ActionUpdateLink};
+use crate::api::{SlackApi, TrelloApi};
+use crate::connector::{action_target, link_url, log_after_delivery, target_end, thread_action, Connector};
+use crate::database::{Link, LinkEnd};
+use crate::error::SyncError;
+use crate::format::{escape, trello_mentions, trello_to_slack, Mentions};
+use crate::events::{process_once, ProcessedEvents};
+use crate::http::{send_with_retry, sleep};
+use crate::settings::DeletionSync;
+use crate::slack::SlackConnector;
+use crate::store::Store;
+use crate::users::{resolve_trello_mentions, UserDirectory};
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct TrelloWebhook {
     pub model: TrelloWebhookModel,
     pub action: TrelloWebhookAction,
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
 pub struct TrelloWebhookModel {
     pub id: String,
@@ -23,12 +41,14 @@ pub struct TrelloWebhookModel {
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
 pub struct TrelloWebhookAction {
     pub id: String,
     pub id_member_creator: String,
     data: TrelloWebhookActionData,
     #[serde(rename = "type")]
+    pub type_: String,
     pub date: String,
     pub display: TrelloWebhookActionDisplay,
     pub app_creator: Option<TrelloWebhookActionAppCreator>
@@ -36,20 +56,66 @@ pub struct TrelloWebhookAction {
 
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
 pub struct TrelloWebhookActionAppCreator {
     pub id: String,
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
 pub struct TrelloWebhookActionData {
     pub id: Option<String>,
     pub text: Option<String>,
+    // The comment being changed, for updateComment
+    pub action: Option<TrelloWebhookActionDataAction>,
     pub card: TrelloWebhookActionCard,
+    pub board: Option<TrelloWebhookActionBoard>,
+    pub list: Option<TrelloWebhookActionList>,
+    pub list_after: Option<TrelloWebhookActionList>,
+    // The file or link added, for addAttachmentToCard
+    pub attachment: Option<TrelloWebhookActionAttachment>,
+    // The card copied, or the card whose checklist item was converted
+    pub card_source: Option<TrelloWebhookActionCard>,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloWebhookActionAttachment {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloWebhookActionDataAction {
+    pub id: String,
+    pub text: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloWebhookActionBoard {
+    pub id: String,
+    pub name: String,
+    pub short_link: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloWebhookActionList {
+    pub id: String,
+    pub name: String,
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
 pub struct TrelloWebhookActionCard {
     pub id: String,
@@ -59,6 +125,7 @@ pub struct TrelloWebhookActionCard {
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
 pub struct TrelloWebhookActionDisplay {
     pub translation_key: ActionDisplayTranslationKey,
@@ -66,15 +133,23 @@ pub struct TrelloWebhookActionDisplay {
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
 pub struct TrelloWebhookActionDisplayEntities {
     pub card: TrelloWebhookActionDisplayEntitiesCard,
     pub member_creator: TrelloWebhookActionDisplayMemberCreator,
     pub list_before: Option<TrelloWebhookActionDisplayListBeforeAfter>,
     pub list_after: Option<TrelloWebhookActionDisplayListBeforeAfter>,
+    pub label: Option<TrelloWebhookActionDisplayLabel>,
+    pub date: Option<TrelloWebhookActionDisplayDate>,
+    // The member added or removed, the member creator is the one who did it
+    pub member: Option<TrelloWebhookActionDisplayMemberCreator>,
+    pub checkitem: Option<TrelloWebhookActionDisplayCheckItem>,
+    pub checklist: Option<TrelloWebhookActionDisplayChecklist>,
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
 pub struct TrelloWebhookActionDisplayEntitiesCard {
     #[serde(rename = "type")]
@@ -87,6 +162,7 @@ pub struct TrelloWebhookActionDisplayEntitiesCard {
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
 pub struct TrelloWebhookActionDisplayMemberCreator {
     #[serde(rename = "type")]
@@ -97,6 +173,7 @@ pub struct TrelloWebhookActionDisplayMemberCreator {
 }
 
 #[derive(Deserialize, Debug, Clone)]
+#[allow(dead_code)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
 pub struct TrelloWebhookActionDisplayListBeforeAfter {
     #[serde(rename = "type")]
@@ -105,6 +182,145 @@ pub struct TrelloWebhookActionDisplayListBeforeAfter {
     pub text: String,
 }
 
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloWebhookActionDisplayLabel {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub id: String,
+    pub color: Option<String>,
+    // Empty for labels that only have a colour
+    #[serde(default)]
+    pub text: String,
+}
+
+impl TrelloWebhookActionDisplayLabel {
+    fn name(&self) -> &str {
+        if self.text.is_empty() {
+            return self.color.as_deref().unwrap_or_default();
+        }
+        return &self.text;
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloWebhookActionDisplayDate {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub date: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloWebhookActionDisplayCheckItem {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub id: String,
+    pub state: String,
+    pub text: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloWebhookActionDisplayChecklist {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub id: String,
+    pub text: String,
+}
+
+/// The comment action returned when adding a comment
+#[derive(Deserialize, Debug)]
+pub struct TrelloComment {
+    pub id: String,
+}
+
+/// A card as returned by `GET /1/cards/{id}`, used for the thread summary
+#[derive(Deserialize, Clone, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloCard {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub closed: bool,
+    pub due: Option<String>,
+    #[serde(default)]
+    pub due_complete: bool,
+    pub short_url: String,
+    #[serde(default)]
+    pub labels: Vec<TrelloLabel>,
+    pub list: Option<TrelloList>,
+    #[serde(default)]
+    pub members: Vec<TrelloMember>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloLabel {
+    pub name: String,
+    pub color: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloList {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloMember {
+    pub id: String,
+    pub full_name: String,
+    pub username: String,
+}
+
+/// An attachment as returned by `GET /1/cards/{id}/attachments/{id}`
+#[derive(Deserialize, Clone, Debug)]
+#[allow(dead_code)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "camelCase"))]
+pub struct TrelloAttachment {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    pub bytes: Option<u64>,
+    pub mime_type: Option<String>,
+    // False for links, which have nothing to download
+    #[serde(default)]
+    pub is_upload: bool,
+}
+
+impl TrelloCard {
+    // What can be worked out from the webhook alone, for when the card can't be fetched
+    fn from_webhook(webhook: &TrelloWebhook) -> TrelloCard {
+        let data = &webhook.action.data;
+        let list = data.list_after.as_ref().or(data.list.as_ref()).map(|list| TrelloList {
+            id: list.id.to_owned(),
+            name: list.name.to_owned(),
+        });
+        return TrelloCard {
+            id: data.card.id.to_owned(),
+            name: webhook.action.display.entities.card.text.to_owned(),
+            closed: webhook.action.display.entities.card.closed.unwrap_or(false),
+            due: None,
+            due_complete: false,
+            short_url: webhook_card_url(webhook),
+            labels: vec![],
+            list,
+            members: vec![],
+        };
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all(serialize = "PascalCase", deserialize = "snake_case"))]
 pub enum ActionDisplayTranslationKey {
@@ -115,64 +331,544 @@ pub enum ActionDisplayTranslationKey {
     ActionMoveCardFromListToList,
     ActionRenamedCard,
     ActionMovedCardLower,
+    ActionAddAttachmentToCard,
+    ActionAddLabelToCard,
+    ActionRemoveLabelFromCard,
+    ActionAddedADueDate,
+    ActionChangedADueDate,
+    ActionRemovedADueDate,
+    ActionMarkedTheDueDateComplete,
+    ActionMarkedTheDueDateIncomplete,
+    ActionAddedMemberToCard,
+    ActionRemovedMemberFromCard,
+    ActionMemberJoinedCard,
+    ActionMemberLeftCard,
+    ActionCompletedCheckitem,
+    ActionMarkedCheckitemIncomplete,
+    ActionCopyCard,
+    ActionConvertToCardFromCheckitem,
+    ActionSentCardToBoard,
+    ActionMovedCardToBoard,
     #[serde(untagged)]
     Unknown(String),
 }
 
-pub async fn handle_webhook(env: Env, webhook: TrelloWebhook, _account: Account) -> worker::Result<Response> {
-    let link = get_link_from_trello_card(&env, &webhook.action.display.entities.card.id).await;
-    let action = generate_action(&webhook, link);
+// Action types that need more than the display translation key to handle
+const COMMENT_ADDED: &str = "commentCard";
+const COMMENT_UPDATED: &str = "updateComment";
+const COMMENT_DELETED: &str = "deleteComment";
+
+/// Checks the `X-Trello-Webhook` header, a base64 HMAC-SHA1 of the body followed by the callback url.
+/// See https://developer.atlassian.com/cloud/trello/guides/rest-api/webhooks/#webhook-signatures
+pub fn verify_signature(app_secret: &str, callback_url: &str, signature: &str, body: &[u8]) -> bool {
+    let expected = match BASE64.decode(signature) {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+
+    let mut mac = match Hmac::<Sha1>::new_from_slice(app_secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(body);
+    mac.update(callback_url.as_bytes());
+
+    return mac.verify_slice(&expected).is_ok();
+}
+
+/// Posts a Trello action to the thread linked to its card, returning what was done with it
+pub async fn handle<S, D, A>(store: &S, directory: &D, api: &A, webhook: &TrelloWebhook, account: &Account, now: u64) -> Result<&'static str, SyncError>
+where
+    S: Store,
+    D: UserDirectory,
+    A: SlackApi + TrelloApi,
+{
+    // Comments and cards the token makes elsewhere come back as webhooks, and would be sent on again
+    let own_member = directory.trello_member(api, account, "me", now).await?;
+    if own_member.is_some_and(|member| member.id == webhook.action.id_member_creator) {
+        console_log!("Skipping action {} by our own member", webhook.action.id);
+        return Ok("Skipping own action");
+    }
+
+    let card = card_end(&webhook.action.display.entities.card.id);
+    let link = store.get_link(account, &card).await?;
+    let target = link.as_ref().map(|link| link.other_end(&card));
+    let mut action = generate_action(webhook, target, &Mentions::new());
     console_log!("Generated action -> {}", &action.update.text);
 
+    if let Some(source) = &webhook.action.data.card_source {
+        let source = card_end(&source.id);
+        if let Some(link) = store.get_link(account, &source).await? {
+            action.update.fields.extend(source_thread_field(link.other_end(&source)));
+        }
+    }
+
+    if action.action == ActionType::DeleteMessage && account.settings.deletion_sync == DeletionSync::Ignore {
+        return Ok("Deletions ignored");
+    }
+
+    if action.action == ActionType::EditMessage || action.action == ActionType::DeleteMessage {
+        let comment = webhook.action.data.action.as_ref().map(|comment| comment.id.as_str()).unwrap_or_default();
+        let comment = LinkEnd::new(ActionService::Trello, None, comment);
+        match store.get_message_mapping(account, &comment).await? {
+            // Only the copies we posted follow their comment, never the message of someone whose words a comment was
+            // copied from
+            Some(mapping) if mapping.source == comment => action.target = action_target(&mapping.target),
+            Some(_) => {
+                console_log!("Comment {} was copied from another service, skipping", comment.id);
+                return Ok("Comment not from Trello");
+            }
+            None => {
+                console_log!("Comment {} was not synced, skipping", comment.id);
+                return Ok("Comment not synced");
+            }
+        }
+    }
+
+    let moved_board = matches!(webhook.action.display.translation_key, ActionDisplayTranslationKey::ActionMovedCardToBoard);
+    let mut moved_from = None;
+    // Links from before channel routing don't record their channel, so it comes from the board's mapping
+    let unrouted = action.target.id.is_none() || (action.target.service == ActionService::Slack && action.target.channel.is_none());
+    if action.action != ActionType::None && (unrouted || moved_board) {
+        let (board, list) = get_board_and_list(webhook);
+        let mapping = match board {
+            Some(board) => store.get_channel_mapping(account, board, list).await,
+            None => Err(Error::RustError("No board on webhook".to_string())),
+        };
+        match mapping {
+            Ok(mapping) if moved_board => moved_from = reroute_to_channel(&mut action, &mapping.target_service, &mapping.target_container),
+            Ok(mapping) if action.target.id.is_none() || action.target.service == mapping.target_service => {
+                action.target.service = mapping.target_service;
+                action.target.channel = Some(mapping.target_container);
+            }
+            Ok(_) => {
+                console_log!("Board {:?} no longer posts to the card's thread, skipping", board);
+                return Ok("No channel mapped");
+            }
+            // A card moved to an unmapped board stays in its current thread
+            Err(_) if !unrouted => {}
+            Err(_) => {
+                console_log!("No channel mapped for board {:?}, skipping", board);
+                return Ok("No channel mapped");
+            }
+        }
+    }
+
+    let service = action.target.service.clone();
+    let send = |action| async move {
+        let action = resolve_mentions(directory, api, account, webhook, action, now).await;
+        return match service {
+            ActionService::Slack => send_to_thread(store, &SlackConnector::new(api), api, account, webhook, action, moved_from, now).await,
+            ActionService::Trello => send_to_thread(store, &TrelloConnector::new(api), api, account, webhook, action, moved_from, now).await,
+        };
+    };
+    if !process_event(store, account, webhook, action, now, send).await? {
+        console_log!("Skipping already processed action {}", webhook.action.id);
+        return Ok("Already processed");
+    }
+
+    return Ok("Success");
+}
+
+// Mentions can each cost a members lookup, so they are only resolved once the action is known to be new and routed
+async fn resolve_mentions<D: UserDirectory, A: TrelloApi>(directory: &D, api: &A, account: &Account, webhook: &TrelloWebhook, mut action: Action, now: u64) -> Action {
+    if trello_mentions(mentionable_text(webhook)).is_empty() {
+        return action;
+    }
+    let parsed = TrelloConnector::new(api).parse_action(directory, account, webhook, None, now).await;
+    action.update.text = parsed.update.text;
+    action.update.body = parsed.update.body;
+    return action;
+}
+
+// Trello retries callbacks it thinks failed, so each action id is only sent on once
+async fn process_event<E, F, Fut>(events: &E, account: &Account, webhook: &TrelloWebhook, action: Action, now: u64, send: F) -> Result<bool, SyncError>
+where
+    E: ProcessedEvents,
+    F: FnOnce(Action) -> Fut,
+    Fut: Future<Output = Result<(), SyncError>>,
+{
+    if action.action == ActionType::None {
+        return Ok(true);
+    }
+    return process_once(events, account, &ActionService::Trello, &webhook.action.id, now, || send(action)).await;
+}
+
+fn card_end(card_id: &str) -> LinkEnd {
+    return LinkEnd::new(ActionService::Trello, None, card_id);
+}
+
+/// Links cards to threads elsewhere. A linked card's thread is a card started on a list, which is its own summary,
+/// and messages are comments on it.
+pub struct TrelloConnector<'a, A> {
+    api: &'a A,
+}
+
+impl<'a, A: TrelloApi> TrelloConnector<'a, A> {
+    pub fn new(api: &'a A) -> TrelloConnector<'a, A> {
+        return TrelloConnector { api };
+    }
+}
+
+impl<A: TrelloApi> Connector for TrelloConnector<'_, A> {
+    type Webhook = TrelloWebhook;
+
+    fn service(&self) -> ActionService {
+        return ActionService::Trello;
+    }
+
+    fn link_end(&self, webhook: &TrelloWebhook) -> Option<LinkEnd> {
+        return Some(card_end(&webhook.action.display.entities.card.id));
+    }
+
+    async fn parse_action<D: UserDirectory>(&self, directory: &D, account: &Account, webhook: &TrelloWebhook, target: Option<&LinkEnd>, now: u64) -> Action {
+        let mentions = resolve_trello_mentions(directory, self.api, account, mentionable_text(webhook), now).await;
+        return generate_action(webhook, target, &mentions);
+    }
+
+    async fn create_thread(&self, account: &Account, list: &str, summary: &ActionUpdate) -> Result<LinkEnd, SyncError> {
+        let card = self.api.create_card(account, list, summary).await?;
+        return Ok(card_end(&card.id));
+    }
+
+    async fn update_thread(&self, _account: &Account, _thread: &LinkEnd, _summary: &ActionUpdate) -> Result<(), SyncError> {
+        return Ok(());
+    }
+
+    async fn reply(&self, account: &Account, thread: &LinkEnd, update: &ActionUpdate) -> Result<LinkEnd, SyncError> {
+        return self.deliver(account, thread_action(thread, update)).await;
+    }
+
+    async fn deliver(&self, account: &Account, action: Action) -> Result<LinkEnd, SyncError> {
+        let comment = self.api.add_comment(account, action).await?;
+        return Ok(LinkEnd::new(ActionService::Trello, None, &comment.id));
+    }
+
+    async fn edit(&self, account: &Account, action: Action) -> Result<(), SyncError> {
+        return self.api.update_comment(account, action).await;
+    }
+
+    async fn delete(&self, account: &Account, action: Action) -> Result<(), SyncError> {
+        return self.api.delete_comment(account, action).await;
+    }
+
+    async fn attach(&self, account: &Account, card: &LinkEnd, attachment: &ActionAttachment, bytes: Option<Vec<u8>>) -> Result<(), SyncError> {
+        return match bytes {
+            Some(bytes) => self.api.upload_attachment(account, &card.id, attachment, bytes).await,
+            None => self.api.attach_link(account, &card.id, &attachment.name, &attachment.permalink).await,
+        };
+    }
+}
+
+// Sends the action through the connector for the other end of the card's link. `moved_from` is the card's
+// link before it moved to a board whose threads are started somewhere else.
+#[allow(clippy::too_many_arguments)]
+async fn send_to_thread<S: Store, C: Connector, A: SlackApi + TrelloApi>(store: &S, target: &C, api: &A, account: &Account, webhook: &TrelloWebhook, mut action: Action, moved_from: Option<Link>, now: u64) -> Result<(), SyncError> {
+    let card = card_end(&webhook.action.display.entities.card.id);
     match action.action {
         ActionType::NewThread => {
-            console_log!("New thread");
-            let response = send_action(&env, action).await;
-            create_link(&env, &webhook.action.display.entities.card.id, &response.ts).await.expect("Error inserting row");
+            // A moved card keeps its link until the new thread replaces it, so a failure leaves it in its old thread
+            if moved_from.is_some() || store.claim_link(account, &card, &target.service(), now).await? {
+                console_log!("New thread");
+                let container = action.target.channel.clone().unwrap_or_default();
+                let summary = card_summary(&get_card_or_webhook(api, account, webhook).await);
+                let thread = match target.create_thread(account, &container, &summary).await {
+                    Ok(thread) => thread,
+                    Err(err) if moved_from.is_some() => return Err(err),
+                    Err(err) => {
+                        store.release_link(account, &card).await?;
+                        return Err(err);
+                    }
+                };
+                let linked = match &moved_from {
+                    Some(previous) => store.replace_link(account, previous, &thread).await,
+                    None => store.create_link(account, &card, &thread).await,
+                };
+                if let Err(err) = linked {
+                    // Nothing leads to the thread now, so it is left behind and the retry starts another
+                    console_log!("Error linking card {} to thread {}, leaving it orphaned: {}", card.id, link_url(&thread).unwrap_or_default(), err);
+                    if moved_from.is_none() {
+                        store.release_link(account, &card).await?;
+                    }
+                    return Err(err.into());
+                }
+                if let Some(previous) = moved_from {
+                    leave_thread(api, account, &previous, &thread).await;
+                }
+
+                action.target = action_target(&thread);
+                send_reply(store, target, api, account, webhook, action).await?;
+            } else {
+                // Another delivery is creating the thread, so reply to it once it exists
+                console_log!("Waiting for thread to be created");
+                let link = wait_for_link(store, account, &card).await?;
+                let channel = action.target.channel.take();
+                action.action = ActionType::UpdateThread;
+                action.target = action_target(link.other_end(&card));
+                action.target.channel = action.target.channel.or(channel);
+                reply_and_refresh_summary(store, target, api, account, webhook, action).await?;
+            }
         }
         ActionType::UpdateThread => {
             console_log!("Existing thread");
-            send_action(&env, action).await;
+            reply_and_refresh_summary(store, target, api, account, webhook, action).await?;
+        }
+        ActionType::EditMessage => {
+            console_log!("Editing mirrored comment");
+            target.edit(account, action).await?;
+        }
+        ActionType::DeleteMessage => {
+            let copy = target_end(&action.target);
+            match account.settings.deletion_sync {
+                DeletionSync::Delete => target.delete(account, action).await?,
+                DeletionSync::Annotate => target.edit(account, action).await?,
+                DeletionSync::Ignore => return Ok(()),
+            }
+            if let Err(err) = store.delete_message_mapping(account, &copy).await {
+                console_log!("Error removing message mapping: {}", err);
+            }
         }
         ActionType::None => {}
     }
+    return Ok(());
+}
+
+/// Points a card moved to another board at where that board's threads are started. A card already posting there
+/// keeps its thread, otherwise the link it leaves behind is returned and a new thread is started.
+fn reroute_to_channel(action: &mut Action, service: &ActionService, channel: &str) -> Option<Link> {
+    let previous = match (&action.target.channel, &action.target.id) {
+        (Some(previous_channel), _) if action.target.service == *service && previous_channel == channel => return None,
+        // Links from before channel routing have no channel, so they can't be known to be in this one
+        (_, Some(_)) => Link {
+            source: card_end(action.source.id.as_deref().unwrap_or_default()),
+            target: target_end(&action.target),
+        },
+        _ => {
+            action.target.service = service.clone();
+            action.target.channel = Some(channel.to_string());
+            return None;
+        }
+    };
 
+    action.action = ActionType::NewThread;
+    action.target.id = None;
+    action.target.service = service.clone();
+    action.target.channel = Some(channel.to_string());
+    action.update.fields.extend(source_thread_field(&previous.target).map(|field| ActionUpdateField { label: "Previous thread".to_string(), ..field }));
+    return Some(previous);
+}
 
-    return Response::ok("Success");
+/// A link to the thread of the card this one came from
+fn source_thread_field(thread: &LinkEnd) -> Option<ActionUpdateField> {
+    return Some(ActionUpdateField {
+        label: "Source thread".to_string(),
+        value: format!("<{}|View thread>", link_url(thread)?),
+    });
 }
 
-fn generate_action(webhook: &TrelloWebhook, link_result: Result<Link, Error>) -> Action {
-    let mut action = ActionType::UpdateThread;
-    let mut slack_id = None;
-    match link_result {
-        Ok(link) => {
-            slack_id = Some(link.slack_thread);
+// Points the old thread at the new one, the new thread is already up so a failure here is only logged.
+// The old thread may be in another service than the new one.
+async fn leave_thread<A: SlackApi + TrelloApi>(api: &A, account: &Account, previous: &Link, thread: &LinkEnd) {
+    let update = ActionUpdate {
+        text: format!("This card has moved to another board, updates continue in {}", link_url(thread).unwrap_or_default()),
+        ..Default::default()
+    };
+    let result = match previous.target.service {
+        ActionService::Slack => SlackConnector::new(api).reply(account, &previous.target, &update).await,
+        ActionService::Trello => TrelloConnector::new(api).reply(account, &previous.target, &update).await,
+    };
+    if let Err(err) = result {
+        console_log!("Error posting to previous thread: {}", err);
+    }
+}
+
+// Replies for comments are remembered so edits to the comment can be mirrored
+async fn send_reply<S: Store, C: Connector, A: TrelloApi>(store: &S, target: &C, api: &A, account: &Account, webhook: &TrelloWebhook, action: Action) -> Result<(), SyncError> {
+    let thread = target_end(&action.target);
+    let attachments = action.update.attachments.clone();
+    let message = target.deliver(account, action).await?;
+    if webhook.action.type_ == COMMENT_ADDED {
+        let comment = LinkEnd::new(ActionService::Trello, None, &webhook.action.id);
+        log_after_delivery("saving message mapping", store.create_message_mapping(account, &comment, &message).await);
+    }
+
+    if account.settings.mirror_attachments {
+        for attachment in attachments {
+            let result = mirror_attachment_to_thread(target, api, account, &webhook.action.data.card.id, &thread, &attachment).await;
+            log_after_delivery(&format!("mirroring attachment {}", attachment.id), result);
         }
-        Err(_) => {
-            action = ActionType::NewThread;
+    }
+    return Ok(());
+}
+
+// The webhook doesn't say how big an attachment is, or whether it is a file at all
+async fn mirror_attachment_to_thread<C: Connector, A: TrelloApi>(target: &C, api: &A, account: &Account, card: &str, thread: &LinkEnd, attachment: &ActionAttachment) -> Result<(), SyncError> {
+    let details = api.get_attachment(account, card, &attachment.id).await?;
+    if !details.is_upload {
+        return Ok(());
+    }
+    let attachment = ActionAttachment {
+        size: details.bytes,
+        mime_type: details.mime_type,
+        ..attachment.clone()
+    };
+    if !attachment.can_copy() {
+        console_log!("Attachment {} is too large to copy, leaving the link", attachment.id);
+        return Ok(());
+    }
+
+    let bytes = api.download_attachment(account, &details.url).await?;
+    return target.attach(account, thread, &attachment, Some(bytes)).await;
+}
+
+async fn reply_and_refresh_summary<S: Store, C: Connector, A: TrelloApi>(store: &S, target: &C, api: &A, account: &Account, webhook: &TrelloWebhook, action: Action) -> Result<(), SyncError> {
+    let thread = target_end(&action.target);
+    send_reply(store, target, api, account, webhook, action).await?;
+
+    let summary = card_summary(&get_card_or_webhook(api, account, webhook).await);
+    log_after_delivery("updating thread summary", target.update_thread(account, &thread, &summary).await);
+    return Ok(());
+}
+
+async fn get_card_or_webhook<A: TrelloApi>(api: &A, account: &Account, webhook: &TrelloWebhook) -> TrelloCard {
+    return match api.get_card(account, &webhook.action.data.card.id).await {
+        Ok(card) => card,
+        Err(err) => {
+            console_log!("Error fetching card, summarising from the webhook: {}", err);
+            TrelloCard::from_webhook(webhook)
+        }
+    };
+}
+
+/// The thread parent, showing the card's current state
+fn card_summary(card: &TrelloCard) -> ActionUpdate {
+    let mut fields = vec![];
+    if let Some(list) = &card.list {
+        fields.push(ActionUpdateField { label: "List".to_string(), value: list.name.to_owned() });
+    }
+    if !card.labels.is_empty() {
+        let labels: Vec<&str> = card.labels.iter()
+            .map(|label| if label.name.is_empty() { label.color.as_deref().unwrap_or_default() } else { label.name.as_str() })
+            .collect();
+        fields.push(ActionUpdateField { label: "Labels".to_string(), value: labels.join(", ") });
+    }
+    if let Some(due) = &card.due {
+        let complete = if card.due_complete { " (complete)" } else { "" };
+        fields.push(ActionUpdateField { label: "Due".to_string(), value: format!("{}{}", format_due_date(due), complete) });
+    }
+    if !card.members.is_empty() {
+        let members: Vec<&str> = card.members.iter().map(|member| member.full_name.as_str()).collect();
+        fields.push(ActionUpdateField { label: "Members".to_string(), value: members.join(", ") });
+    }
+    let status = if card.closed { "Archived" } else { "Open" };
+    fields.push(ActionUpdateField { label: "Status".to_string(), value: status.to_string() });
+
+    let list = card.list.as_ref().map(|list| list.name.as_str()).unwrap_or("unknown list");
+    return ActionUpdate {
+        text: format!("{} ({}, {})", card.name, list, status.to_lowercase()),
+        title: Some(card.name.to_owned()),
+        fields,
+        link: Some(ActionUpdateLink {
+            label: "View card".to_string(),
+            url: card.short_url.to_owned(),
+        }),
+        ..Default::default()
+    };
+}
+
+const WAIT_FOR_LINK_ATTEMPTS: u32 = 10;
+const WAIT_FOR_LINK_DELAY: Duration = Duration::from_millis(500);
+
+async fn wait_for_link<S: Store>(store: &S, account: &Account, card: &LinkEnd) -> Result<Link, Error> {
+    for _ in 0..WAIT_FOR_LINK_ATTEMPTS {
+        sleep(WAIT_FOR_LINK_DELAY).await;
+        if let Some(link) = store.get_link(account, card).await? {
+            return Ok(link);
         }
     }
+    return Err(Error::RustError(format!("Timed out waiting for the thread for card {}", card.id)));
+}
+
+// The board and list the card is on after this action, used to pick a slack channel
+fn get_board_and_list(webhook: &TrelloWebhook) -> (Option<&str>, Option<&str>) {
+    let data = &webhook.action.data;
+    let board = data.board.as_ref().map(|board| board.id.as_str());
+    let list = data.list_after.as_ref()
+        .or(data.list.as_ref())
+        .map(|list| list.id.as_str());
+
+    return (board, list);
+}
+
+// Text written by a member that may mention others
+fn mentionable_text(webhook: &TrelloWebhook) -> &str {
+    if webhook.action.type_ == COMMENT_UPDATED {
+        return webhook.action.data.action.as_ref().and_then(|comment| comment.text.as_deref()).unwrap_or_default();
+    }
+    return match &webhook.action.display.translation_key {
+        ActionDisplayTranslationKey::ActionCommentOnCard => webhook.action.data.text.as_deref().unwrap_or_default(),
+        ActionDisplayTranslationKey::ActionChangedDescriptionOfCard => webhook.action.display.entities.card.desc.as_deref().unwrap_or_default(),
+        _ => "",
+    };
+}
+
+// `target` is the other end of the card's link, a card without one starts a new thread
+fn generate_action(webhook: &TrelloWebhook, target: Option<&LinkEnd>, mentions: &Mentions) -> Action {
+    let mut action = ActionType::UpdateThread;
+    let target = match target {
+        Some(target) => action_target(target),
+        None => {
+            action = ActionType::NewThread;
+            unrouted_target()
+        }
+    };
 
-    let source = create_action_source(&webhook);
-    let target = create_action_target(&webhook, slack_id);
+    let source = create_action_source(webhook);
 
     let update = match &webhook.action.display.translation_key {
-        ActionDisplayTranslationKey::ActionCreateCard => handle_card_created(&webhook),
-        ActionDisplayTranslationKey::ActionArchivedCard => handle_archived_card(&webhook),
-        ActionDisplayTranslationKey::ActionRenamedCard => handle_card_renamed(&webhook),
-        ActionDisplayTranslationKey::ActionChangedDescriptionOfCard => handle_description_updated(&webhook),
-        ActionDisplayTranslationKey::ActionCommentOnCard => handle_comment_added(&webhook),
-        ActionDisplayTranslationKey::ActionMoveCardFromListToList => handle_card_moved(&webhook),
+        // Trello has no display translation for comment edits
+        _ if webhook.action.type_ == COMMENT_UPDATED => {
+            action = ActionType::EditMessage;
+            handle_comment_updated(webhook, mentions)
+        }
+        _ if webhook.action.type_ == COMMENT_DELETED => {
+            action = ActionType::DeleteMessage;
+            handle_comment_deleted(webhook)
+        }
+        ActionDisplayTranslationKey::ActionCreateCard => handle_card_created(webhook),
+        ActionDisplayTranslationKey::ActionArchivedCard => handle_archived_card(webhook),
+        ActionDisplayTranslationKey::ActionRenamedCard => handle_card_renamed(webhook),
+        ActionDisplayTranslationKey::ActionChangedDescriptionOfCard => handle_description_updated(webhook, mentions),
+        ActionDisplayTranslationKey::ActionCommentOnCard => handle_comment_added(webhook, mentions),
+        ActionDisplayTranslationKey::ActionMoveCardFromListToList => handle_card_moved(webhook),
+        ActionDisplayTranslationKey::ActionAddAttachmentToCard => handle_attachment_added(webhook),
+        ActionDisplayTranslationKey::ActionAddLabelToCard => handle_label_changed(webhook, "added"),
+        ActionDisplayTranslationKey::ActionRemoveLabelFromCard => handle_label_changed(webhook, "removed"),
+        ActionDisplayTranslationKey::ActionAddedADueDate => handle_due_date_set(webhook, "added"),
+        ActionDisplayTranslationKey::ActionChangedADueDate => handle_due_date_set(webhook, "changed"),
+        ActionDisplayTranslationKey::ActionRemovedADueDate => handle_due_date_status(webhook, "removed"),
+        ActionDisplayTranslationKey::ActionMarkedTheDueDateComplete => handle_due_date_status(webhook, "marked complete"),
+        ActionDisplayTranslationKey::ActionMarkedTheDueDateIncomplete => handle_due_date_status(webhook, "marked incomplete"),
+        ActionDisplayTranslationKey::ActionAddedMemberToCard | ActionDisplayTranslationKey::ActionMemberJoinedCard => handle_member_changed(webhook, "added"),
+        ActionDisplayTranslationKey::ActionRemovedMemberFromCard | ActionDisplayTranslationKey::ActionMemberLeftCard => handle_member_changed(webhook, "removed"),
+        ActionDisplayTranslationKey::ActionCompletedCheckitem => handle_check_item_changed(webhook, "completed"),
+        ActionDisplayTranslationKey::ActionMarkedCheckitemIncomplete => handle_check_item_changed(webhook, "marked incomplete"),
+        ActionDisplayTranslationKey::ActionCopyCard => handle_card_copied(webhook, "copied from", "Card copied"),
+        ActionDisplayTranslationKey::ActionConvertToCardFromCheckitem => handle_card_copied(webhook, "converted from a checklist item on", "Card created from checklist item"),
+        ActionDisplayTranslationKey::ActionSentCardToBoard => handle_card_restored(webhook),
+        ActionDisplayTranslationKey::ActionMovedCardToBoard => handle_card_moved_to_board(webhook),
         ActionDisplayTranslationKey::Unknown(value) => {
             action = ActionType::None;
             ActionUpdate{
-                text: format!("Unknown key {:?}", value)
+                text: format!("Unknown key {:?}", value),
+                ..Default::default()
             }
         }
         _ => {
             action = ActionType::None;
             ActionUpdate{
-                text: format!("Unknown key {:?}", &webhook.action.display.translation_key)
+                text: format!("Unknown key {:?}", &webhook.action.display.translation_key),
+                ..Default::default()
             }
         }
     };
@@ -192,14 +888,39 @@ fn generate_action(webhook: &TrelloWebhook, link_result: Result<Link, Error>) ->
     };
 }
 
+// The parts of an update every card event shares
+fn card_update(webhook: &TrelloWebhook) -> ActionUpdate {
+    return ActionUpdate {
+        title: Some(webhook.action.display.entities.card.text.to_owned()),
+        actor: Some(webhook.action.display.entities.member_creator.text.to_owned()),
+        link: Some(ActionUpdateLink {
+            label: "View card".to_string(),
+            url: webhook_card_url(webhook),
+        }),
+        ..Default::default()
+    };
+}
+
+// The webhook model is only the card itself when the webhook was registered on the card
+fn webhook_card_url(webhook: &TrelloWebhook) -> String {
+    if webhook.model.id == webhook.action.data.card.id {
+        return webhook.model.short_url.to_owned();
+    }
+    return format!("https://trello.com/c/{}", webhook.action.data.card.short_link);
+}
+
 fn handle_card_created(webhook: &TrelloWebhook) -> ActionUpdate {
     return ActionUpdate {
         text: format!("This card {} has been created by {}", webhook.action.display.entities.card.text, webhook.action.display.entities.member_creator.text),
+        body: Some("Card created".to_string()),
+        ..card_update(webhook)
     };
 }
 fn handle_archived_card(webhook: &TrelloWebhook) -> ActionUpdate {
     return ActionUpdate {
         text: format!("This card has been archived by {}", webhook.action.display.entities.member_creator.text),
+        body: Some("Card archived".to_string()),
+        ..card_update(webhook)
     };
 }
 
@@ -208,88 +929,418 @@ fn handle_card_renamed(webhook: &TrelloWebhook) -> ActionUpdate {
         text: format!("This card has been renamed to {} by {}",
                       webhook.action.display.entities.card.text,
                       webhook.action.display.entities.member_creator.text),
+        body: Some("Card renamed".to_string()),
+        ..card_update(webhook)
     };
 }
 
 fn handle_card_moved(webhook: &TrelloWebhook) -> ActionUpdate {
+    let list_before = webhook.action.display.entities.list_before.as_ref().map(|list| list.text.clone()).unwrap_or_default();
+    let list_after = webhook.action.display.entities.list_after.as_ref().map(|list| list.text.clone()).unwrap_or_default();
     return ActionUpdate {
         text: format!("This card has been moved from list {} to list {} by {}",
-                      webhook.action.display.entities.list_before.clone().unwrap().text,
-                      webhook.action.display.entities.list_after.clone().unwrap().text,
+                      list_before,
+                      list_after,
                       webhook.action.display.entities.member_creator.text),
+        body: Some("Card moved".to_string()),
+        fields: vec![
+            ActionUpdateField { label: "From".to_string(), value: list_before },
+            ActionUpdateField { label: "To".to_string(), value: list_after },
+        ],
+        ..card_update(webhook)
     };
 }
 
-fn handle_description_updated(webhook: &TrelloWebhook) -> ActionUpdate {
+fn handle_description_updated(webhook: &TrelloWebhook, mentions: &Mentions) -> ActionUpdate {
+    let description = trello_to_slack(webhook.action.display.entities.card.desc.as_deref().unwrap_or_default(), mentions);
     return ActionUpdate {
         text: format!("This card description has been updated to {} by {}",
-                      webhook.action.display.entities.card.desc.clone().unwrap(),
+                      description,
                       webhook.action.display.entities.member_creator.text),
+        body: Some(format!("Description updated\n{}", description)),
+        ..card_update(webhook)
     };
 }
 
-fn handle_comment_added(webhook: &TrelloWebhook) -> ActionUpdate {
+fn handle_comment_added(webhook: &TrelloWebhook, mentions: &Mentions) -> ActionUpdate {
+    let comment = trello_to_slack(webhook.action.data.text.as_deref().unwrap_or_default(), mentions);
     return ActionUpdate {
         text: format!("Comment added by {}\n{}",
                       webhook.action.display.entities.member_creator.text,
-                      webhook.action.data.text.clone().unwrap(),
+                      comment,
         ),
+        body: Some(comment),
+        ..card_update(webhook)
     };
 }
 
-
-fn create_action_source(webhook: &TrelloWebhook) -> ActionTargetSource {
-    return ActionTargetSource {
-        id: Option::from(String::from(&webhook.action.data.card.id)),
-        service: ActionService::Trello,
-        url: format!("https://trello.com/c/{}", webhook.action.data.card.short_link),
+// Replaces the reply made by handle_comment_added, so it keeps the same shape
+fn handle_comment_updated(webhook: &TrelloWebhook, mentions: &Mentions) -> ActionUpdate {
+    let comment = webhook.action.data.action.as_ref().and_then(|comment| comment.text.as_deref()).unwrap_or_default();
+    let comment = trello_to_slack(comment, mentions);
+    return ActionUpdate {
+        text: format!("Comment added by {} (edited)\n{}",
+                      webhook.action.display.entities.member_creator.text,
+                      comment,
+        ),
+        body: Some(format!("{}\n_(edited)_", comment)),
+        ..card_update(webhook)
     };
 }
 
-fn create_action_target(_webhook: &TrelloWebhook, id: Option<String>) -> ActionTargetSource {
-    // todo: fix url
-    return ActionTargetSource {
-        id,
-        service: ActionService::Slack,
-        url: "SOME URL FOR SLACK".to_string(),
+// Trello doesn't send the text of a deleted comment, so the annotation replaces the mirrored reply's text
+fn handle_comment_deleted(webhook: &TrelloWebhook) -> ActionUpdate {
+    return ActionUpdate {
+        text: format!("Comment deleted by {}", webhook.action.display.entities.member_creator.text),
+        body: Some("_(deleted)_".to_string()),
+        ..card_update(webhook)
     };
 }
 
-
-
-pub async fn add_comment_to_card(env: &Env, action: Action){
-    if action.action == ActionType::None {
-        return;
+// The new card gets a thread of its own, see source_thread_field for the link back to the source's thread
+fn handle_card_copied(webhook: &TrelloWebhook, change: &str, body: &str) -> ActionUpdate {
+    let mut fields = vec![];
+    let mut source_name = "another card";
+    if let Some(source) = &webhook.action.data.card_source {
+        source_name = &source.name;
+        let url = format!("https://trello.com/c/{}", source.short_link);
+        fields.push(ActionUpdateField { label: "Source card".to_string(), value: format!("<{}|{}>", url, escape(&source.name)) });
     }
-
-    let api_key = env.secret("TRELLO_API_KEY".as_ref()).unwrap().to_string();
-    let api_token =  env.secret("TRELLO_API_TOKEN".as_ref()).unwrap().to_string();
-
-    let card_id = action.target.id.unwrap().to_string();
-    let text: String = byte_serialize( action.update.text.as_bytes()).collect();
-
-    let url = format!("https://api.trello.com/1/cards/{card_id}/actions/comments?text={text}&key={api_key}&token={api_token}");
-
-    let client = reqwest::Client::new();
-    let _res = match client.post(url)
-        .header("Content-Type", "application/json")
-        .send()
-        .await{
-        Ok(value)=> value,
-        Err(_err)=> {
-            console_log!("{}", _err.to_string());
-            panic!("ERR");
-        },
+    return ActionUpdate {
+        text: format!("This card has been {} {} by {}", change, source_name, webhook.action.display.entities.member_creator.text),
+        body: Some(body.to_string()),
+        fields,
+        ..card_update(webhook)
     };
+}
 
-    //return res;
+fn handle_card_restored(webhook: &TrelloWebhook) -> ActionUpdate {
+    return ActionUpdate {
+        text: format!("This card has been restored by {}", webhook.action.display.entities.member_creator.text),
+        body: Some("Card restored".to_string()),
+        ..card_update(webhook)
+    };
 }
 
+fn handle_card_moved_to_board(webhook: &TrelloWebhook) -> ActionUpdate {
+    let board = webhook.action.data.board.as_ref().map(|board| board.name.as_str()).unwrap_or("another board");
+    return ActionUpdate {
+        text: format!("This card has been moved to board {} by {}", board, webhook.action.display.entities.member_creator.text),
+        body: Some("Card moved to board".to_string()),
+        fields: vec![ActionUpdateField { label: "Board".to_string(), value: board.to_string() }],
+        ..card_update(webhook)
+    };
+}
+
+fn handle_label_changed(webhook: &TrelloWebhook, change: &str) -> ActionUpdate {
+    let label = webhook.action.display.entities.label.as_ref().map(|label| label.name()).unwrap_or_default();
+    return ActionUpdate {
+        text: format!("Label {} {} by {}", label, change, webhook.action.display.entities.member_creator.text),
+        body: Some(format!("Label {}", change)),
+        fields: vec![ActionUpdateField { label: "Label".to_string(), value: label.to_string() }],
+        ..card_update(webhook)
+    };
+}
+
+fn handle_due_date_set(webhook: &TrelloWebhook, change: &str) -> ActionUpdate {
+    let due = format_due_date(webhook.action.display.entities.date.as_ref().map(|date| date.date.as_str()).unwrap_or_default());
+    return ActionUpdate {
+        text: format!("Due date {} by {}, now due {}", change, webhook.action.display.entities.member_creator.text, due),
+        body: Some(format!("Due date {}", change)),
+        fields: vec![ActionUpdateField { label: "Due".to_string(), value: due }],
+        ..card_update(webhook)
+    };
+}
+
+/// Formats a Trello due date such as `2024-05-10T16:00:00.000Z` as `10 May 2024 16:00 UTC`. The text goes to Slack
+/// and Trello alike, so it can't be a Slack date token, and is left as it is if it isn't in the format Trello uses.
+fn format_due_date(due: &str) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let parts = (due.get(0..4), due.get(5..7).and_then(|month| month.parse::<usize>().ok()), due.get(8..10), due.get(11..16));
+    return match parts {
+        (Some(year), Some(month @ 1..=12), Some(day), Some(time)) if due.ends_with('Z') => {
+            format!("{} {} {} {} UTC", day.trim_start_matches('0'), MONTHS[month - 1], year, time)
+        }
+        _ => due.to_string(),
+    };
+}
+
+// Changes to a due date that don't carry a date
+fn handle_due_date_status(webhook: &TrelloWebhook, change: &str) -> ActionUpdate {
+    return ActionUpdate {
+        text: format!("Due date {} by {}", change, webhook.action.display.entities.member_creator.text),
+        body: Some(format!("Due date {}", change)),
+        ..card_update(webhook)
+    };
+}
+
+// Members joining or leaving a card themselves have no separate member entity
+fn handle_member_changed(webhook: &TrelloWebhook, change: &str) -> ActionUpdate {
+    let entities = &webhook.action.display.entities;
+    let member = entities.member.as_ref().unwrap_or(&entities.member_creator);
+    return ActionUpdate {
+        text: format!("Member {} {} by {}", member.text, change, entities.member_creator.text),
+        body: Some(format!("Member {}", change)),
+        fields: vec![ActionUpdateField { label: "Member".to_string(), value: member.text.to_owned() }],
+        ..card_update(webhook)
+    };
+}
+
+fn handle_check_item_changed(webhook: &TrelloWebhook, change: &str) -> ActionUpdate {
+    let entities = &webhook.action.display.entities;
+    let item = entities.checkitem.as_ref().map(|item| item.text.as_str()).unwrap_or_default();
+    let checklist = entities.checklist.as_ref().map(|checklist| checklist.text.as_str()).unwrap_or_default();
+    return ActionUpdate {
+        text: format!("Checklist item {} on {} {} by {}", item, checklist, change, entities.member_creator.text),
+        body: Some(format!("Checklist item {}", change)),
+        fields: vec![
+            ActionUpdateField { label: "Checklist".to_string(), value: checklist.to_string() },
+            ActionUpdateField { label: "Item".to_string(), value: item.to_string() },
+        ],
+        ..card_update(webhook)
+    };
+}
+
+// The reply links to the attachment, the file itself is only copied when the account mirrors attachments
+fn handle_attachment_added(webhook: &TrelloWebhook) -> ActionUpdate {
+    let attachment = match &webhook.action.data.attachment {
+        Some(attachment) => attachment,
+        None => return ActionUpdate {
+            text: format!("Attachment added by {}", webhook.action.display.entities.member_creator.text),
+            body: Some("Attachment added".to_string()),
+            ..card_update(webhook)
+        },
+    };
+    return ActionUpdate {
+        text: format!("Attachment {} added by {}", attachment.name, webhook.action.display.entities.member_creator.text),
+        body: Some(format!("Attachment added\n<{}|{}>", attachment.url, escape(&attachment.name))),
+        attachments: vec![ActionAttachment {
+            id: attachment.id.to_owned(),
+            name: attachment.name.to_owned(),
+            url: attachment.url.to_owned(),
+            permalink: attachment.url.to_owned(),
+            size: None,
+            mime_type: None,
+        }],
+        ..card_update(webhook)
+    };
+}
+
+fn create_action_source(webhook: &TrelloWebhook) -> ActionTargetSource {
+    return ActionTargetSource {
+        id: Option::from(String::from(&webhook.action.data.card.id)),
+        service: ActionService::Trello,
+        url: format!("https://trello.com/c/{}", webhook.action.data.card.short_link),
+        channel: None,
+    };
+}
+
+// A new thread's service and channel come from the board's channel mapping once it is looked up
+fn unrouted_target() -> ActionTargetSource {
+    return ActionTargetSource {
+        id: None,
+        service: ActionService::Slack,
+        url: "".to_string(),
+        channel: None,
+    };
+}
+
+
+
+fn get_api_credentials(account: &Account) -> Result<(&str, &str), SyncError> {
+    return match (&account.credentials.trello_api_key, &account.credentials.trello_api_token) {
+        (Some(api_key), Some(api_token)) => Ok((api_key, api_token)),
+        _ => Err(SyncError::Auth(format!("No trello credentials for account {}", account.id))),
+    };
+}
+
+pub async fn get_card(account: &Account, card_id: &str) -> Result<TrelloCard, SyncError> {
+    let (api_key, api_token) = get_api_credentials(account)?;
+    let url = format!("https://api.trello.com/1/cards/{card_id}?fields=name,closed,due,dueComplete,shortUrl,labels&list=true&members=true&member_fields=fullName,username&key={api_key}&token={api_token}");
+
+    let client = reqwest::Client::new();
+    let response = send_with_retry(client.get(url)).await?;
+    return Ok(response.json().await?);
+}
+
+pub async fn get_member(account: &Account, username: &str) -> Result<TrelloMember, SyncError> {
+    let (api_key, api_token) = get_api_credentials(account)?;
+    let username: String = byte_serialize(username.as_bytes()).collect();
+    let url = format!("https://api.trello.com/1/members/{username}?fields=fullName,username&key={api_key}&token={api_token}");
+
+    let client = reqwest::Client::new();
+    let response = send_with_retry(client.get(url)).await?;
+    return Ok(response.json().await?);
+}
+
+pub async fn get_attachment(account: &Account, card_id: &str, attachment_id: &str) -> Result<TrelloAttachment, SyncError> {
+    let (api_key, api_token) = get_api_credentials(account)?;
+    let url = format!("https://api.trello.com/1/cards/{card_id}/attachments/{attachment_id}?fields=name,url,bytes,mimeType,isUpload&key={api_key}&token={api_token}");
+
+    let client = reqwest::Client::new();
+    let response = send_with_retry(client.get(url)).await?;
+    return Ok(response.json().await?);
+}
+
+/// Downloads an uploaded attachment, which Trello only serves with the credentials in a header
+pub async fn download_attachment(account: &Account, url: &str) -> Result<Vec<u8>, SyncError> {
+    let (api_key, api_token) = get_api_credentials(account)?;
+
+    let client = reqwest::Client::new();
+    let request = client.get(url)
+        .header("Authorization", format!("OAuth oauth_consumer_key=\"{api_key}\", oauth_token=\"{api_token}\""));
+    let response = send_with_retry(request).await?;
+    return Ok(response.bytes().await?.to_vec());
+}
+
+/// Adds a copy of a file to the card
+pub async fn upload_attachment_to_card(account: &Account, card_id: &str, attachment: &ActionAttachment, bytes: Vec<u8>) -> Result<(), SyncError> {
+    let (api_key, api_token) = get_api_credentials(account)?;
+    let url = format!("https://api.trello.com/1/cards/{card_id}/attachments?key={api_key}&token={api_token}");
+
+    let mut file = Part::bytes(bytes).file_name(attachment.name.to_owned());
+    if let Some(mime_type) = &attachment.mime_type {
+        file = file.mime_str(mime_type)?;
+    }
+    let form = Form::new()
+        .text("name", attachment.name.to_owned())
+        .part("file", file);
+
+    let client = reqwest::Client::new();
+    send_with_retry(client.post(url).multipart(form)).await?;
+    return Ok(());
+}
+
+/// Attaches a link to the card, for files that aren't copied
+pub async fn attach_link_to_card(account: &Account, card_id: &str, name: &str, link: &str) -> Result<(), SyncError> {
+    let (api_key, api_token) = get_api_credentials(account)?;
+    let name: String = byte_serialize(name.as_bytes()).collect();
+    let link: String = byte_serialize(link.as_bytes()).collect();
+    let url = format!("https://api.trello.com/1/cards/{card_id}/attachments?name={name}&url={link}&key={api_key}&token={api_token}");
 
+    let client = reqwest::Client::new();
+    send_with_retry(client.post(url)).await?;
+    return Ok(());
+}
+
+/// Starts a card on a list, named after the update's title with its text as the description
+pub async fn create_card(account: &Account, list_id: &str, update: &ActionUpdate) -> Result<TrelloCard, SyncError> {
+    let (api_key, api_token) = get_api_credentials(account)?;
+    let name: String = byte_serialize(update.title.as_deref().unwrap_or(&update.text).as_bytes()).collect();
+    let desc: String = byte_serialize(update.text.as_bytes()).collect();
+    let url = format!("https://api.trello.com/1/cards?idList={list_id}&name={name}&desc={desc}&key={api_key}&token={api_token}");
+
+    let client = reqwest::Client::new();
+    let response = send_with_retry(client.post(url)).await?;
+    return Ok(response.json().await?);
+}
+
+/// A link to a card, which works with the card's id as well as its short link
+pub fn card_url(card_id: &str) -> String {
+    return format!("https://trello.com/c/{}", card_id);
+}
+
+pub async fn add_comment_to_card(account: &Account, action: Action) -> Result<TrelloComment, SyncError> {
+    let (api_key, api_token) = get_api_credentials(account)?;
+
+    let card_id = match action.target.id {
+        Some(value) => value,
+        None => return Err(SyncError::NotFound("No trello card to comment on".to_string())),
+    };
+    let text: String = byte_serialize( action.update.text.as_bytes()).collect();
+
+    let url = format!("https://api.trello.com/1/cards/{card_id}/actions/comments?text={text}&key={api_key}&token={api_token}");
+
+    let client = reqwest::Client::new();
+    let request = client.post(url)
+        .header("Content-Type", "application/json");
+    let response = send_with_retry(request).await?;
+
+    return Ok(response.json().await?);
+}
+
+/// Removes a comment added by add_comment_to_card, the target id is the comment's action id
+pub async fn delete_comment(account: &Account, action: Action) -> Result<(), SyncError> {
+    let (api_key, api_token) = get_api_credentials(account)?;
+
+    let comment_id = match action.target.id {
+        Some(value) => value,
+        None => return Err(SyncError::NotFound("No trello comment to delete".to_string())),
+    };
+
+    let url = format!("https://api.trello.com/1/actions/{comment_id}?key={api_key}&token={api_token}");
+
+    let client = reqwest::Client::new();
+    send_with_retry(client.delete(url)).await?;
+
+    return Ok(());
+}
+
+/// Changes the text of a comment added by add_comment_to_card, the target id is the comment's action id
+pub async fn update_comment(account: &Account, action: Action) -> Result<(), SyncError> {
+    let (api_key, api_token) = get_api_credentials(account)?;
+
+    let comment_id = match action.target.id {
+        Some(value) => value,
+        None => return Err(SyncError::NotFound("No trello comment to update".to_string())),
+    };
+    let text: String = byte_serialize(action.update.text.as_bytes()).collect();
+
+    let url = format!("https://api.trello.com/1/actions/{comment_id}/text?value={text}&key={api_key}&token={api_token}");
+
+    let client = reqwest::Client::new();
+    send_with_retry(client.put(url)).await?;
+
+    return Ok(());
+}
+
+
+#[cfg(test)]
 mod tests {
     use std::fs;
-    use worker::{Error};
-    use crate::trello::{generate_action, TrelloWebhook};
+    use std::cell::Cell;
+    use std::time::Duration;
+    use crate::action::{ActionService, ActionType, ActionUpdateField, ActionUpdateLink};
+    use crate::database::{Link, LinkEnd};
+    use crate::events::tests::{test_account, MemoryProcessedEvents};
+    use crate::format::Mentions;
+    use crate::http::sleep;
+    use crate::api::tests::{ApiCall, RecordingApi};
+    use crate::error::SyncError;
+    use crate::settings::DeletionSync;
+    use crate::store::tests::MemoryStore;
+    use crate::store::Store;
+    use crate::trello::{card_end, card_summary, format_due_date, generate_action, get_board_and_list, handle, mentionable_text, process_event, reroute_to_channel, source_thread_field, verify_signature, TrelloAttachment, TrelloCard, TrelloWebhook};
+    use crate::users::tests::{MemoryUserDirectory, OWN_MEMBER_ID};
+
+    const APP_SECRET: &str = "trello-app-secret";
+    const CALLBACK_URL: &str = "https://saas-sync.example.com/trello-webhook/92cfdda8-bb81-480c-b3ca-092d3366b244";
+    const SIGNATURE: &str = "6bxeprSQe1cHRx8QEt+xbmE9d+8=";
+
+    #[test]
+    fn verify_signature_valid() {
+        let body = fs::read("./data/trello/card-moved.json").expect("Error reading file");
+
+        assert!(verify_signature(APP_SECRET, CALLBACK_URL, SIGNATURE, &body));
+    }
+
+    #[test]
+    fn verify_signature_tampered_body() {
+        let data = fs::read_to_string("./data/trello/card-moved.json").expect("Error reading file");
+        let body = data.replace("\"name\": \"Done\"", "\"name\": \"Doing\"");
+        assert_ne!(data, body);
+
+        assert!(!verify_signature(APP_SECRET, CALLBACK_URL, SIGNATURE, body.as_bytes()));
+    }
+
+    #[test]
+    fn verify_signature_wrong_callback_or_secret() {
+        let body = fs::read("./data/trello/card-moved.json").expect("Error reading file");
+
+        assert!(!verify_signature(APP_SECRET, "https://saas-sync.example.com/trello-webhook/other", SIGNATURE, &body));
+        assert!(!verify_signature("wrong-secret", CALLBACK_URL, SIGNATURE, &body));
+        assert!(!verify_signature(APP_SECRET, CALLBACK_URL, "not base64!", &body));
+    }
 
     #[test]
     fn generate_action_card_archived() {
@@ -297,7 +1348,7 @@ mod tests {
 
         let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
 
-        let action = generate_action(&webhook, Err(Error::RustError("test".to_string())));
+        let action = generate_action(&webhook, None, &Mentions::new());
         assert_eq!("abc64ds5ad45s6161d", action.source.id.unwrap());
         assert!(matches!(action.action, crate::action::ActionType::NewThread));
         assert!(action.update.text.contains("TEST UPDATED NAME"));
@@ -310,7 +1361,7 @@ mod tests {
 
         let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
 
-        let action = generate_action(&webhook, Err(Error::RustError("test".to_string())));
+        let action = generate_action(&webhook, None, &Mentions::new());
         assert_eq!("abc64ds5ad45s6161d", action.source.id.unwrap());
         assert!(matches!(action.action, crate::action::ActionType::NewThread));
         assert!(action.update.text.contains("TEST UPDATED NAME"));
@@ -324,7 +1375,7 @@ mod tests {
 
         let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
 
-        let action = generate_action(&webhook, Err(Error::RustError("test".to_string())));
+        let action = generate_action(&webhook, None, &Mentions::new());
         assert_eq!("abc64ds5ad45s6161d", action.source.id.unwrap());
         assert!(matches!(action.action, crate::action::ActionType::NewThread));
         assert!(action.update.text.contains("TEST UPDATED NAME"));
@@ -333,13 +1384,29 @@ mod tests {
         assert!(action.update.text.contains("to list Done"));
     }
 
+    #[test]
+    fn generate_action_card_moved_structured() {
+        let data = fs::read_to_string("./data/trello/card-moved.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+
+        let update = generate_action(&webhook, None, &Mentions::new()).update;
+        assert_eq!(Some("test 5".to_string()), update.title);
+        assert_eq!(Some("TEST UPDATED NAME".to_string()), update.actor);
+        assert_eq!(vec![
+            ActionUpdateField { label: "From".to_string(), value: "Doing".to_string() },
+            ActionUpdateField { label: "To".to_string(), value: "Done".to_string() },
+        ], update.fields);
+        assert_eq!(Some(ActionUpdateLink { label: "View card".to_string(), url: "https://trello.com/c/sadasdsa".to_string() }), update.link);
+    }
+
     #[test]
     fn generate_action_card_description_updated() {
         let data = fs::read_to_string("./data/trello/card-description-edited.json").expect("Error reading file");
 
         let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
 
-        let action = generate_action(&webhook, Err(Error::RustError("test".to_string())));
+        let action = generate_action(&webhook, None, &Mentions::new());
         assert_eq!("abc64ds5ad45s6161d", action.source.id.unwrap());
         assert!(matches!(action.action, crate::action::ActionType::NewThread));
         assert!(action.update.text.contains("TEST UPDATED NAME"));
@@ -353,7 +1420,7 @@ mod tests {
 
         let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
 
-        let action = generate_action(&webhook, Err(Error::RustError("test".to_string())));
+        let action = generate_action(&webhook, None, &Mentions::new());
         assert_eq!("abc64ds5ad45s6161d", action.source.id.unwrap());
         assert!(matches!(action.action, crate::action::ActionType::NewThread));
         assert!(action.update.text.contains("TEST UPDATED NAME"));
@@ -361,19 +1428,331 @@ mod tests {
         assert!(action.update.text.contains("This is a new comment"));
     }
 
+    #[test]
+    fn generate_action_comment_updated() {
+        let data = fs::read_to_string("./data/trello/card-comment-updated.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+        let thread = LinkEnd::new(ActionService::Slack, Some("CHANNEL_ID"), "1715287188.123456");
+
+        assert_eq!("This is an edited comment", mentionable_text(&webhook));
+        let action = generate_action(&webhook, Some(&thread), &Mentions::new());
+        assert_eq!(ActionType::EditMessage, action.action);
+        assert_eq!(Some("This is an edited comment\n_(edited)_".to_string()), action.update.body);
+        assert!(action.update.text.contains("TEST UPDATED NAME"));
+    }
+
+    #[test]
+    fn generate_action_comment_deleted() {
+        let data = fs::read_to_string("./data/trello/card-comment-deleted.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+        let thread = LinkEnd::new(ActionService::Slack, Some("CHANNEL_ID"), "1715287188.123456");
+
+        let action = generate_action(&webhook, Some(&thread), &Mentions::new());
+        assert_eq!(ActionType::DeleteMessage, action.action);
+        assert_eq!(Some("_(deleted)_".to_string()), action.update.body);
+        assert_eq!("Comment deleted by TEST UPDATED NAME", action.update.text);
+    }
+
+    #[test]
+    fn generate_action_attachment_added() {
+        let data = fs::read_to_string("./data/trello/card-attachment-added.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+
+        let action = generate_action(&webhook, None, &Mentions::new());
+        assert_eq!(ActionType::NewThread, action.action);
+        assert_eq!("Attachment mockup.png added by TEST UPDATED NAME", action.update.text);
+        assert_eq!(Some("Attachment added\n<https://trello.com/1/cards/abc64ds5ad45s6161d/attachments/attachmentid/download/mockup.png|mockup.png>".to_string()), action.update.body);
+        assert_eq!(1, action.update.attachments.len());
+        assert_eq!("attachmentid", action.update.attachments[0].id);
+        // The size isn't known until the attachment is fetched
+        assert!(!action.update.attachments[0].can_copy());
+    }
+
+    #[test]
+    fn generate_action_label_added() {
+        let data = fs::read_to_string("./data/trello/card-label-added.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+
+        let update = generate_action(&webhook, None, &Mentions::new()).update;
+        assert_eq!("Label Bug added by TEST UPDATED NAME", update.text);
+        assert_eq!(Some("Label added".to_string()), update.body);
+        assert_eq!(vec![ActionUpdateField { label: "Label".to_string(), value: "Bug".to_string() }], update.fields);
+
+        // Labels without a name are known by their colour
+        let data = data.replace("\"action_add_label_to_card\"", "\"action_remove_label_from_card\"").replace("\"text\": \"Bug\"", "\"text\": \"\"");
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+        let update = generate_action(&webhook, None, &Mentions::new()).update;
+        assert_eq!("Label red removed by TEST UPDATED NAME", update.text);
+    }
+
+    #[test]
+    fn generate_action_due_date() {
+        let data = fs::read_to_string("./data/trello/card-due-date-added.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+
+        let action = generate_action(&webhook, None, &Mentions::new());
+        assert_eq!(ActionType::NewThread, action.action);
+        assert_eq!("Due date added by TEST UPDATED NAME, now due 10 May 2024 16:00 UTC", action.update.text);
+        assert_eq!(vec![ActionUpdateField { label: "Due".to_string(), value: "10 May 2024 16:00 UTC".to_string() }], action.update.fields);
+
+        let keys = [
+            ("action_changed_a_due_date", "Due date changed"),
+            ("action_marked_the_due_date_complete", "Due date marked complete"),
+            ("action_marked_the_due_date_incomplete", "Due date marked incomplete"),
+            ("action_removed_a_due_date", "Due date removed"),
+        ];
+        for (key, body) in keys {
+            let data = data.replace("action_added_a_due_date", key);
+            let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+            let update = generate_action(&webhook, None, &Mentions::new()).update;
+            assert_eq!(Some(body.to_string()), update.body);
+        }
+    }
+
+    #[test]
+    fn due_dates_are_readable() {
+        assert_eq!("1 Dec 2024 09:30 UTC", format_due_date("2024-12-01T09:30:00.000Z"));
+        assert_eq!("2024-13-01T09:30:00.000Z", format_due_date("2024-13-01T09:30:00.000Z"));
+        assert_eq!("next week", format_due_date("next week"));
+        assert_eq!("", format_due_date(""));
+    }
+
+    #[test]
+    fn generate_action_member_added() {
+        let data = fs::read_to_string("./data/trello/card-member-added.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+
+        let update = generate_action(&webhook, None, &Mentions::new()).update;
+        assert_eq!("Member Other User added by TEST UPDATED NAME", update.text);
+        assert_eq!(vec![ActionUpdateField { label: "Member".to_string(), value: "Other User".to_string() }], update.fields);
+
+        let mut webhook: TrelloWebhook = serde_json::from_str(&data.replace("action_added_member_to_card", "action_member_left_card")).expect("Error parsing json");
+        webhook.action.display.entities.member = None;
+        let update = generate_action(&webhook, None, &Mentions::new()).update;
+        assert_eq!("Member TEST UPDATED NAME removed by TEST UPDATED NAME", update.text);
+    }
+
+    #[test]
+    fn generate_action_check_item_completed() {
+        let data = fs::read_to_string("./data/trello/card-checkitem-completed.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+
+        let update = generate_action(&webhook, None, &Mentions::new()).update;
+        assert_eq!("Checklist item Write tests on Release completed by TEST UPDATED NAME", update.text);
+        assert_eq!(Some("Checklist item completed".to_string()), update.body);
+        assert_eq!(vec![
+            ActionUpdateField { label: "Checklist".to_string(), value: "Release".to_string() },
+            ActionUpdateField { label: "Item".to_string(), value: "Write tests".to_string() },
+        ], update.fields);
+    }
+
+    #[test]
+    fn attachment_from_api() {
+        let data = fs::read_to_string("./data/trello/api-attachment.json").expect("Error reading file");
+
+        let attachment: TrelloAttachment = serde_json::from_str(&data).expect("Error parsing json");
+        assert!(attachment.is_upload);
+        assert_eq!(Some(48213), attachment.bytes);
+        assert_eq!(Some("image/png".to_string()), attachment.mime_type);
+    }
+
+    #[test]
+    fn generate_action_comment_mentions() {
+        let data = fs::read_to_string("./data/trello/card-comment-added.json").expect("Error reading file");
+
+        let mut webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+        webhook.action.data.text = Some("@alice can you look at this with @bob?".to_string());
+        let mentions = Mentions::from([
+            ("alice".to_string(), "<@U123>".to_string()),
+            ("bob".to_string(), "Bob Jones".to_string()),
+        ]);
+
+        assert_eq!("@alice can you look at this with @bob?", mentionable_text(&webhook));
+        let action = generate_action(&webhook, None, &mentions);
+        assert_eq!(Some("<@U123> can you look at this with Bob Jones?".to_string()), action.update.body);
+    }
+
+
+    #[test]
+    fn generate_action_existing_thread_keeps_channel() {
+        let data = fs::read_to_string("./data/trello/card-comment-added.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+        let thread = LinkEnd::new(ActionService::Slack, Some("C123456"), "1715287188.123456");
+
+        let action = generate_action(&webhook, Some(&thread), &Mentions::new());
+        assert!(matches!(action.action, crate::action::ActionType::UpdateThread));
+        assert_eq!(Some("1715287188.123456".to_string()), action.target.id);
+        assert_eq!(Some("C123456".to_string()), action.target.channel);
+    }
+
+    #[tokio::test]
+    async fn replayed_action_only_posts_once() {
+        let data = fs::read_to_string("./data/trello/card-moved.json").expect("Error reading file");
+        let events = MemoryProcessedEvents::default();
+        let account = test_account("account");
+        let messages = Cell::new(0);
+
+        for _ in 0..2 {
+            let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+            let action = generate_action(&webhook, None, &Mentions::new());
+            let send = |_| {
+                let messages = &messages;
+                async move {
+                    messages.set(messages.get() + 1);
+                    Ok(())
+                }
+            };
+            process_event(&events, &account, &webhook, action, 1714756952, send).await.unwrap();
+        }
+
+        assert_eq!(1, messages.get());
+    }
+
+    #[test]
+    fn card_summary_from_api() {
+        let data = fs::read_to_string("./data/trello/api-card.json").expect("Error reading file");
+
+        let card: TrelloCard = serde_json::from_str(&data).expect("Error parsing json");
+
+        let summary = card_summary(&card);
+        assert_eq!(Some("test 5".to_string()), summary.title);
+        assert_eq!(vec![
+            ActionUpdateField { label: "List".to_string(), value: "Done".to_string() },
+            ActionUpdateField { label: "Labels".to_string(), value: "Bug, green".to_string() },
+            ActionUpdateField { label: "Due".to_string(), value: "10 May 2024 16:00 UTC".to_string() },
+            ActionUpdateField { label: "Members".to_string(), value: "TEST UPDATED NAME, Other User".to_string() },
+            ActionUpdateField { label: "Status".to_string(), value: "Open".to_string() },
+        ], summary.fields);
+        assert_eq!("https://trello.com/c/sadasdsa", summary.link.unwrap().url);
+    }
+
+    #[test]
+    fn card_summary_from_webhook() {
+        let data = fs::read_to_string("./data/trello/card-archived.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+
+        let summary = card_summary(&TrelloCard::from_webhook(&webhook));
+        assert_eq!(Some("test 4 - changed title".to_string()), summary.title);
+        assert!(summary.fields.contains(&ActionUpdateField { label: "List".to_string(), value: "Doing".to_string() }));
+        assert!(summary.fields.contains(&ActionUpdateField { label: "Status".to_string(), value: "Archived".to_string() }));
+    }
+
+    #[test]
+    fn board_and_list_card_moved() {
+        let data = fs::read_to_string("./data/trello/card-moved.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+
+        assert_eq!((Some("boardid"), Some("abc64ds5ad45s6161d")), get_board_and_list(&webhook));
+    }
+
+    #[test]
+    fn board_and_list_comment_added() {
+        let data = fs::read_to_string("./data/trello/card-comment-added.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+
+        assert_eq!((Some("boardid"), Some("list_id")), get_board_and_list(&webhook));
+    }
 
     #[test]
     fn generate_action_card_copied() {
-        // Not handled at the moment, but make sure it doesn't break
         let data = fs::read_to_string("./data/trello/card-copied.json").expect("Error reading file");
 
         let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
 
-        let action = generate_action(&webhook, Err(Error::RustError("test".to_string())));
+        let action = generate_action(&webhook, None, &Mentions::new());
         assert_eq!("abc64ds5ad45s6161d", action.source.id.unwrap());
-        assert!(matches!(action.action, crate::action::ActionType::None));
-        assert!(action.update.text.contains("action_copy_card"));
-        assert!(action.update.text.contains("Unknown"));
+        assert!(matches!(action.action, crate::action::ActionType::NewThread));
+        assert_eq!("This card has been copied from test 5 by Test User", action.update.text);
+        assert_eq!(vec![
+            ActionUpdateField { label: "Source card".to_string(), value: "<https://trello.com/c/adasdas|test 5>".to_string() },
+        ], action.update.fields);
+    }
+
+    #[test]
+    fn generate_action_card_converted_from_check_item() {
+        let data = fs::read_to_string("./data/trello/card-converted-from-checkitem.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+
+        let action = generate_action(&webhook, None, &Mentions::new());
+        assert!(matches!(action.action, crate::action::ActionType::NewThread));
+        assert_eq!("This card has been converted from a checklist item on test 5 by Test User", action.update.text);
+        assert_eq!(Some("Card created from checklist item".to_string()), action.update.body);
+    }
+
+    #[test]
+    fn source_thread_links_to_slack() {
+        let thread = LinkEnd::new(ActionService::Slack, Some("C123456"), "1715287188.123456");
+        assert_eq!(Some(ActionUpdateField {
+            label: "Source thread".to_string(),
+            value: "<https://slack.com/archives/C123456/p1715287188123456|View thread>".to_string(),
+        }), source_thread_field(&thread));
+
+        let thread = LinkEnd { container: None, ..thread };
+        assert_eq!(None, source_thread_field(&thread));
+    }
+
+    #[test]
+    fn generate_action_card_restored() {
+        let data = fs::read_to_string("./data/trello/card-restored.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+
+        let action = generate_action(&webhook, None, &Mentions::new());
+        assert_eq!("This card has been restored by TEST UPDATED NAME", action.update.text);
+        assert_eq!(Some("Card restored".to_string()), action.update.body);
+    }
+
+    #[test]
+    fn card_moved_to_board_reroutes() {
+        let data = fs::read_to_string("./data/trello/card-moved-to-board.json").expect("Error reading file");
+
+        let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
+        assert_eq!((Some("otherboardid"), Some("otherlistid")), get_board_and_list(&webhook));
+        let thread = LinkEnd::new(ActionService::Slack, Some("C123456"), "1715287188.123456");
+
+        let mut action = generate_action(&webhook, Some(&thread), &Mentions::new());
+        assert_eq!(ActionType::UpdateThread, action.action);
+        assert_eq!("This card has been moved to board Releases by TEST UPDATED NAME", action.update.text);
+
+        // The destination board posts to the same channel, so the thread carries on
+        assert!(reroute_to_channel(&mut action, &ActionService::Slack, "C123456").is_none());
+        assert_eq!(ActionType::UpdateThread, action.action);
+
+        let previous = reroute_to_channel(&mut action, &ActionService::Slack, "C654321").unwrap();
+        assert_eq!("1715287188.123456", previous.target.id);
+        assert_eq!(ActionType::NewThread, action.action);
+        assert_eq!(None, action.target.id);
+        assert_eq!(Some("C654321".to_string()), action.target.channel);
+        assert!(action.update.fields.contains(&ActionUpdateField {
+            label: "Previous thread".to_string(),
+            value: "<https://slack.com/archives/C123456/p1715287188123456|View thread>".to_string(),
+        }));
+
+        // A link from before channel routing could be in any channel, so the card starts over in the new one
+        let legacy = LinkEnd::new(ActionService::Slack, None, "1715287188.123456");
+        let mut action = generate_action(&webhook, Some(&legacy), &Mentions::new());
+        let previous = reroute_to_channel(&mut action, &ActionService::Slack, "C654321").unwrap();
+        assert_eq!(legacy, previous.target);
+        assert_eq!(ActionType::NewThread, action.action);
+        assert_eq!(Some("C654321".to_string()), action.target.channel);
+
+        // A card with no thread yet just starts one in the new channel
+        let mut action = generate_action(&webhook, None, &Mentions::new());
+        assert!(reroute_to_channel(&mut action, &ActionService::Slack, "C654321").is_none());
+        assert_eq!(ActionType::NewThread, action.action);
+        assert_eq!(Some("C654321".to_string()), action.target.channel);
     }
 
 
@@ -383,10 +1762,324 @@ mod tests {
 
         let webhook: TrelloWebhook = serde_json::from_str(&data).expect("Error parsing json");
 
-        let action = generate_action(&webhook, Err(Error::RustError("test".to_string())));
+        let action = generate_action(&webhook, None, &Mentions::new());
         assert_eq!("abc64ds5ad45s6161d", action.source.id.unwrap());
         assert!(matches!(action.action, crate::action::ActionType::None));
     }
 
+    fn read_webhook(name: &str) -> TrelloWebhook {
+        let data = fs::read_to_string(format!("./data/trello/{}.json", name)).expect("Error reading file");
+        return serde_json::from_str(&data).expect("Error parsing json");
+    }
+
+    #[tokio::test]
+    async fn handle_first_update_starts_thread() {
+        let store = MemoryStore::default().with_channel_mapping("boardid", None, "C123456");
+        let api = RecordingApi::default();
+        let account = test_account("account");
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &read_webhook("card-moved"), &account, 1714756952).await;
+
+        assert_eq!("Success", result.unwrap());
+        let calls = api.calls();
+        assert_eq!(3, calls.len());
+        // The card can't be fetched, so the summary comes from the webhook
+        assert_eq!(ApiCall::GetCard { card: "abc64ds5ad45s6161d".to_string() }, calls[0]);
+        assert!(matches!(&calls[1], ApiCall::SendThreadParent { channel, .. } if channel == "C123456"));
+        assert!(matches!(&calls[2], ApiCall::SendAction { channel, thread: Some(thread), .. } if channel == "C123456" && thread == "1000000000.000001"));
+
+        let links = store.links.borrow();
+        assert_eq!(1, links.len());
+        assert_eq!("1000000000.000001", links[0].1.target.id);
+        assert_eq!(Some("C123456".to_string()), links[0].1.target.container);
+    }
+
+    #[tokio::test]
+    async fn handle_claimed_card_replies_in_the_other_delivery_thread() {
+        let store = MemoryStore::default().with_channel_mapping("boardid", None, "C123456");
+        let api = RecordingApi::default();
+        let account = test_account("account");
+        let card = card_end("abc64ds5ad45s6161d");
+        // A simultaneous first event for the card has claimed it and is still creating the thread
+        assert!(store.claim_link(&account, &card, &ActionService::Slack, 1714756951).await.unwrap());
+        let other_delivery = async {
+            sleep(Duration::from_millis(700)).await;
+            store.create_link(&account, &card, &LinkEnd::new(ActionService::Slack, Some("C123456"), "1715287188.123456")).await.unwrap();
+        };
+
+        let directory = MemoryUserDirectory::default();
+        let webhook = read_webhook("card-moved");
+
+        let (result, _) = tokio::join!(handle(&store, &directory, &api, &webhook, &account, 1714756952), other_delivery);
+
+        assert_eq!("Success", result.unwrap());
+        let calls = api.calls();
+        assert!(!calls.iter().any(|call| matches!(call, ApiCall::SendThreadParent { .. })));
+        assert!(matches!(&calls[0], ApiCall::SendAction { channel, thread: Some(thread), .. } if channel == "C123456" && thread == "1715287188.123456"));
+        assert_eq!(1, store.links.borrow().len());
+    }
+
+    #[tokio::test]
+    async fn handle_first_update_starts_card_on_mapped_list() {
+        let store = MemoryStore::default().with_target_mapping("boardid", None, ActionService::Trello, "otherlistid");
+        let api = RecordingApi::default();
+        let account = test_account("account");
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &read_webhook("card-moved"), &account, 1714756952).await;
+
+        assert_eq!("Success", result.unwrap());
+        let calls = api.calls();
+        assert_eq!(3, calls.len());
+        assert!(matches!(&calls[1], ApiCall::CreateCard { list, .. } if list == "otherlistid"));
+        assert!(matches!(&calls[2], ApiCall::AddComment { card, .. } if card == "card2"));
 
-}
\ No newline at end of file
+        let links = store.links.borrow();
+        assert_eq!(1, links.len());
+        assert_eq!(LinkEnd::new(ActionService::Trello, None, "card2"), links[0].1.target);
+    }
+
+    #[tokio::test]
+    async fn handle_comment_replies_and_refreshes_summary() {
+        let store = MemoryStore::default()
+            .with_channel_mapping("boardid", None, "C123456")
+            .with_link("abc64ds5ad45s6161d", "C123456", "1715287188.123456");
+        let api = RecordingApi::default();
+        let account = test_account("account");
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &read_webhook("card-comment-added"), &account, 1714756952).await;
+
+        assert_eq!("Success", result.unwrap());
+        let calls = api.calls();
+        assert!(matches!(&calls[0], ApiCall::SendAction { thread: Some(thread), .. } if thread == "1715287188.123456"));
+        assert!(matches!(&calls[2], ApiCall::UpdateMessage { ts, .. } if ts == "1715287188.123456"));
+
+        // The reply is mapped to the comment so edits can follow it
+        let mappings = store.message_mappings.borrow();
+        assert_eq!(1, mappings.len());
+        assert_eq!(LinkEnd::new(ActionService::Trello, None, "abc64ds5ad45s6161d"), mappings[0].1.source);
+        assert_eq!(LinkEnd::new(ActionService::Slack, Some("C123456"), "1000000000.000001"), mappings[0].1.target);
+    }
+
+    #[tokio::test]
+    async fn handle_unmapped_board_is_skipped() {
+        let api = RecordingApi::default();
+        let account = test_account("account");
+
+        let result = handle(&MemoryStore::default(), &MemoryUserDirectory::default(), &api, &read_webhook("card-moved"), &account, 1714756952).await;
+
+        assert_eq!("No channel mapped", result.unwrap());
+        assert!(api.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_own_comment_on_linked_card_is_skipped() {
+        // The webhook's card is the one the bot started on a mapped list, so its link leads back to the source card
+        let store = MemoryStore::default();
+        store.links.borrow_mut().push(("account".to_string(), Link {
+            source: card_end("sourcecard"),
+            target: card_end("abc64ds5ad45s6161d"),
+        }));
+        let api = RecordingApi::default();
+        let account = test_account("account");
+        let mut webhook = read_webhook("card-comment-added");
+        webhook.action.id_member_creator = OWN_MEMBER_ID.to_string();
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756952).await;
+        assert_eq!("Skipping own action", result.unwrap());
+        assert!(api.calls().is_empty());
+
+        // Someone else's comment on it is still sent back to the source card
+        let webhook = read_webhook("card-comment-added");
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756952).await;
+        assert_eq!("Success", result.unwrap());
+        assert!(matches!(&api.calls()[0], ApiCall::AddComment { card, .. } if card == "sourcecard"));
+    }
+
+    #[tokio::test]
+    async fn mentions_are_only_looked_up_for_new_routed_comments() {
+        let mut webhook = read_webhook("card-comment-added");
+        webhook.action.data.text = Some("Can you check this @dave?".to_string());
+        let api = RecordingApi::default();
+        let account = test_account("account");
+
+        let result = handle(&MemoryStore::default(), &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756952).await;
+        assert_eq!("No channel mapped", result.unwrap());
+        assert!(api.calls().is_empty());
+
+        let store = MemoryStore::default()
+            .with_channel_mapping("boardid", None, "C123456")
+            .with_link("abc64ds5ad45s6161d", "C123456", "1715287188.123456");
+        for _ in 0..2 {
+            handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756952).await.unwrap();
+        }
+        let lookups = api.calls().into_iter().filter(|call| matches!(call, ApiCall::GetMember { .. })).count();
+        assert_eq!(1, lookups);
+    }
+
+    #[tokio::test]
+    async fn handle_failed_thread_can_be_retried() {
+        let store = MemoryStore::default().with_channel_mapping("boardid", None, "C123456");
+        let api = RecordingApi::default().fail("send_thread_parent", SyncError::Auth("invalid_auth".to_string()));
+        let account = test_account("account");
+        let webhook = read_webhook("card-moved");
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756952).await;
+        assert!(matches!(result, Err(SyncError::Auth(_))));
+        // The claim is released, so the retry isn't left waiting for a thread that will never exist
+        assert!(store.links.borrow().is_empty());
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756953).await;
+        assert_eq!("Success", result.unwrap());
+        assert_eq!(1, store.links.borrow().len());
+    }
+
+    #[tokio::test]
+    async fn handle_failed_link_releases_claim() {
+        let mut store = MemoryStore::default().with_channel_mapping("boardid", None, "C123456");
+        store.failing_create_link = true;
+        let api = RecordingApi::default();
+        let account = test_account("account");
+        let webhook = read_webhook("card-moved");
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756952).await;
+        assert!(matches!(result, Err(SyncError::Worker(_))));
+        assert!(store.links.borrow().is_empty());
+
+        store.failing_create_link = false;
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756953).await;
+        assert_eq!("Success", result.unwrap());
+        assert_eq!(1, store.links.borrow().len());
+    }
+
+    #[tokio::test]
+    async fn handle_stale_summary_still_succeeds() {
+        let store = MemoryStore::default()
+            .with_channel_mapping("boardid", None, "C123456")
+            .with_link("abc64ds5ad45s6161d", "C123456", "1715287188.123456");
+        let api = RecordingApi::default().fail("update_message", SyncError::RateLimited { retry_after: None });
+        let account = test_account("account");
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &read_webhook("card-moved"), &account, 1714756952).await;
+
+        assert_eq!("Success", result.unwrap());
+    }
+
+    #[tokio::test]
+    async fn handle_comment_edit() {
+        let store = MemoryStore::default().with_message_mapping(LinkEnd::new(ActionService::Trello, None, "abc64ds5ad45s6161d"), LinkEnd::new(ActionService::Slack, Some("C123456"), "1715523657.123456"));
+        let api = RecordingApi::default();
+        let account = test_account("account");
+        let webhook = read_webhook("card-comment-updated");
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756952).await;
+        assert_eq!("Success", result.unwrap());
+        let calls = api.calls();
+        assert_eq!(1, calls.len());
+        assert!(matches!(&calls[0], ApiCall::UpdateMessage { channel, ts, text } if channel == "C123456" && ts == "1715523657.123456" && text.contains("This is an edited comment")));
+
+        let api = RecordingApi::default();
+        let result = handle(&MemoryStore::default(), &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756952).await;
+        assert_eq!("Comment not synced", result.unwrap());
+        assert!(api.calls().is_empty());
+
+        // A comment copied from someone's Slack message never edits their message
+        let store = MemoryStore::default().with_message_mapping(LinkEnd::new(ActionService::Slack, Some("C123456"), "1715523657.123456"), LinkEnd::new(ActionService::Trello, None, "abc64ds5ad45s6161d"));
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756952).await;
+        assert_eq!("Comment not from Trello", result.unwrap());
+        assert!(api.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_lookup_errors_are_retried() {
+        // A database error isn't taken to mean the card or comment was never synced, the job is retried
+        let mut store = MemoryStore::default()
+            .with_channel_mapping("boardid", None, "C123456")
+            .with_message_mapping(LinkEnd::new(ActionService::Trello, None, "abc64ds5ad45s6161d"), LinkEnd::new(ActionService::Slack, Some("C123456"), "1715523657.123456"));
+        store.failing_lookups = true;
+        let api = RecordingApi::default();
+        let account = test_account("account");
+
+        for name in ["card-moved", "card-comment-updated"] {
+            let result = handle(&store, &MemoryUserDirectory::default(), &api, &read_webhook(name), &account, 1714756952).await;
+            assert!(matches!(result, Err(SyncError::Worker(_))), "{}", name);
+        }
+        assert!(api.calls().iter().all(|call| matches!(call, ApiCall::GetMember { .. })));
+    }
+
+    #[tokio::test]
+    async fn handle_comment_delete_only_removes_our_copy() {
+        let api = RecordingApi::default();
+        let mut account = test_account("account");
+        account.settings.deletion_sync = DeletionSync::Delete;
+        let webhook = read_webhook("card-comment-deleted");
+
+        // Deleting the comment we copied someone's Slack message to leaves their message alone
+        let store = MemoryStore::default().with_message_mapping(LinkEnd::new(ActionService::Slack, Some("C123456"), "1715523657.123456"), LinkEnd::new(ActionService::Trello, None, "abc64ds5ad45s6161d"));
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756952).await;
+        assert_eq!("Comment not from Trello", result.unwrap());
+        assert!(api.calls().is_empty());
+
+        let store = MemoryStore::default().with_message_mapping(LinkEnd::new(ActionService::Trello, None, "abc64ds5ad45s6161d"), LinkEnd::new(ActionService::Slack, Some("C123456"), "1715523657.123456"));
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &webhook, &account, 1714756952).await;
+        assert_eq!("Success", result.unwrap());
+        assert_eq!(vec![ApiCall::DeleteMessage { channel: "C123456".to_string(), ts: "1715523657.123456".to_string() }], api.calls());
+        assert!(store.message_mappings.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_card_moved_to_board_starts_new_thread() {
+        let store = MemoryStore::default()
+            .with_channel_mapping("otherboardid", None, "C654321")
+            .with_link("abc64ds5ad45s6161d", "C123456", "1715287188.123456");
+        let api = RecordingApi::default();
+        let account = test_account("account");
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &read_webhook("card-moved-to-board"), &account, 1714756952).await;
+
+        assert_eq!("Success", result.unwrap());
+        let calls = api.calls();
+        assert!(matches!(&calls[1], ApiCall::SendThreadParent { channel, .. } if channel == "C654321"));
+        assert!(matches!(&calls[2], ApiCall::SendThreadReply { channel, thread, .. } if channel == "C123456" && thread == "1715287188.123456"));
+        assert!(matches!(&calls[3], ApiCall::SendAction { channel, .. } if channel == "C654321"));
+
+        let links = store.links.borrow();
+        assert_eq!(1, links.len());
+        assert_eq!(Some("C654321".to_string()), links[0].1.target.container);
+    }
+
+    #[tokio::test]
+    async fn handle_card_moved_to_board_keeps_link_until_new_thread_exists() {
+        let store = MemoryStore::default()
+            .with_channel_mapping("otherboardid", None, "C654321")
+            .with_link("abc64ds5ad45s6161d", "C123456", "1715287188.123456");
+        let api = RecordingApi::default().fail("send_thread_parent", SyncError::Auth("invalid_auth".to_string()));
+        let account = test_account("account");
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &read_webhook("card-moved-to-board"), &account, 1714756952).await;
+
+        assert!(matches!(result, Err(SyncError::Auth(_))));
+        let links = store.links.borrow();
+        assert_eq!(1, links.len());
+        assert_eq!(LinkEnd::new(ActionService::Slack, Some("C123456"), "1715287188.123456"), links[0].1.target);
+    }
+
+    #[tokio::test]
+    async fn handle_attachment_is_mirrored() {
+        let data = fs::read_to_string("./data/trello/api-attachment.json").expect("Error reading file");
+        let attachment: TrelloAttachment = serde_json::from_str(&data).expect("Error parsing json");
+        let store = MemoryStore::default()
+            .with_channel_mapping("boardid", None, "C123456")
+            .with_link("abc64ds5ad45s6161d", "C123456", "1715287188.123456");
+        let api = RecordingApi::default().with_attachment(attachment);
+        let mut account = test_account("account");
+        account.settings.mirror_attachments = true;
+
+        let result = handle(&store, &MemoryUserDirectory::default(), &api, &read_webhook("card-attachment-added"), &account, 1714756952).await;
+
+        assert_eq!("Success", result.unwrap());
+        let calls = api.calls();
+        assert_eq!(ApiCall::GetAttachment { card: "abc64ds5ad45s6161d".to_string(), attachment: "attachmentid".to_string() }, calls[1]);
+        assert!(matches!(&calls[2], ApiCall::DownloadAttachment { .. }));
+        assert_eq!(ApiCall::UploadFile { channel: "C123456".to_string(), thread: "1715287188.123456".to_string(), name: "mockup.png".to_string() }, calls[3]);
+    }
+}
@@ -0,0 +1,246 @@
+use worker::{Env, Error};
+use crate::account::Account;
+use crate::api::{SlackApi, TrelloApi};
+use crate::database::{cache_slack_user, cache_trello_member, get_cached_slack_user, get_cached_trello_member, get_user_mapping_by_slack_user, get_user_mapping_by_trello_username, CachedTrelloMember};
+use crate::error::SyncError;
+use crate::format::{escape, slack_mentions, trello_mentions, Mentions};
+use crate::slack::SlackApiError;
+use crate::trello::TrelloMember;
+
+// Names rarely change, so a day old name saves a users.info call per message
+pub const SLACK_USER_CACHE_TTL_SECONDS: u64 = 60 * 60 * 24;
+
+// Likewise for a members lookup per mention
+pub const TRELLO_MEMBER_CACHE_TTL_SECONDS: u64 = 60 * 60 * 24;
+
+pub trait UserDirectory {
+    /// The name Slack shows for a user, None if Slack doesn't know the user
+    async fn slack_display_name<A: SlackApi>(&self, api: &A, account: &Account, slack_user: &str, now: u64) -> Result<Option<String>, SyncError>;
+
+    /// A Trello member by username, or `me` for the token's own member. None if there is no member with the username.
+    async fn trello_member<A: TrelloApi>(&self, api: &A, account: &Account, trello_username: &str, now: u64) -> Result<Option<TrelloMember>, SyncError>;
+
+    /// The Trello member a Slack user has been mapped to
+    async fn trello_username(&self, account: &Account, slack_user: &str) -> Result<Option<String>, Error>;
+
+    /// The Slack user a Trello member has been mapped to
+    async fn slack_user(&self, account: &Account, trello_username: &str) -> Result<Option<String>, Error>;
+}
+
+pub struct D1UserDirectory<'a> {
+    env: &'a Env,
+}
+
+impl<'a> D1UserDirectory<'a> {
+    pub fn new(env: &'a Env) -> Self {
+        return D1UserDirectory { env };
+    }
+}
+
+impl UserDirectory for D1UserDirectory<'_> {
+    async fn slack_display_name<A: SlackApi>(&self, api: &A, account: &Account, slack_user: &str, now: u64) -> Result<Option<String>, SyncError> {
+        let fetched_after = now.saturating_sub(SLACK_USER_CACHE_TTL_SECONDS);
+        if let Some(cached) = get_cached_slack_user(self.env, account, slack_user, fetched_after).await? {
+            return Ok(Some(cached.display_name));
+        }
+
+        let name = fetch_slack_display_name(api, account, slack_user).await?;
+        if let Some(name) = &name {
+            cache_slack_user(self.env, account, slack_user, name, now).await?;
+        }
+        return Ok(name);
+    }
+
+    async fn trello_member<A: TrelloApi>(&self, api: &A, account: &Account, trello_username: &str, now: u64) -> Result<Option<TrelloMember>, SyncError> {
+        let fetched_after = now.saturating_sub(TRELLO_MEMBER_CACHE_TTL_SECONDS);
+        if let Some(cached) = get_cached_trello_member(self.env, account, trello_username, fetched_after).await? {
+            return Ok(Some(cached_member(trello_username, cached)));
+        }
+
+        let member = fetch_trello_member(api, account, trello_username).await?;
+        if let Some(member) = &member {
+            cache_trello_member(self.env, account, trello_username, member, now).await?;
+        }
+        return Ok(member);
+    }
+
+    async fn trello_username(&self, account: &Account, slack_user: &str) -> Result<Option<String>, Error> {
+        let mapping = get_user_mapping_by_slack_user(self.env, account, slack_user).await?;
+        return Ok(mapping.map(|mapping| mapping.trello_username));
+    }
+
+    async fn slack_user(&self, account: &Account, trello_username: &str) -> Result<Option<String>, Error> {
+        let mapping = get_user_mapping_by_trello_username(self.env, account, trello_username).await?;
+        return Ok(mapping.map(|mapping| mapping.slack_user));
+    }
+}
+
+/// Looks a user up with users.info, None if Slack doesn't know them
+pub async fn fetch_slack_display_name<A: SlackApi>(api: &A, account: &Account, slack_user: &str) -> Result<Option<String>, SyncError> {
+    return match api.get_user(account, slack_user).await? {
+        Ok(user) => Ok(Some(user.display_name().to_string())),
+        Err(SlackApiError::UserNotFound) => Ok(None),
+        Err(err) => Err(err.into()),
+    };
+}
+
+pub async fn fetch_trello_member<A: TrelloApi>(api: &A, account: &Account, trello_username: &str) -> Result<Option<TrelloMember>, SyncError> {
+    return match api.get_member(account, trello_username).await {
+        Ok(member) => Ok(Some(member)),
+        Err(SyncError::NotFound(_)) => Ok(None),
+        Err(err) => Err(err),
+    };
+}
+
+// The cache is keyed by the username that was looked up, which is `me` rather than the username for the token's member
+pub fn cached_member(trello_username: &str, cached: CachedTrelloMember) -> TrelloMember {
+    return TrelloMember {
+        id: cached.member_id,
+        full_name: cached.full_name,
+        username: trello_username.to_string(),
+    };
+}
+
+/// How a Slack user is credited on Trello, as their Trello member when they are mapped to one.
+/// A failed lookup falls back to the raw user id rather than losing the message.
+pub async fn slack_sender_name<D: UserDirectory, A: SlackApi>(directory: &D, api: &A, account: &Account, slack_user: &str, now: u64) -> String {
+    if let Ok(Some(username)) = directory.trello_username(account, slack_user).await {
+        return format!("@{} (via Slack)", username);
+    }
+    return match directory.slack_display_name(api, account, slack_user, now).await {
+        Ok(Some(name)) => format!("{} (via Slack)", name),
+        _ => format!("{} (via Slack)", slack_user),
+    };
+}
+
+/// Trello mentions for the users mentioned in a Slack message, so mapped members are notified.
+/// Unmapped users are named instead, and users that can't be looked up are left as they are.
+pub async fn resolve_slack_mentions<D: UserDirectory, A: SlackApi>(directory: &D, api: &A, account: &Account, text: &str, now: u64) -> Mentions {
+    let mut mentions = Mentions::new();
+    for slack_user in slack_mentions(text) {
+        if let Ok(Some(username)) = directory.trello_username(account, &slack_user).await {
+            mentions.insert(slack_user, format!("@{}", username));
+        } else if let Ok(Some(name)) = directory.slack_display_name(api, account, &slack_user, now).await {
+            mentions.insert(slack_user, name);
+        }
+    }
+    return mentions;
+}
+
+/// Slack mentions for the members mentioned in Trello text, the reverse of resolve_slack_mentions
+pub async fn resolve_trello_mentions<D: UserDirectory, A: TrelloApi>(directory: &D, api: &A, account: &Account, text: &str, now: u64) -> Mentions {
+    let mut mentions = Mentions::new();
+    for username in trello_mentions(text) {
+        if let Ok(Some(slack_user)) = directory.slack_user(account, &username).await {
+            mentions.insert(username, format!("<@{}>", slack_user));
+        } else if let Ok(Some(member)) = directory.trello_member(api, account, &username, now).await {
+            mentions.insert(username, escape(&member.full_name));
+        }
+    }
+    return mentions;
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashMap;
+    use worker::Error;
+    use crate::account::Account;
+    use crate::api::tests::RecordingApi;
+    use crate::api::{SlackApi, TrelloApi};
+    use crate::error::SyncError;
+    use crate::events::tests::test_account;
+    use crate::trello::TrelloMember;
+    use crate::users::{fetch_trello_member, resolve_slack_mentions, resolve_trello_mentions, slack_sender_name, UserDirectory};
+
+    pub struct MemoryUserDirectory {
+        // Slack user id to display name
+        pub names: HashMap<String, String>,
+        // Trello username to member
+        pub trello_members: HashMap<String, TrelloMember>,
+        // Slack user id to Trello username
+        pub mappings: HashMap<String, String>,
+    }
+
+    // The token's own member, which the handlers look up to skip their own comments
+    pub const OWN_MEMBER_ID: &str = "ownmemberid";
+
+    impl Default for MemoryUserDirectory {
+        fn default() -> Self {
+            let directory = MemoryUserDirectory { names: HashMap::new(), trello_members: HashMap::new(), mappings: HashMap::new() };
+            return directory.with_trello_member("me", OWN_MEMBER_ID, "Sync Bot");
+        }
+    }
+
+    impl MemoryUserDirectory {
+        pub fn with_user(mut self, slack_user: &str, name: &str, trello_username: Option<&str>) -> Self {
+            self.names.insert(slack_user.to_string(), name.to_string());
+            if let Some(username) = trello_username {
+                self.mappings.insert(slack_user.to_string(), username.to_string());
+            }
+            return self;
+        }
+
+        pub fn with_trello_member(mut self, username: &str, id: &str, name: &str) -> Self {
+            self.trello_members.insert(username.to_string(), TrelloMember {
+                id: id.to_string(),
+                full_name: name.to_string(),
+                username: username.to_string(),
+            });
+            return self;
+        }
+    }
+
+    impl UserDirectory for MemoryUserDirectory {
+        async fn slack_display_name<A: SlackApi>(&self, _api: &A, _account: &Account, slack_user: &str, _now: u64) -> Result<Option<String>, SyncError> {
+            return Ok(self.names.get(slack_user).cloned());
+        }
+
+        // Members that weren't added are looked up, so tests can see when a lookup is made
+        async fn trello_member<A: TrelloApi>(&self, api: &A, account: &Account, trello_username: &str, _now: u64) -> Result<Option<TrelloMember>, SyncError> {
+            if let Some(member) = self.trello_members.get(trello_username) {
+                return Ok(Some(member.clone()));
+            }
+            return fetch_trello_member(api, account, trello_username).await;
+        }
+
+        async fn trello_username(&self, _account: &Account, slack_user: &str) -> Result<Option<String>, Error> {
+            return Ok(self.mappings.get(slack_user).cloned());
+        }
+
+        async fn slack_user(&self, _account: &Account, trello_username: &str) -> Result<Option<String>, Error> {
+            let user = self.mappings.iter().find(|(_, username)| *username == trello_username);
+            return Ok(user.map(|(slack_user, _)| slack_user.to_owned()));
+        }
+    }
+
+    #[tokio::test]
+    async fn sender_name_prefers_trello_member() {
+        let directory = MemoryUserDirectory::default()
+            .with_user("U123", "Alice Smith", Some("alice"))
+            .with_user("U456", "Bob", None);
+        let account = test_account("account");
+
+        assert_eq!("@alice (via Slack)", slack_sender_name(&directory, &RecordingApi::default(), &account, "U123", 100).await);
+        assert_eq!("Bob (via Slack)", slack_sender_name(&directory, &RecordingApi::default(), &account, "U456", 100).await);
+        assert_eq!("U789 (via Slack)", slack_sender_name(&directory, &RecordingApi::default(), &account, "U789", 100).await);
+    }
+
+    #[tokio::test]
+    async fn mentions_resolve_to_mapped_users_or_names() {
+        let directory = MemoryUserDirectory::default()
+            .with_user("U123", "Alice Smith", Some("alice"))
+            .with_user("U456", "Bob", None)
+            .with_trello_member("carol", "5f0c", "Carol & Co");
+        let account = test_account("account");
+
+        let mentions = resolve_slack_mentions(&directory, &RecordingApi::default(), &account, "<@U123> <@U456> <@U789>", 100).await;
+        assert_eq!(Some(&"@alice".to_string()), mentions.get("U123"));
+        assert_eq!(Some(&"Bob".to_string()), mentions.get("U456"));
+        assert_eq!(None, mentions.get("U789"));
+
+        let mentions = resolve_trello_mentions(&directory, &RecordingApi::default(), &account, "@alice @carol @dave", 100).await;
+        assert_eq!(Some(&"<@U123>".to_string()), mentions.get("alice"));
+        assert_eq!(Some(&"Carol &amp; Co".to_string()), mentions.get("carol"));
+        assert_eq!(None, mentions.get("dave"));
+    }
+}